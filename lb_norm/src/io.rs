@@ -2,7 +2,7 @@ use std::{collections::BTreeMap, io::Write, path::Path};
 
 use anyhow::Context;
 use compress_io::compress::CompressIo;
-use utils::get_next_line;
+use utils::{get_next_line, is_coverage_header};
 
 pub fn read_sample_contig_data(p: &Path) -> anyhow::Result<Vec<(usize, f64)>> {
     let mut v = Vec::new();
@@ -17,7 +17,7 @@ pub fn read_sample_contig_data(p: &Path) -> anyhow::Result<Vec<(usize, f64)>> {
     {
         line += 1;
         // Parse input line and store to hash if valid
-        if fields.len() >= 3 {
+        if fields.len() >= 3 && !is_coverage_header(&fields) {
             // Skip short lines
             let x = fields[1]
                 .parse::<usize>()
@@ -62,7 +62,7 @@ pub fn output_sample_contig_data(
     })? {
         line += 1;
         // Parse input line and store to hash if valid
-        if fields.len() >= 3 {
+        if fields.len() >= 3 && !is_coverage_header(&fields) {
             // Skip short lines
             let x = fields[1].parse::<usize>().with_context(|| {
                 format!("{}:{} Error reading position", in_path.display(), line)
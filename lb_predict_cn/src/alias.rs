@@ -0,0 +1,269 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use utils::get_next_line;
+
+/// UCSC names of the primary chromosomes
+pub const CHROMOSOMES: [&str; 24] = [
+    "chr1", "chr2", "chr3", "chr4", "chr5", "chr6", "chr7", "chr8", "chr9", "chr10", "chr11",
+    "chr12", "chr13", "chr14", "chr15", "chr16", "chr17", "chr18", "chr19", "chr20", "chr21",
+    "chr22", "chrX", "chrY",
+];
+
+/// Lengths of the primary chromosomes (in the same order as CHROMOSOMES) for known assemblies
+pub const ASSEMBLIES: [(&str, [usize; 24]); 3] = [
+    (
+        "GRCh37",
+        [
+            249250621, 243199373, 198022430, 191154276, 180915260, 171115067, 159138663, 146364022,
+            141213431, 135534747, 135006516, 133851895, 115169878, 107349540, 102531392, 90354753,
+            81195210, 78077248, 59128983, 63025520, 48129895, 51304566, 155270560, 59373566,
+        ],
+    ),
+    (
+        "GRCh38",
+        [
+            248956422, 242193529, 198295559, 190214555, 181538259, 170805979, 159345973, 145138636,
+            138394717, 133797422, 135086622, 133275309, 114364328, 107043718, 101991189, 90338345,
+            83257441, 80373285, 58617616, 64444167, 46709983, 50818468, 156040895, 57227415,
+        ],
+    ),
+    (
+        "T2T-CHM13",
+        [
+            248387328, 242696752, 201105948, 193574945, 182045439, 172126628, 160567428, 146259331,
+            150617247, 134758134, 135127769, 133324548, 113566686, 101161492, 99753195, 96330374,
+            84276897, 80542538, 61707364, 66210255, 45090682, 51324926, 154259566, 62460029,
+        ],
+    ),
+];
+
+/// Built in alias groups for the human primary assembly chromosomes
+///
+/// Each entry gives the Ensembl, GenBank and RefSeq names for the chromosomes in CHROMOSOMES
+/// (which gives the UCSC names) for an assembly.  The UCSC and Ensembl names are the same for
+/// GRCh37 and GRCh38, so the groups are kept separate and are only applied to sequences with
+/// the chromosome length from ASSEMBLIES
+#[rustfmt::skip]
+const BUILTIN_ALIASES: [(&str, [[&str; 3]; 24]); 2] = [
+    (
+        "GRCh37",
+        [
+            ["1", "CM000663.1", "NC_000001.10"],
+            ["2", "CM000664.1", "NC_000002.11"],
+            ["3", "CM000665.1", "NC_000003.11"],
+            ["4", "CM000666.1", "NC_000004.11"],
+            ["5", "CM000667.1", "NC_000005.9"],
+            ["6", "CM000668.1", "NC_000006.11"],
+            ["7", "CM000669.1", "NC_000007.13"],
+            ["8", "CM000670.1", "NC_000008.10"],
+            ["9", "CM000671.1", "NC_000009.11"],
+            ["10", "CM000672.1", "NC_000010.10"],
+            ["11", "CM000673.1", "NC_000011.9"],
+            ["12", "CM000674.1", "NC_000012.11"],
+            ["13", "CM000675.1", "NC_000013.10"],
+            ["14", "CM000676.1", "NC_000014.8"],
+            ["15", "CM000677.1", "NC_000015.9"],
+            ["16", "CM000678.1", "NC_000016.9"],
+            ["17", "CM000679.1", "NC_000017.10"],
+            ["18", "CM000680.1", "NC_000018.9"],
+            ["19", "CM000681.1", "NC_000019.9"],
+            ["20", "CM000682.1", "NC_000020.10"],
+            ["21", "CM000683.1", "NC_000021.8"],
+            ["22", "CM000684.1", "NC_000022.10"],
+            ["X", "CM000685.1", "NC_000023.10"],
+            ["Y", "CM000686.1", "NC_000024.9"],
+        ],
+    ),
+    (
+        "GRCh38",
+        [
+            ["1", "CM000663.2", "NC_000001.11"],
+            ["2", "CM000664.2", "NC_000002.12"],
+            ["3", "CM000665.2", "NC_000003.12"],
+            ["4", "CM000666.2", "NC_000004.12"],
+            ["5", "CM000667.2", "NC_000005.10"],
+            ["6", "CM000668.2", "NC_000006.12"],
+            ["7", "CM000669.2", "NC_000007.14"],
+            ["8", "CM000670.2", "NC_000008.11"],
+            ["9", "CM000671.2", "NC_000009.12"],
+            ["10", "CM000672.2", "NC_000010.11"],
+            ["11", "CM000673.2", "NC_000011.10"],
+            ["12", "CM000674.2", "NC_000012.12"],
+            ["13", "CM000675.2", "NC_000013.11"],
+            ["14", "CM000676.2", "NC_000014.9"],
+            ["15", "CM000677.2", "NC_000015.10"],
+            ["16", "CM000678.2", "NC_000016.10"],
+            ["17", "CM000679.2", "NC_000017.11"],
+            ["18", "CM000680.2", "NC_000018.10"],
+            ["19", "CM000681.2", "NC_000019.10"],
+            ["20", "CM000682.2", "NC_000020.11"],
+            ["21", "CM000683.2", "NC_000021.9"],
+            ["22", "CM000684.2", "NC_000022.11"],
+            ["X", "CM000685.2", "NC_000023.11"],
+            ["Y", "CM000686.2", "NC_000024.10"],
+        ],
+    ),
+];
+
+/// Built in alias groups for the mitochondrial sequence.  The hg19 chrM (NC_001807) is not the
+/// same sequence as the rCRS used for MT in GRCh37 and for chrM in GRCh38, so the groups are
+/// distinguished by length
+const BUILTIN_MITO_ALIASES: [(&[&str], usize); 2] = [
+    (
+        &["chrM", "MT", "M", "chrMT", "J01415.2", "NC_012920.1"],
+        16569,
+    ),
+    (&["chrM", "NC_001807.4"], 16571),
+];
+
+/// ContigAlias
+///
+/// Groups of contig names that refer to the same sequence
+///
+/// groups - the alias groups from the alias file.  Each group is a list of equivalent names
+/// lookup - maps each name to the index of its group
+/// builtin - the built in alias groups, with the sequence length the aliases apply to
+/// builtin_lookup - maps each name to the indices of the built in groups containing it
+///
+#[derive(Default)]
+pub struct ContigAlias {
+    groups: Vec<Vec<Arc<str>>>,
+    lookup: HashMap<Arc<str>, usize>,
+    builtin: Vec<(Vec<Arc<str>>, usize)>,
+    builtin_lookup: HashMap<Arc<str>, Vec<usize>>,
+}
+
+impl ContigAlias {
+    /// Alias table with the built in human chromosome mappings
+    pub fn builtin() -> Self {
+        let mut ca = Self::default();
+        for (assembly, names) in BUILTIN_ALIASES.iter() {
+            let (_, lengths) = ASSEMBLIES
+                .iter()
+                .find(|(a, _)| a == assembly)
+                .expect("Missing lengths for assembly");
+            for ((ucsc, v), len) in CHROMOSOMES.iter().zip(names.iter()).zip(lengths.iter()) {
+                ca.add_builtin_group(std::iter::once(ucsc).chain(v.iter()), *len)
+            }
+        }
+        for (names, len) in BUILTIN_MITO_ALIASES.iter() {
+            ca.add_builtin_group(names.iter(), *len)
+        }
+        ca
+    }
+
+    fn add_builtin_group<'a, I: Iterator<Item = &'a &'a str>>(&mut self, names: I, len: usize) {
+        let ix = self.builtin.len();
+        let v: Vec<Arc<str>> = names.map(|s| Arc::from(*s)).collect();
+        for s in v.iter() {
+            self.builtin_lookup
+                .entry(Arc::clone(s))
+                .or_default()
+                .push(ix)
+        }
+        self.builtin.push((v, len))
+    }
+
+    /// Add a group of equivalent names from the alias file.  If any of the names are already
+    /// present in a group then the groups are merged
+    fn add_group<S: AsRef<str>>(&mut self, names: &[S]) {
+        let mut ix = None;
+        for name in names.iter().map(|s| s.as_ref()) {
+            match (ix, self.lookup.get(name).copied()) {
+                (None, Some(j)) => ix = Some(j),
+                (Some(i), Some(j)) if i != j => {
+                    // Merge group j into group i
+                    let v = std::mem::take(&mut self.groups[j]);
+                    for s in v {
+                        self.lookup.insert(Arc::clone(&s), i);
+                        self.groups[i].push(s)
+                    }
+                }
+                _ => (),
+            }
+        }
+        let ix = ix.unwrap_or_else(|| {
+            self.groups.push(Vec::new());
+            self.groups.len() - 1
+        });
+        for name in names.iter().map(|s| s.as_ref()) {
+            if !self.lookup.contains_key(name) {
+                let s: Arc<str> = Arc::from(name);
+                self.lookup.insert(Arc::clone(&s), ix);
+                self.groups[ix].push(s)
+            }
+        }
+    }
+
+    /// Return the aliases for a contig name (not including the name itself).  Each alias is
+    /// returned with the sequence length it is restricted to (None for aliases from the alias
+    /// file, which apply to sequences of any length)
+    pub fn aliases<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = (&'a Arc<str>, Option<usize>)> {
+        let user = self
+            .lookup
+            .get(name)
+            .map(|i| self.groups[*i].iter())
+            .into_iter()
+            .flatten()
+            .map(|s| (s, None));
+        let builtin = self
+            .builtin_lookup
+            .get(name)
+            .into_iter()
+            .flatten()
+            .flat_map(|i| {
+                let (v, len) = &self.builtin[*i];
+                v.iter().map(move |s| (s, Some(*len)))
+            });
+        user.chain(builtin).filter(move |(s, _)| s.as_ref() != name)
+    }
+
+    /// Read in aliases from file
+    /// Each line has two or more tab separated columns, all of which are alternative names
+    /// for the same contig.  Lines starting with '#' are ignored, so UCSC chromAlias.txt files
+    /// can be used directly.
+    ///
+    pub fn add_from_file<S: AsRef<Path>>(&mut self, fname: S) -> anyhow::Result<()> {
+        debug!(
+            "Reading in contig aliases from {}",
+            fname.as_ref().display()
+        );
+
+        let mut rdr = CompressIo::new()
+            .path(&fname)
+            .bufreader()
+            .with_context(|| format!("Error opening alias file {}", fname.as_ref().display()))?;
+
+        let mut buf = String::new();
+        let mut line = 0;
+
+        while let Some(fields) = get_next_line(&mut rdr, &mut buf).with_context(|| {
+            format!(
+                "Error after reading {} lines from {}",
+                line,
+                fname.as_ref().display()
+            )
+        })? {
+            line += 1;
+
+            // Skip comments and lines without aliases
+            if fields.len() >= 2 && !fields[0].starts_with('#') {
+                let names: Vec<_> = fields.iter().filter(|s| !s.is_empty()).collect();
+                self.add_group(&names)
+            }
+        }
+
+        debug!(
+            "Finished reading in {} lines; {} alias groups",
+            line,
+            self.groups.iter().filter(|g| !g.is_empty()).count()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+/// Reading of BED and bedGraph files
+///
+/// BED files are used for the targets and the training regions, and bedGraph files for the
+/// additional covariate tracks.  Blank, comment, track and browser lines are skipped, and
+/// regions on contigs not in the contig list (matching on the contig names and aliases)
+/// are ignored.
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use utils::get_next_line;
+
+use crate::contig::{contig_alias_lookup, Contig};
+
+/// Region from BED file (start, end, fourth field if present)
+pub type BedRegion = (usize, usize, Option<String>);
+
+/// Read a BED file, calling f with the contig, the start and end positions and the fourth
+/// field (if present) for each region on a contig from the contig list.  Lines with fewer
+/// than min_fields fields are skipped.  desc describes the contents of the file for messages
+pub fn read_bed<S, F>(
+    fname: S,
+    desc: &str,
+    min_fields: usize,
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    S: AsRef<Path>,
+    F: FnMut(&Arc<str>, usize, usize, Option<&str>) -> anyhow::Result<()>,
+{
+    let fname = fname.as_ref();
+    let mut rdr = CompressIo::new()
+        .path(fname)
+        .bufreader()
+        .with_context(|| format!("Error opening {} file {}", desc, fname.display()))?;
+
+    let lookup = contig_alias_lookup(ctg_hash);
+    let mut buf = String::new();
+    let mut line = 0;
+    let mut n_skipped = 0;
+
+    while let Some(fields) = get_next_line(&mut rdr, &mut buf).with_context(|| {
+        format!(
+            "Error after reading {} lines from {}",
+            line,
+            fname.display()
+        )
+    })? {
+        line += 1;
+        // Skip blank and header lines
+        if fields.len() < min_fields.max(3)
+            || fields[0].starts_with('#')
+            || fields[0] == "track"
+            || fields[0] == "browser"
+        {
+            continue;
+        }
+        match lookup.get(fields[0]) {
+            Some(ctg) => {
+                let start = fields[1].parse::<usize>().with_context(|| {
+                    format!("{}:{} Error reading start position", fname.display(), line)
+                })?;
+                let end = fields[2].parse::<usize>().with_context(|| {
+                    format!("{}:{} Error reading end position", fname.display(), line)
+                })?;
+                if end <= start {
+                    return Err(anyhow!(
+                        "{}:{} End position is not after start position",
+                        fname.display(),
+                        line
+                    ));
+                }
+                f(ctg, start, end, fields.get(3).copied())
+                    .with_context(|| format!("{}:{}", fname.display(), line))?
+            }
+            None => n_skipped += 1,
+        }
+    }
+    if n_skipped > 0 {
+        debug!(
+            "Skipped {} {} lines from {} on contigs not in the contig list",
+            n_skipped,
+            desc,
+            fname.display()
+        )
+    }
+    Ok(())
+}
+
+/// Read regions from a BED file, returning the regions for each contig sorted by position
+pub fn read_bed_regions<S: AsRef<Path>>(
+    fname: S,
+    desc: &str,
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+) -> anyhow::Result<HashMap<Arc<str>, Vec<BedRegion>>> {
+    let mut regions: HashMap<Arc<str>, Vec<BedRegion>> = HashMap::new();
+    read_bed(fname, desc, 3, ctg_hash, |ctg, start, end, name| {
+        regions
+            .entry(Arc::clone(ctg))
+            .or_default()
+            .push((start, end, name.map(|s| s.to_owned())));
+        Ok(())
+    })?;
+    for v in regions.values_mut() {
+        v.sort_unstable_by_key(|(s, e, _)| (*s, *e))
+    }
+    Ok(regions)
+}
+
+/// Merge overlapping regions (which must be sorted by position).  The merged region keeps
+/// the fourth field of the first region.  Returns the number of regions merged
+pub fn merge_regions(v: &mut Vec<BedRegion>) -> usize {
+    let mut n_merged = 0;
+    let mut merged: Vec<BedRegion> = Vec::with_capacity(v.len());
+    for (start, end, name) in v.drain(..) {
+        match merged.last_mut() {
+            Some(r) if r.1 > start => {
+                r.1 = r.1.max(end);
+                n_merged += 1
+            }
+            _ => merged.push((start, end, name)),
+        }
+    }
+    *v = merged;
+    n_merged
+}
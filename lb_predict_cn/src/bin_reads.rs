@@ -0,0 +1,118 @@
+/// Per bin read statistics
+///
+/// Optionally, the mapping quality and strand of the reads in each bin are recorded while the
+/// input files are read.  Reads are assigned to the bin containing their start position.  For
+/// each bin the mean MAPQ and the fraction of forward strand reads are calculated from the
+/// reads that are counted (those passing all read filters), and the fraction of reads with
+/// MAPQ 0 is calculated from the counted reads plus the reads removed by the MAPQ filter (so
+/// that this is available even if a MAPQ threshold is set).  These can be used to generate a
+/// mappability mask or to find bins with strand biased artefacts.
+use std::{collections::HashMap, io, io::Write, sync::Arc};
+
+use crate::config::Config;
+
+/// Column names for the read statistics in the coverage output
+pub const BIN_READS_HEADER: [&str; 3] = ["mean_mapq", "mapq0_frac", "forward_frac"];
+
+/// Read statistics for a bin
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BinReads {
+    counted: u32,
+    mapq_sum: u64,
+    forward: u32,
+    unfiltered: u32,
+    mapq0: u32,
+}
+
+impl BinReads {
+    /// Add a read that is either counted or was removed by the MAPQ filter
+    pub fn add(&mut self, mapq: u8, reverse: bool, counted: bool) {
+        self.unfiltered += 1;
+        if mapq == 0 {
+            self.mapq0 += 1
+        }
+        if counted {
+            self.counted += 1;
+            self.mapq_sum += mapq as u64;
+            if !reverse {
+                self.forward += 1
+            }
+        }
+    }
+
+    fn combine(&mut self, other: &Self) {
+        self.counted += other.counted;
+        self.mapq_sum += other.mapq_sum;
+        self.forward += other.forward;
+        self.unfiltered += other.unfiltered;
+        self.mapq0 += other.mapq0;
+    }
+
+    pub fn mean_mapq(&self) -> Option<f64> {
+        if self.counted > 0 {
+            Some((self.mapq_sum as f64) / (self.counted as f64))
+        } else {
+            None
+        }
+    }
+
+    pub fn mapq0_frac(&self) -> Option<f64> {
+        if self.unfiltered > 0 {
+            Some((self.mapq0 as f64) / (self.unfiltered as f64))
+        } else {
+            None
+        }
+    }
+
+    /// Fraction of counted reads on the forward strand
+    pub fn forward_frac(&self) -> Option<f64> {
+        if self.counted > 0 {
+            Some((self.forward as f64) / (self.counted as f64))
+        } else {
+            None
+        }
+    }
+
+    /// Write the statistics as extra columns of the coverage output
+    pub fn write<W: Write>(&self, wrt: &mut W) -> io::Result<()> {
+        for x in [self.mean_mapq(), self.mapq0_frac(), self.forward_frac()] {
+            match x {
+                Some(z) => write!(wrt, "\t{:.4}", z)?,
+                None => write!(wrt, "\tNA")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read statistics per bin for each contig
+pub type BinReadCounts = HashMap<Arc<str>, Vec<BinReads>>;
+
+/// Aggregate read statistics from the smallest block size into blocks k times larger
+pub fn aggregate_bin_reads(br: &BinReadCounts, k: usize) -> BinReadCounts {
+    br.iter()
+        .map(|(ctg, v)| {
+            (
+                Arc::clone(ctg),
+                v.chunks(k)
+                    .map(|c| {
+                        c.iter().fold(BinReads::default(), |mut a, b| {
+                            a.combine(b);
+                            a
+                        })
+                    })
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Read statistics for each block size (resolution level), in the same order as the GC data
+pub fn bin_reads_per_level(cfg: &Config, br: &BinReadCounts) -> Vec<BinReadCounts> {
+    let gc_data = cfg.gc_data();
+    let base = gc_data[0].block_size();
+    gc_data
+        .iter()
+        .map(|gc| aggregate_bin_reads(br, (gc.block_size() / base) as usize))
+        .collect()
+}
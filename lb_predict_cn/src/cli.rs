@@ -1,29 +1,548 @@
 use std::{
+    collections::HashMap,
     num::{NonZeroU32, NonZeroUsize},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use clap::{
     crate_authors, crate_description, crate_name, crate_version, value_parser, Arg, ArgAction,
-    Command,
+    ArgMatches, Command,
 };
 
 use utils::{init_log, LogLevel};
 
 use crate::{
-    config::Config, contig::contig_hash_from_file, gc::GcData, sample::sample_vec_from_file,
+    alias::ContigAlias,
+    config::Config,
+    contig::{contig_alias_lookup, contig_hash_from_file, Contig},
+    covariates::setup_covariates,
+    dilute::Mixture,
+    downsample::DownsampleTarget,
+    gc::{GcData, GcParams},
+    gc_cache::{default_cache_path, gc_data_with_cache},
+    gc_model::SharedGcModel,
+    normalize::{LoessWeight, MAX_LOESS_DEGREE},
+    outliers::OutlierMode,
+    sample::{merge_list_from_file, sample_vec_from_file, Sample},
+    targets::Targets,
+    training::TrainingRegions,
 };
 
+/// Task selected from the command line
+pub enum Task {
+    Process(Config),
+    MakeGcCache(GcCacheTask),
+    Rebin(RebinTask),
+    Dilute(DiluteTask),
+}
+
+/// Parameters for generating the GC cache files (gc subcommand)
+pub struct GcCacheTask {
+    pub gc_data: Vec<GcData>,
+    pub reference: PathBuf,
+    pub caches: Vec<PathBuf>,
+}
+
+/// Parameters for rebinning or merging existing coverage files (rebin and merge subcommands)
+/// runs has the list of directories with existing coverage files for each sample
+pub struct RebinTask {
+    pub cfg: Config,
+    pub runs: Vec<Vec<PathBuf>>,
+    pub input_prefix: String,
+    pub input_block_size: u32,
+    pub merge: bool,
+}
+
+/// Parameters for generating an in-silico dilution series (dilute subcommand)
+pub struct DiluteTask {
+    pub cfg: Config,
+    pub tumour: PathBuf,
+    pub normal: PathBuf,
+    pub mixtures: Vec<Mixture>,
+    pub seed: u64,
+    pub read_list: bool,
+}
+
+fn block_size_arg() -> Arg {
+    Arg::new("block_size")
+        .short('b')
+        .long("block-size")
+        .value_parser(value_parser!(NonZeroU32))
+        .value_name("INT")
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .default_value("10000")
+        .help("Set block size(s) in base pairs (comma separated list for multiple resolutions)")
+}
+
+fn alias_file_arg() -> Arg {
+    Arg::new("alias_file")
+        .short('A')
+        .long("alias-file")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("FILE")
+        .help("Input file with contig aliases (in addition to built in human aliases)")
+}
+
+fn gc_cache_arg() -> Arg {
+    Arg::new("gc_cache")
+        .short('g')
+        .long("gc-cache")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("FILE")
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .help(
+            "Set GC cache file(s), one per block size [default: <REFERENCE_FILE>.<BLOCK_SIZE>.gcc]",
+        )
+}
+
+fn threads_arg() -> Arg {
+    Arg::new("threads")
+        .short('t')
+        .long("threads")
+        .value_parser(value_parser!(NonZeroUsize))
+        .value_name("INT")
+        .help("Set number of threads [default: available cores]")
+}
+
+fn input_block_size_arg() -> Arg {
+    Arg::new("input_block_size")
+        .short('i')
+        .long("input-block-size")
+        .value_parser(value_parser!(NonZeroU32))
+        .value_name("INT")
+        .default_value("10000")
+        .help("Block size of existing coverage files")
+}
+
+fn input_prefix_arg() -> Arg {
+    Arg::new("input_prefix")
+        .short('P')
+        .long("input-prefix")
+        .value_parser(value_parser!(String))
+        .value_name("STRING")
+        .default_value("cov")
+        .help("Set prefix of existing coverage file names")
+}
+
+fn hts_threads_arg() -> Arg {
+    Arg::new("hts_threads")
+        .short('@')
+        .long("hts-threads")
+        .value_parser(value_parser!(NonZeroUsize))
+        .value_name("INT")
+        .help("Set number of threads for sam/bam/cram reading [default: available cores]")
+}
+
+fn seed_arg() -> Arg {
+    Arg::new("seed")
+        .short('S')
+        .long("seed")
+        .value_parser(value_parser!(u64))
+        .value_name("INT")
+        .default_value("42")
+        .help("Seed for sampling reads")
+}
+
+/// Options controlling which reads are counted
+fn read_filter_args() -> [Arg; 6] {
+    [
+        Arg::new("mapq")
+            .short('Q')
+            .long("mapq")
+            .value_parser(value_parser!(u8))
+            .value_name("INT")
+            .default_value("0")
+            .help("Minimum MAPQ for reads"),
+        Arg::new("qual")
+            .short('q')
+            .long("qual")
+            .value_parser(value_parser!(u8))
+            .value_name("INT")
+            .default_value("0")
+            .help("Minimum base quality"),
+        Arg::new("max_template_len")
+            .short('m')
+            .long("max-template-len")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .help("Set maximum template length"),
+        Arg::new("min_template_len")
+            .short('M')
+            .long("min-template-len")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .default_value("0")
+            .help("Set minimum template length"),
+        Arg::new("keep_duplicates")
+            .short('k')
+            .long("keep-duplicates")
+            .action(ArgAction::SetTrue)
+            .help("Do not remove duplicates"),
+        Arg::new("ignore_dup_flag")
+            .short('D')
+            .long("ignore-duplicate-flag")
+            .action(ArgAction::SetTrue)
+            .help("Ignore duplicate flag in input file"),
+    ]
+}
+
+fn gc_model_arg() -> Arg {
+    Arg::new("gc_model")
+        .long("gc-model")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("FILE")
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .help("GC model file(s) to use for all samples, one per block size (comma separated list)")
+}
+
+/// Options controlling the LOESS fit of the GC model
+fn loess_args() -> [Arg; 5] {
+    [
+        Arg::new("loess_span")
+            .long("loess-span")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .default_value("31")
+            .help("Number of GC bins used for each local fit in the GC model"),
+        Arg::new("loess_degree")
+            .long("loess-degree")
+            .value_parser(value_parser!(u8).range(0..=MAX_LOESS_DEGREE as i64))
+            .value_name("INT")
+            .default_value("2")
+            .help("Degree of local polynomial in the GC model"),
+        Arg::new("loess_weight")
+            .long("loess-weight")
+            .value_parser(value_parser!(LoessWeight))
+            .value_name("WEIGHT")
+            .default_value("count")
+            .help("Weighting of GC bins in the GC model [count, inv-var]"),
+        Arg::new("loess_iterations")
+            .long("loess-iterations")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .default_value("0")
+            .help("Number of robustness iterations for the GC model"),
+        Arg::new("min_gc_pred")
+            .long("min-gc-pred")
+            .value_parser(value_parser!(f64))
+            .value_name("FLOAT")
+            .default_value("10")
+            .help("Minimum predicted count per bin for a GC bin to be used"),
+    ]
+}
+
+fn gc_param_args() -> [Arg; 3] {
+    [
+        Arg::new("gc_bins")
+            .long("gc-bins")
+            .value_parser(value_parser!(u32).range(2..u16::MAX as i64))
+            .value_name("INT")
+            .default_value("128")
+            .help("Number of GC bins"),
+        Arg::new("min_valid_frac")
+            .long("min-valid-frac")
+            .value_parser(value_parser!(f64))
+            .value_name("FLOAT")
+            .default_value("0.9")
+            .help("Minimum fraction of valid (non N) bases for a bin to be used"),
+        Arg::new("gc_window_ext")
+            .long("gc-window-ext")
+            .value_parser(value_parser!(u32))
+            .value_name("INT")
+            .default_value("0")
+            .help("Extend window for GC calculation by this many bases either side of each bin"),
+    ]
+}
+
+fn training_args() -> [Arg; 3] {
+    [
+        Arg::new("training_regions")
+            .long("training-regions")
+            .value_parser(value_parser!(PathBuf))
+            .value_name("FILE")
+            .help("BED file with regions used for training the GC model"),
+        Arg::new("gc_refit_iterations")
+            .long("gc-refit-iterations")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .default_value("0")
+            .help("Maximum iterations excluding deviant bins and refitting the GC model"),
+        Arg::new("gc_refit_threshold")
+            .long("gc-refit-threshold")
+            .value_parser(value_parser!(f64))
+            .value_name("FLOAT")
+            .default_value("0.5")
+            .help("Exclude bins with smoothed copy number further than this from 2 when refitting"),
+    ]
+}
+
+fn outlier_args() -> [Arg; 4] {
+    [
+        Arg::new("outliers")
+            .long("outliers")
+            .value_parser(value_parser!(OutlierMode))
+            .value_name("MODE")
+            .help("Flag or winsorize outlier bins [flag, winsorize]"),
+        Arg::new("outlier_k")
+            .long("outlier-k")
+            .value_parser(value_parser!(f64))
+            .value_name("FLOAT")
+            .default_value("5")
+            .help("Bins more than this many MADs from the running median are outliers"),
+        Arg::new("outlier_window")
+            .long("outlier-window")
+            .value_parser(value_parser!(usize))
+            .value_name("INT")
+            .default_value("11")
+            .help("Number of bins for running median and MAD"),
+        Arg::new("normalized_outliers")
+            .long("normalized-outliers")
+            .action(ArgAction::SetTrue)
+            .requires("outliers")
+            .help("Also detect outliers in normalized coverage"),
+    ]
+}
+
+fn mito_contig_arg() -> Arg {
+    Arg::new("mito_contig")
+        .long("mito-contig")
+        .value_parser(value_parser!(String))
+        .value_name("CONTIG")
+        .help("Mitochondrial contig for mtDNA QC metrics (or 'none') [default: chrM if present]")
+}
+
+fn covariates_arg() -> Arg {
+    Arg::new("covariates")
+        .long("covariates")
+        .value_parser(value_parser!(String))
+        .value_name("LIST")
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .help("Additional covariates for residual regression (cpg or bedGraph files)")
+}
+
+fn prefix_arg() -> Arg {
+    Arg::new("prefix")
+        .short('p')
+        .long("prefix")
+        .value_parser(value_parser!(String))
+        .value_name("STRING")
+        .default_value("cov")
+        .help("Set prefix for output file names")
+}
+
+fn dir_arg() -> Arg {
+    Arg::new("dir")
+        .short('d')
+        .long("dir")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("PATH")
+        .help("Set output directory [default: current directory]")
+}
+
+fn no_gc_cache_arg() -> Arg {
+    Arg::new("no_gc_cache")
+        .long("no-gc-cache")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("gc_cache")
+        .help("Do not read or write GC cache file")
+}
+
+fn sample_file_arg() -> Arg {
+    Arg::new("sample_file")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("SAMPLE_FILE")
+        .required(true)
+        .help("Input file with list of sample names and file paths")
+}
+
+fn contig_file_arg() -> Arg {
+    Arg::new("contig_file")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("CONTIG_FILE")
+        .required(true)
+        .help("Input file with list of contig names")
+}
+
+fn reference_file_arg() -> Arg {
+    Arg::new("reference_file")
+        .value_parser(value_parser!(PathBuf))
+        .value_name("REFERENCE_FILE")
+        .required(true)
+        .help("Input FASTA file with reference sequence")
+}
+
+/// Set up definition of gc subcommand
+fn gc_cli_model() -> Command {
+    Command::new("gc")
+        .about("Calculate GC content of genomic bins and store in GC cache file")
+        .arg(block_size_arg())
+        .arg(threads_arg())
+        .arg(alias_file_arg())
+        .arg(gc_cache_arg())
+        .args(gc_param_args())
+        .arg(contig_file_arg())
+        .arg(reference_file_arg())
+}
+
+/// Set up definition of rebin subcommand
+fn rebin_cli_model() -> Command {
+    Command::new("rebin")
+        .about("Aggregate existing coverage files into larger bins and repeat the GC normalization")
+        .arg(block_size_arg())
+        .arg(input_block_size_arg())
+        .arg(
+            Arg::new("input_dir")
+                .short('I')
+                .long("input-dir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("PATH")
+                .help("Set directory with existing coverage files [default: current directory]"),
+        )
+        .arg(input_prefix_arg())
+        .arg(threads_arg())
+        .arg(prefix_arg())
+        .arg(dir_arg())
+        .args(gc_param_args())
+        .args(loess_args())
+        .arg(covariates_arg())
+        .args(training_args())
+        .args(outlier_args())
+        .arg(mito_contig_arg())
+        .arg(gc_model_arg())
+        .arg(alias_file_arg())
+        .arg(gc_cache_arg())
+        .arg(no_gc_cache_arg())
+        .arg(sample_file_arg())
+        .arg(contig_file_arg())
+        .arg(reference_file_arg())
+}
+
+/// Set up definition of merge subcommand
+fn merge_cli_model() -> Command {
+    Command::new("merge")
+        .about("Merge existing coverage files from multiple runs of the same sample and repeat the GC normalization")
+        .arg(block_size_arg())
+        .arg(input_block_size_arg())
+        .arg(input_prefix_arg())
+        .arg(threads_arg())
+        .arg(prefix_arg())
+        .arg(dir_arg())
+        .args(gc_param_args())
+        .args(loess_args())
+        .arg(covariates_arg())
+        .args(training_args())
+        .args(outlier_args())
+        .arg(mito_contig_arg())
+        .arg(gc_model_arg())
+        .arg(alias_file_arg())
+        .arg(gc_cache_arg())
+        .arg(no_gc_cache_arg())
+        .arg(
+            Arg::new("merge_file")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("MERGE_FILE")
+                .required(true)
+                .help("Input file with list of sample names and directories of runs to merge"),
+        )
+        .arg(contig_file_arg())
+        .arg(reference_file_arg())
+}
+
+/// Set up definition of dilute subcommand
+fn dilute_cli_model() -> Command {
+    Command::new("dilute")
+        .about(
+            "Generate in-silico dilution series by mixing reads from tumour and normal input files",
+        )
+        .arg(
+            Arg::new("tumour")
+                .short('T')
+                .long("tumour")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .required(true)
+                .help("Tumour SAM/BAM/CRAM file"),
+        )
+        .arg(
+            Arg::new("normal")
+                .short('N')
+                .long("normal")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .required(true)
+                .help("Normal SAM/BAM/CRAM file"),
+        )
+        .arg(
+            Arg::new("fractions")
+                .short('f')
+                .long("fractions")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .required(true)
+                .help("Fraction(s) of coverage from tumour file (comma separated list)"),
+        )
+        .arg(
+            Arg::new("depths")
+                .short('c')
+                .long("depths")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .required(true)
+                .help("Mean coverage(s) of mixtures (comma separated list)"),
+        )
+        .arg(seed_arg())
+        .arg(
+            Arg::new("read_list")
+                .short('L')
+                .long("read-list")
+                .action(ArgAction::SetTrue)
+                .help("Write lists of sampled read names for each mixture"),
+        )
+        .arg(block_size_arg())
+        .args(read_filter_args())
+        .arg(threads_arg())
+        .arg(hts_threads_arg())
+        .arg(prefix_arg())
+        .arg(dir_arg())
+        .args(gc_param_args())
+        .args(loess_args())
+        .arg(covariates_arg())
+        .args(training_args())
+        .args(outlier_args())
+        .arg(mito_contig_arg())
+        .arg(alias_file_arg())
+        .arg(gc_cache_arg())
+        .arg(no_gc_cache_arg())
+        .arg(contig_file_arg())
+        .arg(reference_file_arg())
+}
+
 /// Set up definition of command options for clap
 fn cli_model() -> Command {
     Command::new(crate_name!())
         .about(crate_description!())
         .version(crate_version!())
         .author(crate_authors!())
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(gc_cli_model())
+        .subcommand(rebin_cli_model())
+        .subcommand(merge_cli_model())
+        .subcommand(dilute_cli_model())
         .arg(
             Arg::new("timestamp")
                 .short('X')
                 .long("timestamp")
+                .global(true)
                 .value_parser(value_parser!(stderrlog::Timestamp))
                 .value_name("GRANULARITY")
                 .default_value("none")
@@ -35,6 +554,7 @@ fn cli_model() -> Command {
                 .long("loglevel")
                 .value_name("LOGLEVEL")
                 .value_parser(value_parser!(LogLevel))
+                .global(true)
                 .ignore_case(true)
                 .default_value("warn")
                 .help("Set log level"),
@@ -43,18 +563,11 @@ fn cli_model() -> Command {
             Arg::new("quiet")
                 .action(ArgAction::SetTrue)
                 .long("quiet")
+                .global(true)
                 .conflicts_with("loglevel")
                 .help("Silence all output"),
         )
-        .arg(
-            Arg::new("block_size")
-                .short('b')
-                .long("block-size")
-                .value_parser(value_parser!(NonZeroU32))
-                .value_name("INT")
-                .default_value("10000")
-                .help("Set block size in base pairs"),
-        )
+        .arg(block_size_arg())
         .arg(
             Arg::new("threads")
                 .short('t')
@@ -63,14 +576,7 @@ fn cli_model() -> Command {
                 .value_name("INT")
                 .help("Set number of calculation threads [default: available cores]"),
         )
-        .arg(
-            Arg::new("hts_threads")
-                .short('@')
-                .long("hts-threads")
-                .value_parser(value_parser!(NonZeroUsize))
-                .value_name("INT")
-                .help("Set number of threads for sam/bam/cram reading [default: available cores]"),
-        )
+        .arg(hts_threads_arg())
         .arg(
             Arg::new("readers")
                 .short('R')
@@ -79,97 +585,557 @@ fn cli_model() -> Command {
                 .value_name("INT")
                 .help("Set maximum number of file readers operating simultaneously for sam/bam/cram reading [default: (threads + 3) / 4]"),
         )
+        .args(read_filter_args())
         .arg(
-            Arg::new("mapq")
-                .short('Q')
-                .long("mapq")
-                .value_parser(value_parser!(u8))
-                .value_name("INT")
-                .default_value("0")
-                .help("Minimum MAPQ for reads"),
-        )
-        .arg(
-            Arg::new("qual")
-                .short('q')
-                .long("qual")
-                .value_parser(value_parser!(u8))
-                .value_name("INT")
-                .default_value("0")
-                .help("Minimum base quality"),
-        )
-        .arg(
-            Arg::new("max_template_len")
-                .short('m')
-                .long("max-template-len")
-                .value_parser(value_parser!(usize))
+            Arg::new("target_fragments")
+                .long("target-fragments")
+                .value_parser(value_parser!(NonZeroUsize))
                 .value_name("INT")
-                .help("Set maximum template length"),
+                .help("Downsample samples to target number of fragments"),
         )
         .arg(
-            Arg::new("prefix")
-                .short('p')
-                .long("prefix")
-                .value_parser(value_parser!(String))
-                .value_name("STRING")
-                .default_value("cov")
-                .help("Set prefix for output file names"),
+            Arg::new("target_coverage")
+                .long("target-coverage")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .conflicts_with("target_fragments")
+                .help("Downsample samples to target mean coverage"),
         )
+        .arg(seed_arg())
         .arg(
-            Arg::new("dir")
-                .short('d')
-                .long("dir")
-                .value_parser(value_parser!(PathBuf))
-                .value_name("PATH")
-                .help("Set output directory [default: current directory]"),
+            Arg::new("fragment_gc")
+                .long("fragment-gc")
+                .action(ArgAction::SetTrue)
+                .help("Perform fragment level GC bias correction"),
         )
         .arg(
-            Arg::new("min_template_len")
-                .short('M')
-                .long("min-template-len")
-                .value_parser(value_parser!(usize))
-                .value_name("INT")
-                .default_value("0")
-                .help("Set minimum template length"),
+            Arg::new("bin_read_stats")
+                .long("bin-read-stats")
+                .action(ArgAction::SetTrue)
+                .help("Output mean MAPQ, MAPQ 0 fraction and strand balance per bin"),
         )
         .arg(
-            Arg::new("keep_duplicates")
-                .short('k')
-                .long("keep-duplicates")
+            Arg::new("pooled_gc")
+                .long("pooled-gc")
                 .action(ArgAction::SetTrue)
-                .help("Do not remove duplicates"),
+                .conflicts_with("gc_model")
+                .help("Fit GC model pooled across all samples"),
         )
         .arg(
-            Arg::new("ignore_dup_flag")
-                .short('D')
-                .long("ignore-duplicate-flag")
-                .action(ArgAction::SetTrue)
-                .help("Ignore duplicate flag in input file"),
+            Arg::new("gc_tag")
+                .long("gc-tag")
+                .value_parser(value_parser!(String))
+                .value_name("TAG")
+                .help("Write copies of input files with GC correction weights in TAG"),
         )
         .arg(
-            Arg::new("sample_file")
+            Arg::new("targets")
+                .long("targets")
                 .value_parser(value_parser!(PathBuf))
-                .value_name("SAMPLE_FILE")
-                .required(true)
-                .help("Input file with list of sample names and file paths"),
+                .value_name("BED")
+                .help("BED file with target regions (for hybrid-capture panels)"),
         )
         .arg(
-            Arg::new("contig_file")
-                .value_parser(value_parser!(PathBuf))
-                .value_name("CONTIG_FILE")
-                .required(true)
-                .help("Input file with list of contig names"),
+            Arg::new("target_padding")
+                .long("target-padding")
+                .value_parser(value_parser!(usize))
+                .value_name("INT")
+                .default_value("250")
+                .requires("targets")
+                .help("Padding around targets excluded from off-target bins"),
         )
+        .arg(prefix_arg())
+        .arg(dir_arg())
         .arg(
-            Arg::new("reference_file")
-                .value_parser(value_parser!(PathBuf))
-                .value_name("REFERENCE_FILE")
-                .required(true)
-                .help("Input FASTA file with reference sequence"),
+            Arg::new("no_validate")
+                .long("no-validate")
+                .action(ArgAction::SetTrue)
+                .help("Do not check input files against reference before processing"),
         )
+        .args(gc_param_args())
+        .args(loess_args())
+        .arg(covariates_arg())
+        .args(training_args())
+        .args(outlier_args())
+        .arg(mito_contig_arg())
+        .arg(gc_model_arg())
+        .arg(alias_file_arg())
+        .arg(gc_cache_arg())
+        .arg(no_gc_cache_arg())
+        .arg(sample_file_arg())
+        .arg(contig_file_arg())
+        .arg(reference_file_arg())
+}
+
+/// Read in contig list, using aliases from the alias file if supplied
+fn read_contigs(m: &ArgMatches) -> anyhow::Result<HashMap<Arc<str>, Contig>> {
+    // Set up contig aliases
+    let mut alias = ContigAlias::builtin();
+    if let Some(p) = m.get_one::<PathBuf>("alias_file") {
+        alias.add_from_file(p)?
+    }
+
+    // Read in contig list
+    contig_hash_from_file(m.get_one::<PathBuf>("contig_file").unwrap(), &alias)
+}
+
+/// Get list of block sizes, sorted in increasing order.  The coverage is counted using the
+/// smallest block size and aggregated for the larger sizes, so all block sizes must be
+/// multiples of the smallest.  The block sizes are returned in increasing order
+fn get_block_sizes(m: &ArgMatches) -> anyhow::Result<Vec<u32>> {
+    let mut v = block_size_list(m);
+    v.sort_unstable();
+    if let Some(w) = v.windows(2).find(|w| w[0] == w[1]) {
+        Err(anyhow!("Block size {} given more than once", w[0]))
+    } else if let Some(x) = v.iter().find(|x| *x % v[0] != 0) {
+        Err(anyhow!(
+            "Block size {} is not a multiple of smallest block size {}",
+            x,
+            v[0]
+        ))
+    } else {
+        Ok(v)
+    }
+}
+
+/// Get parameters for calculation of GC bins.  The window extension can not be
+/// larger than the smallest block size
+fn get_gc_params(m: &ArgMatches, block_sizes: &[u32]) -> anyhow::Result<GcParams> {
+    let n_gc_bins = *m.get_one::<u32>("gc_bins").expect("Missing default");
+    let min_valid_frac = *m.get_one::<f64>("min_valid_frac").expect("Missing default");
+    if !(0.0..=1.0).contains(&min_valid_frac) {
+        return Err(anyhow!("Minimum valid fraction must be between 0 and 1"));
+    }
+    let window_ext = *m.get_one::<u32>("gc_window_ext").expect("Missing default");
+    if window_ext > block_sizes[0] {
+        return Err(anyhow!(
+            "GC window extension {} is larger than the smallest block size {}",
+            window_ext,
+            block_sizes[0]
+        ));
+    }
+    Ok(GcParams::new(n_gc_bins, min_valid_frac, window_ext))
+}
+
+/// Set up additional covariates for regression of the GC model residuals.  Returns the
+/// indices of the covariates in the GC data
+fn get_covariates(
+    m: &ArgMatches,
+    gc_data: &mut [GcData],
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+) -> anyhow::Result<Vec<usize>> {
+    match m.get_many::<String>("covariates") {
+        Some(v) => {
+            let v: Vec<_> = v.collect();
+            setup_covariates(&v, gc_data, ctg_hash)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Block sizes in the order given on the command line
+fn block_size_list(m: &ArgMatches) -> Vec<u32> {
+    m.get_many::<NonZeroU32>("block_size")
+        .unwrap()
+        .map(|x| u32::from(*x))
+        .collect()
+}
+
+/// Get list of GC cache files (one per block size, in the same order as block_sizes).  The
+/// cache files are given in the same order as the block sizes on the command line
+fn get_gc_caches(
+    m: &ArgMatches,
+    reference: &Path,
+    block_sizes: &[u32],
+) -> anyhow::Result<Vec<PathBuf>> {
+    match m.get_many::<PathBuf>("gc_cache") {
+        Some(c) => {
+            let v: Vec<_> = c.cloned().collect();
+            let bs = block_size_list(m);
+            if v.len() == bs.len() {
+                let mut caches: Vec<_> = bs.into_iter().zip(v).collect();
+                caches.sort_unstable_by_key(|(b, _)| *b);
+                Ok(caches.into_iter().map(|(_, p)| p).collect())
+            } else {
+                Err(anyhow!(
+                    "Number of GC cache files ({}) does not match number of block sizes ({})",
+                    v.len(),
+                    bs.len()
+                ))
+            }
+        }
+        None => Ok(block_sizes
+            .iter()
+            .map(|bs| default_cache_path(reference, *bs))
+            .collect()),
+    }
+}
+
+/// Set read filter options in Config
+fn set_read_filter_options(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(x) = m.get_one::<usize>("min_template_len") {
+        cfg.set_min_template_len(*x)?
+    }
+    if let Some(x) = m.get_one::<usize>("max_template_len") {
+        cfg.set_max_template_len(*x)?
+    }
+    if let Some(x) = m.get_one::<u8>("mapq") {
+        cfg.set_min_mapq(*x)
+    }
+    if let Some(x) = m.get_one::<u8>("qual") {
+        cfg.set_min_qual(*x)
+    }
+    if m.get_flag("keep_duplicates") {
+        cfg.set_keep_duplicates()
+    }
+    if m.get_flag("ignore_dup_flag") {
+        cfg.set_ignore_dup_flag()
+    }
+    Ok(())
+}
+
+/// Set options for the selection of bins used for training the GC model
+fn set_training_options(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(p) = m.get_one::<PathBuf>("training_regions") {
+        let t = TrainingRegions::from_bed(p, cfg.gc_data(), cfg.ctg_hash())?;
+        cfg.set_training_regions(t)
+    }
+    if let Some(x) = m.get_one::<usize>("gc_refit_iterations") {
+        cfg.set_gc_refit_iterations(*x)
+    }
+    if let Some(x) = m.get_one::<f64>("gc_refit_threshold") {
+        if *x <= 0.0 {
+            return Err(anyhow!("GC refit threshold must be positive"));
+        }
+        cfg.set_gc_refit_threshold(*x)
+    }
+    Ok(())
+}
+
+/// Set options for outlier detection
+fn set_outlier_options(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(mode) = m.get_one::<OutlierMode>("outliers") {
+        cfg.set_outlier_mode(*mode)
+    }
+    if let Some(x) = m.get_one::<f64>("outlier_k") {
+        if *x <= 0.0 {
+            return Err(anyhow!("Outlier threshold must be positive"));
+        }
+        cfg.set_outlier_k(*x)
+    }
+    if let Some(x) = m.get_one::<usize>("outlier_window") {
+        if *x < 3 {
+            return Err(anyhow!("Outlier window must be at least 3 bins"));
+        }
+        cfg.set_outlier_window(*x)
+    }
+    if m.get_flag("normalized_outliers") {
+        cfg.set_normalized_outliers()
+    }
+    Ok(())
+}
+
+/// Set the mitochondrial contig if requested.  The contig must be in the contig list (possibly
+/// under an alias); 'none' turns off the mtDNA metrics
+fn set_mito_contig(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(s) = m.get_one::<String>("mito_contig") {
+        let ctg = if s.eq_ignore_ascii_case("none") {
+            None
+        } else {
+            let lookup = contig_alias_lookup(cfg.ctg_hash());
+            match lookup.get(s.as_str()) {
+                Some(c) => Some(Arc::clone(c)),
+                None => return Err(anyhow!("Mitochondrial contig {} not in contig list", s)),
+            }
+        };
+        cfg.set_mito_contig(ctg)
+    }
+    Ok(())
+}
+
+/// Read in shared GC model if requested
+fn set_gc_model_option(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(v) = m.get_many::<PathBuf>("gc_model") {
+        let files: Vec<_> = v.collect();
+        let block_sizes: Vec<_> = cfg.gc_data().iter().map(|g| g.block_size()).collect();
+        let params = *cfg.gc_data()[0].params();
+        cfg.set_gc_model(SharedGcModel::from_files(&files, &block_sizes, &params)?)
+    }
+    Ok(())
+}
+
+/// Set options for the LOESS fit of the GC model in Config
+fn set_loess_options(cfg: &mut Config, m: &ArgMatches) -> anyhow::Result<()> {
+    let span = *m.get_one::<usize>("loess_span").expect("Missing default");
+    let degree = *m.get_one::<u8>("loess_degree").expect("Missing default") as usize;
+    // The furthest point in each window has zero weight, so we need
+    // at least degree + 2 points
+    if span < degree + 2 {
+        return Err(anyhow!(
+            "LOESS span must be at least {} for a local polynomial of degree {}",
+            degree + 2,
+            degree
+        ));
+    }
+    cfg.set_loess_span(span);
+    cfg.set_loess_degree(degree);
+    if let Some(w) = m.get_one::<LoessWeight>("loess_weight") {
+        cfg.set_loess_weight(*w)
+    }
+    if let Some(x) = m.get_one::<usize>("loess_iterations") {
+        cfg.set_loess_iterations(*x)
+    }
+    if let Some(x) = m.get_one::<f64>("min_gc_pred") {
+        if *x < 0.0 {
+            return Err(anyhow!("Minimum GC prediction must not be negative"));
+        }
+        cfg.set_min_gc_pred(*x)
+    }
+    Ok(())
+}
+
+/// Handle options for the gc subcommand
+fn handle_gc_cli(m: &ArgMatches) -> anyhow::Result<GcCacheTask> {
+    let ctg_hash = read_contigs(m)?;
+
+    let nt = m
+        .get_one::<NonZeroUsize>("threads")
+        .map(|x| usize::from(*x))
+        .unwrap_or_else(num_cpus::get);
+
+    let block_sizes = get_block_sizes(m)?;
+
+    let reference = m
+        .get_one::<PathBuf>("reference_file")
+        .expect("Missing reference file")
+        .clone();
+
+    let caches = get_gc_caches(m, &reference, &block_sizes)?;
+    let params = get_gc_params(m, &block_sizes)?;
+
+    // MD5 digests are always calculated so that the cache can be used for validation
+    let gc_data = GcData::from_reference(&reference, &block_sizes, params, nt, &ctg_hash, true)?;
+
+    Ok(GcCacheTask {
+        gc_data,
+        reference,
+        caches,
+    })
+}
+
+/// Set up RebinTask for rebin and merge subcommands
+fn make_rebin_task(
+    m: &ArgMatches,
+    samples: Vec<Sample>,
+    runs: Vec<Vec<PathBuf>>,
+    merge: bool,
+) -> anyhow::Result<RebinTask> {
+    let ctg_hash = read_contigs(m)?;
+
+    let nt = m
+        .get_one::<NonZeroUsize>("threads")
+        .map(|x| usize::from(*x))
+        .unwrap_or_else(num_cpus::get);
+
+    let input_block_size = u32::from(*m.get_one::<NonZeroU32>("input_block_size").unwrap());
+    let block_sizes = get_block_sizes(m)?;
+    if block_sizes[0] % input_block_size != 0 {
+        return Err(anyhow!(
+            "Block size {} is not a multiple of input block size {}",
+            block_sizes[0],
+            input_block_size
+        ));
+    }
+
+    let reference = m
+        .get_one::<PathBuf>("reference_file")
+        .expect("Missing reference file")
+        .clone();
+
+    let caches = if m.get_flag("no_gc_cache") {
+        None
+    } else {
+        Some(get_gc_caches(m, &reference, &block_sizes)?)
+    };
+    let gc_params = get_gc_params(m, &block_sizes)?;
+    let mut gc_data = gc_data_with_cache(
+        caches.as_deref(),
+        &reference,
+        &block_sizes,
+        gc_params,
+        nt,
+        &ctg_hash,
+        false,
+    )?;
+    let covariates = get_covariates(m, &mut gc_data, &ctg_hash)?;
+
+    let prefix = m
+        .get_one::<String>("prefix")
+        .expect("Missing default prefix")
+        .clone();
+
+    let mut cfg = Config::new(samples, ctg_hash, gc_data, reference, prefix);
+    cfg.set_covariates(covariates);
+    set_loess_options(&mut cfg, m)?;
+    set_training_options(&mut cfg, m)?;
+    set_outlier_options(&mut cfg, m)?;
+    set_mito_contig(&mut cfg, m)?;
+    set_gc_model_option(&mut cfg, m)?;
+    if let Some(p) = m.get_one::<PathBuf>("dir") {
+        cfg.set_output_dir(p)
+    }
+    cfg.set_n_tasks(nt);
+
+    Ok(RebinTask {
+        cfg,
+        runs,
+        input_prefix: m
+            .get_one::<String>("input_prefix")
+            .expect("Missing default input prefix")
+            .clone(),
+        input_block_size,
+        merge,
+    })
+}
+
+/// Handle options for the rebin subcommand
+fn handle_rebin_cli(m: &ArgMatches) -> anyhow::Result<RebinTask> {
+    let samples = sample_vec_from_file(
+        m.get_one::<PathBuf>("sample_file")
+            .expect("Missing sample list file"),
+    )?;
+
+    // Existing coverage files are in <input_dir>/<sample name>
+    let input_dir = m.get_one::<PathBuf>("input_dir");
+    let runs = samples
+        .iter()
+        .map(|s| {
+            vec![match input_dir {
+                Some(d) => d.join(s.name()),
+                None => PathBuf::from(s.name()),
+            }]
+        })
+        .collect();
+
+    let mut task = make_rebin_task(m, samples, runs, false)?;
+
+    // Always add the block size to the output file names so that the existing files
+    // are not overwritten
+    task.cfg.set_tag_block_size();
+    Ok(task)
+}
+
+/// Handle options for the merge subcommand
+fn handle_merge_cli(m: &ArgMatches) -> anyhow::Result<RebinTask> {
+    let (samples, runs) = merge_list_from_file(
+        m.get_one::<PathBuf>("merge_file")
+            .expect("Missing merge file"),
+    )?
+    .into_iter()
+    .unzip();
+    make_rebin_task(m, samples, runs, true)
+}
+
+/// Handle options for the dilute subcommand
+fn handle_dilute_cli(m: &ArgMatches) -> anyhow::Result<DiluteTask> {
+    let ctg_hash = read_contigs(m)?;
+
+    let nt = m
+        .get_one::<NonZeroUsize>("threads")
+        .map(|x| usize::from(*x))
+        .unwrap_or_else(num_cpus::get);
+
+    let hts_threads = m
+        .get_one::<NonZeroUsize>("hts_threads")
+        .map(|x| usize::from(*x))
+        .unwrap_or_else(num_cpus::get);
+
+    // Mixtures are all combinations of the requested fractions and depths
+    let mut mixtures = Vec::new();
+    for f in m.get_many::<f64>("fractions").unwrap() {
+        if !(0.0..=1.0).contains(f) {
+            return Err(anyhow!("Tumour fraction {} not between 0 and 1", f));
+        }
+        for d in m.get_many::<f64>("depths").unwrap() {
+            if *d <= 0.0 {
+                return Err(anyhow!("Depth {} is not positive", d));
+            }
+            // Mixtures with the same fraction and depth would have the same output directory
+            let mx = Mixture::new(*f, *d);
+            if mixtures.iter().any(|x: &Mixture| x.name() == mx.name()) {
+                return Err(anyhow!(
+                    "Tumour fraction {} with depth {} requested more than once",
+                    f,
+                    d
+                ));
+            }
+            mixtures.push(mx)
+        }
+    }
+
+    let tumour = m.get_one::<PathBuf>("tumour").unwrap().clone();
+    let normal = m.get_one::<PathBuf>("normal").unwrap().clone();
+
+    let block_sizes = get_block_sizes(m)?;
+
+    let reference = m
+        .get_one::<PathBuf>("reference_file")
+        .expect("Missing reference file")
+        .clone();
+
+    let caches = if m.get_flag("no_gc_cache") {
+        None
+    } else {
+        Some(get_gc_caches(m, &reference, &block_sizes)?)
+    };
+    let gc_params = get_gc_params(m, &block_sizes)?;
+    let mut gc_data = gc_data_with_cache(
+        caches.as_deref(),
+        &reference,
+        &block_sizes,
+        gc_params,
+        nt,
+        &ctg_hash,
+        false,
+    )?;
+    let covariates = get_covariates(m, &mut gc_data, &ctg_hash)?;
+
+    let prefix = m
+        .get_one::<String>("prefix")
+        .expect("Missing default prefix")
+        .clone();
+
+    // Each mixture is treated as a sample for output
+    let samples = mixtures
+        .iter()
+        .map(|mx| Sample::new(mx.name(), &tumour))
+        .collect();
+
+    let mut cfg = Config::new(samples, ctg_hash, gc_data, reference, prefix);
+    cfg.set_covariates(covariates);
+    set_loess_options(&mut cfg, m)?;
+    set_training_options(&mut cfg, m)?;
+    set_outlier_options(&mut cfg, m)?;
+    set_mito_contig(&mut cfg, m)?;
+    set_read_filter_options(&mut cfg, m)?;
+    if let Some(p) = m.get_one::<PathBuf>("dir") {
+        cfg.set_output_dir(p)
+    }
+    cfg.set_hts_threads(hts_threads);
+    cfg.set_n_tasks(nt);
+
+    Ok(DiluteTask {
+        cfg,
+        tumour,
+        normal,
+        mixtures,
+        seed: *m.get_one::<u64>("seed").unwrap(),
+        read_list: m.get_flag("read_list"),
+    })
 }
 
 /// Handle command line options.  Set up Config structure
-pub fn handle_cli() -> anyhow::Result<Config> {
+pub fn handle_cli() -> anyhow::Result<Task> {
     // Get matches from command line
     let m = cli_model().get_matches();
 
@@ -178,8 +1144,15 @@ pub fn handle_cli() -> anyhow::Result<Config> {
 
     debug!("Processing command line options");
 
-    // Read in contig list
-    let ctg_hash = contig_hash_from_file(m.get_one::<PathBuf>("contig_file").unwrap())?;
+    match m.subcommand() {
+        Some(("gc", m)) => return Ok(Task::MakeGcCache(handle_gc_cli(m)?)),
+        Some(("rebin", m)) => return Ok(Task::Rebin(handle_rebin_cli(m)?)),
+        Some(("merge", m)) => return Ok(Task::Rebin(handle_merge_cli(m)?)),
+        Some(("dilute", m)) => return Ok(Task::Dilute(handle_dilute_cli(m)?)),
+        _ => (),
+    }
+
+    let ctg_hash = read_contigs(&m)?;
 
     // Set up threads
     let nt = m
@@ -197,7 +1170,7 @@ pub fn handle_cli() -> anyhow::Result<Config> {
         .map(|x| usize::from(*x))
         .unwrap_or_else(|| (nt + 3) >> 2);
 
-    let block_size = u32::from(*m.get_one::<NonZeroU32>("block_size").unwrap());
+    let block_sizes = get_block_sizes(&m)?;
 
     let reference = m
         .get_one::<PathBuf>("reference_file")
@@ -205,7 +1178,24 @@ pub fn handle_cli() -> anyhow::Result<Config> {
         .clone();
 
     // Set up gc information from reference
-    let gc_data = GcData::from_reference(&reference, block_size, nt, &ctg_hash)?;
+    // If the input files are to be validated we also need the MD5 digests of the reference contigs
+    let validate = !m.get_flag("no_validate");
+    let caches = if m.get_flag("no_gc_cache") {
+        None
+    } else {
+        Some(get_gc_caches(&m, &reference, &block_sizes)?)
+    };
+    let gc_params = get_gc_params(&m, &block_sizes)?;
+    let mut gc_data = gc_data_with_cache(
+        caches.as_deref(),
+        &reference,
+        &block_sizes,
+        gc_params,
+        nt,
+        &ctg_hash,
+        validate,
+    )?;
+    let covariates = get_covariates(&m, &mut gc_data, &ctg_hash)?;
 
     let prefix = m
         .get_one::<String>("prefix")
@@ -217,37 +1207,69 @@ pub fn handle_cli() -> anyhow::Result<Config> {
             .expect("Missing sample list file"),
     )?;
 
+    let targets = match m.get_one::<PathBuf>("targets") {
+        Some(p) => Some(Targets::from_bed(
+            p,
+            *m.get_one::<usize>("target_padding").unwrap(),
+            &reference,
+            gc_params,
+            &ctg_hash,
+        )?),
+        None => None,
+    };
+
     let mut cfg = Config::new(samples, ctg_hash, gc_data, reference, prefix);
+    cfg.set_covariates(covariates);
+    set_loess_options(&mut cfg, &m)?;
+    set_training_options(&mut cfg, &m)?;
+    set_outlier_options(&mut cfg, &m)?;
+    set_mito_contig(&mut cfg, &m)?;
 
-    if let Some(x) = m.get_one::<usize>("min_template_len") {
-        cfg.set_min_template_len(*x)?
+    if let Some(t) = targets {
+        cfg.set_targets(t)
     }
-    if let Some(x) = m.get_one::<usize>("max_template_len") {
-        cfg.set_max_template_len(*x)?
+
+    set_gc_model_option(&mut cfg, &m)?;
+    if m.get_flag("pooled_gc") {
+        cfg.set_pooled_gc()
     }
 
-    if let Some(p) = m.get_one::<PathBuf>("dir") {
-        cfg.set_output_dir(p)
+    if m.get_flag("fragment_gc") {
+        cfg.set_fragment_gc()
     }
 
-    if let Some(x) = m.get_one::<u8>("mapq") {
-        cfg.set_min_mapq(*x)
+    if m.get_flag("bin_read_stats") {
+        cfg.set_bin_read_stats()
     }
-    if let Some(x) = m.get_one::<u8>("qual") {
-        cfg.set_min_qual(*x)
+
+    if let Some(t) = m.get_one::<String>("gc_tag") {
+        cfg.set_gc_tag(t)?
     }
-    if m.get_flag("keep_duplicates") {
-        cfg.set_keep_duplicates()
+
+    set_read_filter_options(&mut cfg, &m)?;
+
+    if let Some(p) = m.get_one::<PathBuf>("dir") {
+        cfg.set_output_dir(p)
     }
-    if m.get_flag("ignore_dup_flag") {
-        cfg.set_ignore_dup_flag()
+
+    if !validate {
+        cfg.set_no_validate()
     }
 
     cfg.set_hts_threads(hts_threads);
 
-    cfg.set_block_size(block_size);
+    if let Some(x) = m.get_one::<NonZeroUsize>("target_fragments") {
+        cfg.set_downsample(DownsampleTarget::Fragments(usize::from(*x)))
+    } else if let Some(x) = m.get_one::<f64>("target_coverage") {
+        if *x <= 0.0 {
+            return Err(anyhow!("Target coverage must be positive"));
+        }
+        cfg.set_downsample(DownsampleTarget::Coverage(*x))
+    }
+    cfg.set_seed(*m.get_one::<u64>("seed").unwrap());
+
     cfg.set_n_tasks(nt);
     cfg.set_n_readers(n_readers);
 
-    Ok(cfg)
+    Ok(Task::Process(cfg))
 }
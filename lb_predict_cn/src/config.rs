@@ -4,7 +4,19 @@ use std::{
     sync::Arc,
 };
 
-use crate::{contig::Contig, gc::GcData, sample::Sample};
+use crate::{
+    contig::{find_mito_contig, Contig},
+    downsample::DownsampleTarget,
+    gc::GcData,
+    gc_model::SharedGcModel,
+    input::is_cram,
+    normalize::LoessWeight,
+    outliers::OutlierMode,
+    sample::Sample,
+    targets::Targets,
+    training::TrainingRegions,
+    twobit::is_two_bit,
+};
 
 /// Config
 ///
@@ -14,21 +26,43 @@ use crate::{contig::Contig, gc::GcData, sample::Sample};
 ///
 /// sample_list - list of input samples
 /// ctg_hash - look up hash for contigs
-/// gc_data - data on gc content per bin across the genome
+/// gc_data - data on gc content per bin across the genome (one entry per block size, in increasing order)
 /// reference - path to reference (FASTA) file
 /// output_dir - output directory
-/// block_size - block size for coverage
 /// min_template_len - minimum allowed template (fragment) length
 /// max_template_len - maximum allowed template length
 /// threads - number of threads
+/// validate - check compatibility of input files with reference before processing
+/// tag_block_size - add block size to output file names even if only one block size is used
+/// downsample - optional target depth for downsampling samples
+/// seed - seed for selecting reads when downsampling
+/// targets - optional target regions (for hybrid-capture panels)
+/// fragment_gc - perform fragment level GC bias correction
+/// gc_tag - if set, write tagged copies of the input files with the GC weights in this tag
+/// loess_span - number of GC bins used for each local fit in the GC model
+/// loess_degree - degree of local polynomial in the GC model
+/// loess_weight - weighting of GC bins in the GC model
+/// loess_iterations - number of robustness iterations for the GC model
+/// min_gc_pred - minimum predicted count per bin for a GC bin to be used
+/// gc_model - optional GC model to be used for all samples instead of fitting per sample
+/// pooled_gc - fit a GC model pooled across all samples
+/// covariates - indices of covariates (in gc_data) regressed on the residuals of the GC model
+/// training_regions - optional regions used for training the GC model
+/// gc_refit_iterations - maximum number of iterations excluding deviant bins and refitting the GC model
+/// gc_refit_threshold - bins with running median normalized coverage further than this from 2 are excluded
+/// outlier_mode - if set, outlier bins are flagged or winsorized
+/// outlier_k - bins further than outlier_k x scale from the running median are outliers
+/// outlier_window - number of bins for the running median and scale
+/// normalized_outliers - also detect outliers in the normalized coverage
+/// mito_contig - mitochondrial contig (if present in the contig list) used for the mtDNA QC metrics
+/// bin_read_stats - collect per bin read statistics (MAPQ and strand) and write as extra output columns
 ///
 pub struct Config {
     sample_list: Vec<Sample>,
     ctg_hash: HashMap<Arc<str>, Contig>,
-    gc_data: GcData,
+    gc_data: Vec<GcData>,
     reference: PathBuf,
     output_dir: Option<PathBuf>,
-    block_size: u32,
     min_template_len: usize,
     max_template_len: Option<usize>,
     min_mapq: u8,
@@ -39,16 +73,41 @@ pub struct Config {
     hts_threads: usize,
     n_tasks: usize,
     n_readers: usize,
+    validate: bool,
+    tag_block_size: bool,
+    downsample: Option<DownsampleTarget>,
+    seed: u64,
+    targets: Option<Targets>,
+    fragment_gc: bool,
+    gc_tag: Option<String>,
+    loess_span: usize,
+    loess_degree: usize,
+    loess_weight: LoessWeight,
+    loess_iterations: usize,
+    min_gc_pred: f64,
+    gc_model: Option<SharedGcModel>,
+    pooled_gc: bool,
+    covariates: Vec<usize>,
+    training_regions: Option<TrainingRegions>,
+    gc_refit_iterations: usize,
+    gc_refit_threshold: f64,
+    outlier_mode: Option<OutlierMode>,
+    outlier_k: f64,
+    outlier_window: usize,
+    normalized_outliers: bool,
+    mito_contig: Option<Arc<str>>,
+    bin_read_stats: bool,
 }
 
 impl Config {
     pub fn new(
         sample_list: Vec<Sample>,
         ctg_hash: HashMap<Arc<str>, Contig>,
-        gc_data: GcData,
+        gc_data: Vec<GcData>,
         reference: PathBuf,
         output_prefix: String,
     ) -> Self {
+        let mito_contig = find_mito_contig(&ctg_hash);
         Self {
             sample_list,
             ctg_hash,
@@ -56,7 +115,6 @@ impl Config {
             reference,
             output_prefix,
             output_dir: None,
-            block_size: 1000,
             min_template_len: 0,
             max_template_len: None,
             keep_duplicates: false,
@@ -66,6 +124,30 @@ impl Config {
             hts_threads: 1,
             n_tasks: 1,
             n_readers: 1,
+            validate: true,
+            tag_block_size: false,
+            downsample: None,
+            seed: 42,
+            targets: None,
+            fragment_gc: false,
+            gc_tag: None,
+            loess_span: 31,
+            loess_degree: 2,
+            loess_weight: LoessWeight::Count,
+            loess_iterations: 0,
+            min_gc_pred: 10.0,
+            gc_model: None,
+            pooled_gc: false,
+            covariates: Vec::new(),
+            training_regions: None,
+            gc_refit_iterations: 0,
+            gc_refit_threshold: 0.5,
+            outlier_mode: None,
+            outlier_k: 5.0,
+            outlier_window: 11,
+            normalized_outliers: false,
+            mito_contig,
+            bin_read_stats: false,
         }
     }
 
@@ -73,10 +155,6 @@ impl Config {
         self.output_dir = Some(dir.as_ref().to_owned())
     }
 
-    pub fn set_block_size(&mut self, bs: u32) {
-        self.block_size = bs
-    }
-
     pub fn set_min_template_len(&mut self, x: usize) -> anyhow::Result<()> {
         self.min_template_len = x;
         match self.max_template_len {
@@ -110,6 +188,117 @@ impl Config {
         }
     }
 
+    pub fn set_no_validate(&mut self) {
+        self.validate = false
+    }
+
+    pub fn set_tag_block_size(&mut self) {
+        self.tag_block_size = true
+    }
+
+    pub fn set_downsample(&mut self, target: DownsampleTarget) {
+        self.downsample = Some(target)
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed
+    }
+
+    pub fn set_targets(&mut self, targets: Targets) {
+        self.targets = Some(targets)
+    }
+
+    pub fn set_fragment_gc(&mut self) {
+        self.fragment_gc = true
+    }
+
+    /// Set the tag for the GC tagged copies of the input files.  CRAM input files give
+    /// CRAM output, which requires a FASTA reference
+    pub fn set_gc_tag(&mut self, tag: &str) -> anyhow::Result<()> {
+        let b = tag.as_bytes();
+        if !(b.len() == 2 && b[0].is_ascii_alphabetic() && b[1].is_ascii_alphanumeric()) {
+            return Err(anyhow!("Invalid SAM tag {}", tag));
+        }
+        if is_two_bit(&self.reference) {
+            if let Some(s) = self.sample_list.iter().find(|s| is_cram(s.input_path())) {
+                return Err(anyhow!(
+                    "GC tagged output for CRAM input file {} requires a FASTA reference (not 2bit)",
+                    s.input_path().display()
+                ));
+            }
+        }
+        self.gc_tag = Some(tag.to_owned());
+        Ok(())
+    }
+
+    pub fn set_loess_span(&mut self, x: usize) {
+        self.loess_span = x
+    }
+
+    pub fn set_loess_degree(&mut self, x: usize) {
+        self.loess_degree = x
+    }
+
+    pub fn set_loess_weight(&mut self, w: LoessWeight) {
+        self.loess_weight = w
+    }
+
+    pub fn set_loess_iterations(&mut self, x: usize) {
+        self.loess_iterations = x
+    }
+
+    pub fn set_min_gc_pred(&mut self, x: f64) {
+        self.min_gc_pred = x
+    }
+
+    pub fn set_gc_model(&mut self, model: SharedGcModel) {
+        self.gc_model = Some(model)
+    }
+
+    pub fn set_pooled_gc(&mut self) {
+        self.pooled_gc = true
+    }
+
+    pub fn set_covariates(&mut self, v: Vec<usize>) {
+        self.covariates = v
+    }
+
+    pub fn set_training_regions(&mut self, t: TrainingRegions) {
+        self.training_regions = Some(t)
+    }
+
+    pub fn set_gc_refit_iterations(&mut self, x: usize) {
+        self.gc_refit_iterations = x
+    }
+
+    pub fn set_gc_refit_threshold(&mut self, x: f64) {
+        self.gc_refit_threshold = x
+    }
+
+    pub fn set_outlier_mode(&mut self, mode: OutlierMode) {
+        self.outlier_mode = Some(mode)
+    }
+
+    pub fn set_outlier_k(&mut self, x: f64) {
+        self.outlier_k = x
+    }
+
+    pub fn set_outlier_window(&mut self, x: usize) {
+        self.outlier_window = x
+    }
+
+    pub fn set_normalized_outliers(&mut self) {
+        self.normalized_outliers = true
+    }
+
+    pub fn set_mito_contig(&mut self, ctg: Option<Arc<str>>) {
+        self.mito_contig = ctg
+    }
+
+    pub fn set_bin_read_stats(&mut self) {
+        self.bin_read_stats = true
+    }
+
     pub fn set_hts_threads(&mut self, x: usize) {
         self.hts_threads = x
     }
@@ -130,7 +319,8 @@ impl Config {
         &self.ctg_hash
     }
 
-    pub fn gc_data(&self) -> &GcData {
+    /// GC data for each block size (resolution level)
+    pub fn gc_data(&self) -> &[GcData] {
         &self.gc_data
     }
 
@@ -146,8 +336,23 @@ impl Config {
         self.output_dir.as_deref()
     }
 
+    /// Block size used for counting coverage (the smallest block size)
     pub fn block_size(&self) -> u32 {
-        self.block_size
+        self.gc_data[0].block_size()
+    }
+
+    /// Output prefix for a resolution level.  If multiple block sizes are used (or if
+    /// tag_block_size is set) then the block size is added to the prefix
+    pub fn level_prefix(&self, level: usize) -> String {
+        if self.gc_data.len() > 1 || self.tag_block_size {
+            format!(
+                "{}_{}",
+                self.output_prefix(),
+                self.gc_data[level].block_size()
+            )
+        } else {
+            self.output_prefix().to_owned()
+        }
     }
 
     pub fn min_template_len(&self) -> usize {
@@ -185,4 +390,96 @@ impl Config {
     pub fn ignore_dup_flag(&self) -> bool {
         self.ignore_dup_flag
     }
+
+    pub fn validate(&self) -> bool {
+        self.validate
+    }
+
+    pub fn downsample(&self) -> Option<DownsampleTarget> {
+        self.downsample
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn targets(&self) -> Option<&Targets> {
+        self.targets.as_ref()
+    }
+
+    pub fn fragment_gc(&self) -> bool {
+        self.fragment_gc
+    }
+
+    pub fn gc_tag(&self) -> Option<&str> {
+        self.gc_tag.as_deref()
+    }
+
+    pub fn loess_span(&self) -> usize {
+        self.loess_span
+    }
+
+    pub fn loess_degree(&self) -> usize {
+        self.loess_degree
+    }
+
+    pub fn loess_weight(&self) -> LoessWeight {
+        self.loess_weight
+    }
+
+    pub fn loess_iterations(&self) -> usize {
+        self.loess_iterations
+    }
+
+    pub fn min_gc_pred(&self) -> f64 {
+        self.min_gc_pred
+    }
+
+    pub fn gc_model(&self) -> Option<&SharedGcModel> {
+        self.gc_model.as_ref()
+    }
+
+    pub fn pooled_gc(&self) -> bool {
+        self.pooled_gc
+    }
+
+    pub fn covariates(&self) -> &[usize] {
+        &self.covariates
+    }
+
+    pub fn training_regions(&self) -> Option<&TrainingRegions> {
+        self.training_regions.as_ref()
+    }
+
+    pub fn gc_refit_iterations(&self) -> usize {
+        self.gc_refit_iterations
+    }
+
+    pub fn gc_refit_threshold(&self) -> f64 {
+        self.gc_refit_threshold
+    }
+
+    pub fn outlier_mode(&self) -> Option<OutlierMode> {
+        self.outlier_mode
+    }
+
+    pub fn outlier_k(&self) -> f64 {
+        self.outlier_k
+    }
+
+    pub fn outlier_window(&self) -> usize {
+        self.outlier_window
+    }
+
+    pub fn normalized_outliers(&self) -> bool {
+        self.normalized_outliers
+    }
+
+    pub fn mito_contig(&self) -> Option<&Arc<str>> {
+        self.mito_contig.as_ref()
+    }
+
+    pub fn bin_read_stats(&self) -> bool {
+        self.bin_read_stats
+    }
 }
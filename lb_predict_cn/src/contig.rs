@@ -5,22 +5,34 @@ use compress_io::compress::CompressIo;
 
 use utils::get_next_line;
 
+use crate::alias::ContigAlias;
+
 /// Contig
 ///
 /// name - this is shared across many data structures so we use Rc<str>
 /// use_for_normalization - whether to use this contig for sample normalization (normally set for the autosomes)
+/// non_host - contig is not part of the host genome (i.e., a viral genome)
+/// aliases - alternative names for the contig in the reference or input files, with the sequence
+///           length the alias is restricted to (if any)
 ///
 pub struct Contig {
     name: Arc<str>,
     use_for_normalization: bool,
+    non_host: bool,
+    aliases: Vec<(Arc<str>, Option<usize>)>,
 }
 
 impl Contig {
-    fn new(name: &str, flag: bool) -> Self {
-        trace!("Creating new contig {}, normalize: {}", name, flag);
+    fn new(name: &str, class: ContigClass, alias: &ContigAlias) -> Self {
+        trace!("Creating new contig {}, class: {:?}", name, class);
         Self {
             name: Arc::from(name.to_owned()),
-            use_for_normalization: flag,
+            use_for_normalization: class == ContigClass::Normalize,
+            non_host: class == ContigClass::NonHost,
+            aliases: alias
+                .aliases(name)
+                .map(|(s, l)| (Arc::clone(s), l))
+                .collect(),
         }
     }
 
@@ -36,13 +48,64 @@ impl Contig {
     pub fn use_for_normalization(&self) -> bool {
         self.use_for_normalization
     }
+
+    pub fn non_host(&self) -> bool {
+        self.non_host
+    }
+
+    /// Iterate over the contig name followed by any aliases
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_ref()).chain(self.aliases.iter().map(|(s, _)| s.as_ref()))
+    }
+
+    /// Check whether a sequence called name with length seq_len in the reference or an input
+    /// file is this contig.  Aliases restricted to a sequence length (the built in aliases)
+    /// only match sequences of that length
+    pub fn matches_seq(&self, name: &str, seq_len: usize) -> bool {
+        name == self.name.as_ref()
+            || self
+                .aliases
+                .iter()
+                .any(|(s, l)| s.as_ref() == name && l.map(|x| x == seq_len).unwrap_or(true))
+    }
+}
+
+/// Make a look up table from contig names and aliases to the contig names used in the contig hash
+pub fn contig_alias_lookup(ctg_hash: &HashMap<Arc<str>, Contig>) -> HashMap<&str, &Arc<str>> {
+    ctg_hash
+        .iter()
+        .flat_map(|(k, c)| c.names().map(move |s| (s, k)))
+        .collect()
 }
 
-fn parse_bool(s: &str) -> anyhow::Result<bool> {
+/// Names used to identify the mitochondrial contig
+const MITO_NAMES: [&str; 4] = ["chrM", "MT", "M", "chrMT"];
+
+/// Find the mitochondrial contig in the contig list (matching on the contig names and aliases)
+pub fn find_mito_contig(ctg_hash: &HashMap<Arc<str>, Contig>) -> Option<Arc<str>> {
+    ctg_hash
+        .values()
+        .find(|c| c.names().any(|s| MITO_NAMES.contains(&s)))
+        .map(|c| Arc::clone(c.name()))
+}
+
+/// Class of contig from the contig file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ContigClass {
+    Normalize,
+    NoNormalize,
+    NonHost,
+}
+
+fn parse_class(s: &str) -> anyhow::Result<ContigClass> {
     match s.to_ascii_lowercase().as_str() {
-        "true" | "1" | "yes" => Ok(true),
-        "false" | "0" | "no" => Ok(false),
-        _ => Err(anyhow!("Could not parse {} as bool", s)),
+        "true" | "1" | "yes" => Ok(ContigClass::Normalize),
+        "false" | "0" | "no" => Ok(ContigClass::NoNormalize),
+        "non-host" | "non_host" | "nonhost" => Ok(ContigClass::NonHost),
+        _ => Err(anyhow!(
+            "Could not parse {} as bool or non-host contig class",
+            s
+        )),
     }
 }
 
@@ -51,9 +114,14 @@ fn parse_bool(s: &str) -> anyhow::Result<bool> {
 /// The first column has the contig name
 /// The second column, if present, should be 0/no/false or 1/yes/true to indicate
 /// whether or not the contig should be used for normalization.  If absent, ttue is assumed.
+/// Contigs that are not part of the host genome (i.e., viral genomes) are marked with non-host
+/// in the second column; these are not used for normalization.
+/// The contig names in the file are used for output; alternative names for matching
+/// the reference and input files are taken from alias.
 ///
 pub fn contig_hash_from_file<S: AsRef<Path>>(
     fname: S,
+    alias: &ContigAlias,
 ) -> anyhow::Result<HashMap<Arc<str>, Contig>> {
     debug!("Reading in contig list from {}", fname.as_ref().display());
 
@@ -80,12 +148,28 @@ pub fn contig_hash_from_file<S: AsRef<Path>>(
         // Parse input line and store to hash if valid
         if !fields.is_empty() {
             // Skip blank lines
-            let flag = match fields.get(1) {
-                Some(s) => parse_bool(*s)
+            let class = match fields.get(1) {
+                Some(s) => parse_class(s)
                     .with_context(|| format!("Error at {}:{}", fname.as_ref().display(), line))?,
-                None => true,
+                None => ContigClass::Normalize,
             };
-            Contig::new(fields[0], flag).add_to_hash(&mut ctg_hash)
+            Contig::new(fields[0], class, alias).add_to_hash(&mut ctg_hash)
+        }
+    }
+
+    // Check that no two contigs are aliases of each other
+    for (k, c) in ctg_hash.iter() {
+        if let Some((a, _)) = c
+            .aliases
+            .iter()
+            .find(|(a, _)| ctg_hash.contains_key(a.as_ref()))
+        {
+            return Err(anyhow!(
+                "Contigs {} and {} from contig file {} are aliases of each other",
+                k,
+                a,
+                fname.as_ref().display()
+            ));
         }
     }
 
@@ -13,34 +13,77 @@
 /// Possible job types are:
 ///
 ///   ReadData - read a contig (if file is indexed) or all contigs for a sample
+///   FitPooledModel - Fit a GC model pooled across all samples
 ///   NormalizeSample - Perform GC normalization on all contigs of a sample
 ///   OutputSampleCtg - Output a contig for a processed sample
+///   OutputSampleTargets - Output the target coverage for a processed sample
 ///   Wait - No jobs are available, but more will be available in future
 ///
-///   After processing a ReadData job the child tasks will return Completed::RawCounts.  When
-///   all contigs have been processed for a sample it will be eligible for Normalization.
+///   After processing a ReadData job the child tasks will return Completed::RawCounts (with
+///   the binned counts, the counts per target if targets have been supplied and the read
+///   counters for QC).  When all contigs have been processed for a sample it will be eligible
+///   for Normalization.  The read counters for each contig are combined, and are sent with
+///   the NormalizeSample job so that the QC table can be written.
 ///
-///   Processing of a NormalizeSample jobs will result in Completed::NormalizeCounts. As this
-///   is for a complete sample this will be immediately eligible for Output
+///   If a pooled GC model is requested, no samples are normalized until all samples have been
+///   read.  A FitPooledModel job is then sent with the counts for all samples, and the child
+///   task returns Completed::PooledModel with the model and the counts.  The pooled model is
+///   then sent with each NormalizeSample job.
+///
+///   Samples where not all contigs were read (because a contig was not found in the input file)
+///   are reported as failed once all samples have been read, and are not used for the pooled
+///   GC model.
+///
+///   Processing of a NormalizeSample jobs will result in Completed::NormalizeCounts (with one
+///   set of normalized counts per block size plus the normalized target counts if present, and
+///   the per bin read statistics per block size if requested).  As this is for a complete
+///   sample this will be immediately eligible for Output
+///
+///   If normalization fails for a sample, Completed::Failed is returned with the reason.  No
+///   output is generated for the sample, but processing of the other samples continues.  If
+///   fitting the pooled GC model fails, Completed::Failed is returned for all samples.  An
+///   error is returned once all jobs are finished
 ///
 ///   Processing of an output job has no results returned (just a request for a new job)
 ///
-use std::{collections::hash_map, fmt, sync::Arc};
+use std::{collections::hash_map, fmt, mem, sync::Arc};
 
 use anyhow::Context;
 use crossbeam_channel::{Receiver, Sender};
 use r_htslib::*;
 
 use crate::{
+    bin_reads::{BinReadCounts, BinReads},
     config::Config,
     coverage::{Coverage, NormCov, RawCounts},
+    gc_model::SharedGcModel,
+    qc::ReadStats,
     sample::Sample,
 };
 
+/// Counts for a sample (sample id, binned counts, target counts)
+pub type SampleCounts = (usize, RawCounts, RawCounts);
+
+/// Normalized output for a sample (sample id, normalized counts per block size, target
+/// counts, read statistics per block size)
+type SampleOutput = (
+    usize,
+    Vec<NormCov>,
+    Option<NormCov>,
+    Option<Vec<BinReadCounts>>,
+);
+
 pub enum JobType {
     ReadData(Option<Arc<str>>),
-    NormalizeSample(RawCounts),
-    OutputSampleCtg(usize, Arc<str>, Coverage),
+    FitPooledModel(Vec<SampleCounts>),
+    NormalizeSample(
+        RawCounts,
+        RawCounts,
+        Box<ReadStats>,
+        Option<Arc<SharedGcModel>>,
+    ), // (binned counts, target counts, read counters, pooled GC model)
+    OutputSampleCtg(usize, usize, Arc<str>, Coverage, Option<Vec<BinReads>>), // (sample id, block size level, contig, coverage, read statistics)
+    OutputSampleTargets(usize, NormCov), // (sample id, target coverage)
     Wait, // No jobs currently available, but there will be jobs in the future
 }
 
@@ -48,10 +91,12 @@ impl fmt::Debug for JobType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::ReadData(s) => write!(f, "JobType::ReadData({:?})", s),
-            Self::NormalizeSample(_) => f.write_str("JobType::NormalizeSample"),
-            Self::OutputSampleCtg(i, s, _) => {
-                write!(f, "JobType::OutputSampleCtg({}, {:?})", *i, s)
+            Self::FitPooledModel(_) => f.write_str("JobType::FitPooledModel"),
+            Self::NormalizeSample(_, _, _, _) => f.write_str("JobType::NormalizeSample"),
+            Self::OutputSampleCtg(i, l, s, _, _) => {
+                write!(f, "JobType::OutputSampleCtg({}, {}, {:?})", *i, *l, s)
             }
+            Self::OutputSampleTargets(i, _) => write!(f, "JobType::OutputSampleTargets({})", *i),
             Self::Wait => f.write_str("JobType::Wait"),
         }
     }
@@ -59,16 +104,27 @@ impl fmt::Debug for JobType {
 
 /// The child tasks send their results as Completed objects
 pub enum Completed {
-    RawCounts(usize, RawCounts), // (sample id, raw (un-normalized) counts)
-    NormalizedCounts(usize, NormCov), // (sample id, normalized and raw counts
+    RawCounts(usize, RawCounts, RawCounts, ReadStats), // (sample id, raw (un-normalized) counts, raw target counts, read counters)
+    NormalizedCounts(
+        usize,
+        Vec<NormCov>,
+        Option<NormCov>,
+        Option<Vec<BinReadCounts>>,
+    ), // (sample id, normalized and raw counts per block size, target counts, read statistics per block size)
+    Failed(Vec<usize>, String), // (sample ids, reason) Normalization failed for samples
+    PooledModel(SharedGcModel, Vec<SampleCounts>), // (pooled GC model, counts for all samples)
     None, // This is returned either initially or after a task receives a Wait or OutputSampleCtg job
 }
 
 impl fmt::Debug for Completed {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::RawCounts(i, _) => write!(f, "Completed::RawCounts(Sample {})", *i),
-            Self::NormalizedCounts(i, _) => write!(f, "Completed::NormalizedCounts(Sample {})", *i),
+            Self::RawCounts(i, _, _, _) => write!(f, "Completed::RawCounts(Sample {})", *i),
+            Self::NormalizedCounts(i, _, _, _) => {
+                write!(f, "Completed::NormalizedCounts(Sample {})", *i)
+            }
+            Self::Failed(v, _) => write!(f, "Completed::Failed(Samples {:?})", v),
+            Self::PooledModel(_, _) => f.write_str("Completed::PooledModel"),
             Self::None => f.write_str("Completed::None"),
         }
     }
@@ -100,18 +156,22 @@ impl Tracker {
     fn update_at_send(&mut self, job: &Job) {
         match job.job_type {
             JobType::ReadData(_) => self.n_read_jobs_pending += 1,
-            JobType::NormalizeSample(_) => self.n_normalize_jobs_pending += 1,
+            JobType::NormalizeSample(_, _, _, _) | JobType::FitPooledModel(_) => {
+                self.n_normalize_jobs_pending += 1
+            }
             _ => (),
         }
     }
 
     fn update_at_recv(&mut self, jr: &JobRequest) {
         match jr.prev_results {
-            Completed::RawCounts(_, _) => {
+            Completed::RawCounts(_, _, _, _) => {
                 assert!(self.n_read_jobs_pending > 0);
                 self.n_read_jobs_pending -= 1;
             }
-            Completed::NormalizedCounts(_, _) => {
+            Completed::NormalizedCounts(_, _, _, _)
+            | Completed::Failed(_, _)
+            | Completed::PooledModel(_, _) => {
                 assert!(self.n_normalize_jobs_pending > 0);
                 self.n_normalize_jobs_pending -= 1;
             }
@@ -124,28 +184,57 @@ impl Tracker {
     }
 }
 
+/// Output for one contig at one block size (block size level, contig, coverage, read statistics)
+type CtgOutput = (usize, Arc<str>, Coverage, Option<Vec<BinReads>>);
+
 /// A sample that is currently being output
 struct OnGoingOutput {
     sample_idx: usize,
-    norm_cov: Vec<(Arc<str>, Coverage)>,
+    norm_cov: Vec<CtgOutput>,
+    target_cov: Option<NormCov>,
 }
 
 impl OnGoingOutput {
-    fn new(sample_idx: usize, mut nc: NormCov) -> Self {
+    fn new(
+        sample_idx: usize,
+        mut nc: Vec<NormCov>,
+        target_cov: Option<NormCov>,
+        mut bin_reads: Option<Vec<BinReadCounts>>,
+    ) -> Self {
         trace!("OngoingOutput::new({})", sample_idx);
-        let norm_cov: Vec<_> = nc.drain().collect();
+        let norm_cov: Vec<_> = nc
+            .drain(..)
+            .enumerate()
+            .flat_map(|(level, mut h)| {
+                h.drain()
+                    .map(|(ctg, c)| {
+                        let br = bin_reads.as_mut().and_then(|b| b[level].remove(&ctg));
+                        (level, ctg, c, br)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
         Self {
             sample_idx,
             norm_cov,
+            target_cov,
         }
     }
 
     fn next_job(&mut self) -> Option<Job> {
         trace!("OngoingOutput::next_job({})", self.sample_idx);
-        self.norm_cov.pop().map(|(ctg, c)| Job {
-            sample_idx: self.sample_idx,
-            job_type: JobType::OutputSampleCtg(self.sample_idx, ctg, c),
-        })
+        self.norm_cov
+            .pop()
+            .map(|(level, ctg, c, br)| Job {
+                sample_idx: self.sample_idx,
+                job_type: JobType::OutputSampleCtg(self.sample_idx, level, ctg, c, br),
+            })
+            .or_else(|| {
+                self.target_cov.take().map(|t| Job {
+                    sample_idx: self.sample_idx,
+                    job_type: JobType::OutputSampleTargets(self.sample_idx, t),
+                })
+            })
     }
 }
 
@@ -227,24 +316,47 @@ impl<'a, T> InputFile<'a, T> {
 /// Selects an InputFile with pending contigs from sample_vec.  
 /// Starts looking at index idx and processed through the whole vector,
 /// wrapping around if required. On return idx will be set to the next
-/// index after the selected sample (if the selection is made)
+/// index after the selected sample (if the selection is made).  Returns None
+/// only if all samples have finished
 fn get_new_read_job<'a, T>(
     sample_vec: &mut [InputFile<'a, T>],
     idx: &mut usize,
 ) -> anyhow::Result<Option<Job>> {
-    // Find first sample with available samples starting from *idx (and wrapping around)
+    // Try each sample with available contigs starting from *idx (and wrapping around)
     let l = sample_vec.len();
     for _ in 0..l {
-        if !sample_vec[*idx].check_finished()? {
-            break;
-        }
+        let i = *idx;
         *idx = (*idx + 1) % l;
+        if !sample_vec[i].check_finished()? {
+            if let Some(job) = sample_vec[i].next_job() {
+                return Ok(Some(job));
+            }
+        }
     }
+    Ok(None)
+}
 
-    // Get job (if available) from selected sample
-    let job = sample_vec[*idx].next_job();
-    *idx = (*idx + 1) % l;
-    Ok(job)
+/// Report samples where not all contigs were read as failed.  This can happen if a contig is
+/// not found in the input file (i.e., if validation is turned off)
+fn fail_incomplete_samples(
+    cfg: &Config,
+    sample_data: &mut [Option<(RawCounts, RawCounts)>],
+    failed: &mut Vec<(usize, String)>,
+) {
+    for (i, d) in sample_data.iter_mut().enumerate() {
+        if let Some((cts, _)) = d.take() {
+            let mut missing: Vec<_> = cfg
+                .ctg_hash()
+                .keys()
+                .filter(|c| !cts.contains_key(*c))
+                .map(|c| c.as_ref())
+                .collect();
+            missing.sort_unstable();
+            let s = format!("contigs not found in input file: {}", missing.join(", "));
+            error!("Sample {}: {}", cfg.sample_list()[i].name(), s);
+            failed.push((i, s))
+        }
+    }
 }
 
 /// Main loop.  Recieves messages from child tasks and allocates jobs appropriately.  Will
@@ -273,13 +385,23 @@ pub fn controller(
     let read_job_limit = cfg.n_readers();
 
     // Tracking for samples to be normalized
-    let mut sample_data: Vec<Option<RawCounts>> = vec![None; ns];
-    let mut pending_norm: Vec<(usize, RawCounts)> = Vec::new();
+    let mut sample_data: Vec<Option<(RawCounts, RawCounts)>> = vec![None; ns];
+    let mut pending_norm: Vec<SampleCounts> = Vec::new();
+
+    // Read counters for each sample (combined across contigs)
+    let mut read_stats = vec![ReadStats::default(); ns];
+
+    // Pooled GC model (if requested)
+    let mut pooled_model: Option<Arc<SharedGcModel>> = None;
+    let mut fitting_pooled = false;
 
     // Tracking for samples/ctgs still to be output
-    let mut pending_output: Vec<(usize, NormCov)> = Vec::new();
+    let mut pending_output: Vec<SampleOutput> = Vec::new();
     let mut ongoing_output: Option<OnGoingOutput> = None;
 
+    // Samples where the normalization failed
+    let mut failed: Vec<(usize, String)> = Vec::new();
+
     while let Ok(jr) = r.recv() {
         trace!("Controller received request {:?}; pending: {:?}", jr, track);
 
@@ -288,24 +410,33 @@ pub fn controller(
         // Store data from previous results
         match jr.prev_results {
             // Returning raw counts.  Add to sample_data
-            Completed::RawCounts(i, mut h) => {
-                let cts = if let Some(mut d) = sample_data[i].take() {
+            Completed::RawCounts(i, mut h, mut t, st) => {
+                read_stats[i].combine(&st);
+                let (cts, tcts) = if let Some((mut d, mut e)) = sample_data[i].take() {
                     for (k, v) in h.drain() {
                         d.insert(k, v);
                     }
-                    d
+                    for (k, v) in t.drain() {
+                        e.insert(k, v);
+                    }
+                    (d, e)
                 } else {
-                    h
+                    (h, t)
                 };
 
                 // If all contigs have been read then move to pending_norm else store in sample_data
                 if cts.len() == nc {
-                    pending_norm.push((i, cts))
+                    pending_norm.push((i, cts, tcts))
                 } else {
-                    sample_data[i] = Some(cts)
+                    sample_data[i] = Some((cts, tcts))
                 }
             }
-            Completed::NormalizedCounts(i, v) => pending_output.push((i, v)),
+            Completed::NormalizedCounts(i, v, t, b) => pending_output.push((i, v, t, b)),
+            Completed::Failed(v, s) => failed.extend(v.into_iter().map(|i| (i, s.clone()))),
+            Completed::PooledModel(m, v) => {
+                pooled_model = Some(Arc::new(m));
+                pending_norm.extend(v)
+            }
             Completed::None => (),
         }
 
@@ -326,44 +457,73 @@ pub fn controller(
             .or_else(|| {
                 ongoing_output = pending_output
                     .pop()
-                    .map(|(ix, nc)| OnGoingOutput::new(ix, nc));
+                    .map(|(ix, nc, tc, br)| OnGoingOutput::new(ix, nc, tc, br));
                 ongoing_output.as_mut().and_then(|o| o.next_job())
             })
             // If we have no available output jobs, check if there is a normalization jobs waiting
             .or_else(|| {
-                pending_norm.pop().map(|(ix, c)| Job {
-                    sample_idx: ix,
-                    job_type: JobType::NormalizeSample(c),
-                })
+                if cfg.pooled_gc() && pooled_model.is_none() {
+                    // The pooled GC model is fitted once all samples have been read
+                    if !fitting_pooled && pending_norm.len() == ns {
+                        fitting_pooled = true;
+                        Some(Job {
+                            sample_idx: 0,
+                            job_type: JobType::FitPooledModel(mem::take(&mut pending_norm)),
+                        })
+                    } else {
+                        None
+                    }
+                } else {
+                    pending_norm.pop().map(|(ix, c, t)| Job {
+                        sample_idx: ix,
+                        job_type: JobType::NormalizeSample(
+                            c,
+                            t,
+                            Box::new(mem::take(&mut read_stats[ix])),
+                            pooled_model.clone(),
+                        ),
+                    })
+                }
             });
 
         // If we still have no job, check for additional sample/ctgs for reading
-        if job.is_none() {
-            job = if new_reads {
-                get_new_read_job(
-                    // &mut ongoing_read,
-                    // &mut pending_read,
-                    //cfg.ctg_hash(),
-                    &mut sample_vec,
-                    &mut sample_idx,
-                )?
-            } else {
-                None
+        if job.is_none() && new_reads {
+            job = get_new_read_job(
+                // &mut ongoing_read,
+                // &mut pending_read,
+                //cfg.ctg_hash(),
+                &mut sample_vec,
+                &mut sample_idx,
+            )?
+        }
+
+        // If we get here then we have no pending read, normalization or output jobs.  If no jobs
+        // have been sent for processing that have not returned then all samples have been read.
+        // Any samples that were not completely read are reported as failed, and the pooled GC
+        // model (if requested) is fitted to the remaining samples
+        if job.is_none() && !track.pending() {
+            fail_incomplete_samples(cfg, &mut sample_data, &mut failed);
+            if cfg.pooled_gc()
+                && pooled_model.is_none()
+                && !fitting_pooled
+                && !pending_norm.is_empty()
+            {
+                fitting_pooled = true;
+                job = Some(Job {
+                    sample_idx: 0,
+                    job_type: JobType::FitPooledModel(mem::take(&mut pending_norm)),
+                })
             }
-            // If we get here then we have no pending read, normalization or output jobs
-            // Check if jobs have been sent for processing that have not returned.  If yes,
-            // then return JobType::Wait otherwise processing is finished so we can return None.
-            .or_else(|| {
-                if track.pending() {
-                    Some(Job {
-                        sample_idx: 0,
-                        job_type: JobType::Wait,
-                    })
-                } else {
-                    None
-                }
-            });
-        };
+        }
+
+        // Check if jobs have been sent for processing that have not returned.  If yes,
+        // then return JobType::Wait otherwise processing is finished so we can return None.
+        if job.is_none() && track.pending() {
+            job = Some(Job {
+                sample_idx: 0,
+                job_type: JobType::Wait,
+            })
+        }
 
         if let Some(j) = job.as_ref() {
             track.update_at_send(j)
@@ -379,5 +539,19 @@ pub fn controller(
             .expect("Error sending message to task");
     }
     debug!("Controller thread closing down");
-    Ok(())
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        failed.sort_unstable_by_key(|(i, _)| *i);
+        let names: Vec<_> = failed
+            .iter()
+            .map(|(i, s)| format!("{} ({})", cfg.sample_list()[*i].name(), s))
+            .collect();
+        Err(anyhow!(
+            "Processing failed for {} of {} samples: {}",
+            failed.len(),
+            ns,
+            names.join(", ")
+        ))
+    }
 }
@@ -0,0 +1,329 @@
+/// Additional per bin covariates
+///
+/// After GC normalization, residual coverage bias can remain that correlates with other
+/// properties of the bins such as CpG density or replication timing.  The log2 ratios of
+/// the observed to the GC predicted counts are regressed on the selected covariates, and
+/// the GC predictions are adjusted by the fitted effects.  CpG density is calculated from
+/// the reference with the GC content; other covariates are read from bedGraph files.
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+
+use crate::{
+    bed::read_bed,
+    config::Config,
+    contig::Contig,
+    coverage::RawCounts,
+    gc::{GcData, CPG_COVARIATE},
+    normalize::GcBins,
+};
+
+/// Observed counts that differ from the GC prediction by more than this (log2) are
+/// not used for the regression
+const MAX_ABS_LOG_RATIO: f64 = 1.0;
+
+/// Name of covariate from bedGraph file (the file name without the directory and extensions)
+fn track_name(fname: &Path) -> String {
+    let mut name = fname
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for ext in [".gz", ".bgz", ".xz", ".zst", ".bz2"] {
+        if let Some(s) = name.strip_suffix(ext) {
+            name = s.to_owned();
+            break;
+        }
+    }
+    for ext in [".bedGraph", ".bedgraph", ".bdg", ".bg"] {
+        if let Some(s) = name.strip_suffix(ext) {
+            name = s.to_owned();
+            break;
+        }
+    }
+    name
+}
+
+/// Read in covariate track from a bedGraph file and add it to the GC data for each block size.
+/// The value of the covariate for a block is the mean of the track values over the bases of
+/// the block covered by the track; blocks not covered by the track have missing values.
+/// Returns the index of the covariate (the same for all block sizes)
+pub fn add_covariate_track<S: AsRef<Path>>(
+    fname: S,
+    gc_data: &mut [GcData],
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+) -> anyhow::Result<usize> {
+    let fname = fname.as_ref();
+    let name = track_name(fname);
+    debug!(
+        "Reading in covariate track {} from {}",
+        name,
+        fname.display()
+    );
+    let bs = gc_data[0].block_size() as usize;
+
+    // Sum of values x overlap and total overlap per block at the smallest block size
+    let mut sums: HashMap<Arc<str>, Vec<(f64, usize)>> = HashMap::new();
+    read_bed(fname, "covariate", 4, ctg_hash, |ctg, start, end, value| {
+        let Some(gc) = gc_data[0].ctg_data(ctg) else {
+            return Ok(());
+        };
+        let end = end.min(gc.seq_len());
+        let z = value
+            .expect("Missing value")
+            .parse::<f64>()
+            .with_context(|| "Error reading value")?;
+        if z.is_finite() {
+            let v = sums
+                .entry(Arc::clone(ctg))
+                .or_insert_with(|| vec![(0.0, 0); gc.data().len()]);
+            let mut x = start;
+            while x < end {
+                let i = x / bs;
+                let y = ((i + 1) * bs).min(end);
+                v[i].0 += z * ((y - x) as f64);
+                v[i].1 += y - x;
+                x = y;
+            }
+        }
+        Ok(())
+    })?;
+
+    // Aggregate for each block size and add to GC data
+    let mut ix = None;
+    for gc in gc_data.iter_mut() {
+        let k = (gc.block_size() as usize) / bs;
+        let values = sums
+            .iter()
+            .map(|(ctg, v)| {
+                let w = v
+                    .chunks(k)
+                    .map(|c| {
+                        let (s, n) = c.iter().fold((0.0, 0), |(s, n), (s1, n1)| (s + s1, n + n1));
+                        if n > 0 {
+                            Some((s / (n as f64)) as f32)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                (Arc::clone(ctg), w)
+            })
+            .collect();
+        ix = Some(
+            gc.add_covariate(&name, values)
+                .with_context(|| format!("Error adding covariate from {}", fname.display()))?,
+        );
+    }
+    Ok(ix.expect("No block sizes"))
+}
+
+/// Set up covariates from command line.  Each covariate is either "cpg" for the CpG density
+/// or the path to a bedGraph file.  Returns the indices of the covariates in the GC data
+pub fn setup_covariates<S: AsRef<str>>(
+    covariates: &[S],
+    gc_data: &mut [GcData],
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+) -> anyhow::Result<Vec<usize>> {
+    let mut v = Vec::with_capacity(covariates.len());
+    for s in covariates {
+        let s = s.as_ref();
+        let ix = if s == CPG_COVARIATE {
+            0
+        } else {
+            add_covariate_track(s, gc_data, ctg_hash)?
+        };
+        if v.contains(&ix) {
+            return Err(anyhow!("Covariate {} given multiple times", s));
+        }
+        v.push(ix)
+    }
+    Ok(v)
+}
+
+/// Fitted linear regression of the log2 ratios of observed to GC predicted counts on the
+/// covariates
+pub struct CovariateFit {
+    names: Vec<Arc<str>>,
+    n: usize,
+    intercept: f64,
+    coef: Vec<f64>,
+    means: Vec<f64>,
+}
+
+impl CovariateFit {
+    /// Multiplicative adjustment to the GC prediction for a bin with covariate values x.  The
+    /// covariates are centred on their means, so a bin with average covariate values is not
+    /// adjusted
+    pub fn adjustment(&self, x: &[f64]) -> f64 {
+        self.coef
+            .iter()
+            .zip(x.iter().zip(self.means.iter()))
+            .map(|(b, (x, m))| b * (x - m))
+            .sum::<f64>()
+            .exp2()
+    }
+
+    /// Write table of fitted coefficients.  The intercept is the fitted log2 ratio at the mean
+    /// values of the covariates.  As for the GC model, the table is preceded by metadata lines
+    pub fn write<W: Write>(&self, wrt: &mut W, sample: &str, block_size: u32) -> io::Result<()> {
+        writeln!(wrt, "# sample: {}", sample)?;
+        writeln!(wrt, "# block_size: {}", block_size)?;
+        writeln!(wrt, "# n_bins: {}", self.n)?;
+        writeln!(wrt, "covariate\tcoefficient\tmean")?;
+        writeln!(wrt, "intercept\t{:.6}\tNA", self.intercept)?;
+        for (name, (b, m)) in self
+            .names
+            .iter()
+            .zip(self.coef.iter().zip(self.means.iter()))
+        {
+            writeln!(wrt, "{}\t{:.6}\t{:.6}", name, b, m)?
+        }
+        Ok(())
+    }
+}
+
+/// Values of the selected covariates for block i (None if any are missing)
+pub fn bin_covariates(cov: &[&[Option<f32>]], i: usize, x: &mut Vec<f64>) -> Option<()> {
+    x.clear();
+    for c in cov {
+        x.push(c.get(i).copied().flatten()? as f64)
+    }
+    Some(())
+}
+
+/// Covariate values for a contig
+pub fn ctg_covariates<'a>(cfg: &Config, gc_data: &'a GcData, ctg: &str) -> Vec<&'a [Option<f32>]> {
+    match gc_data.ctg_data(ctg) {
+        Some(gc) => cfg.covariates().iter().map(|i| gc.covariate(*i)).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Solve linear system a.x = b by Gaussian elimination with partial pivoting.
+/// Returns None if the matrix is (numerically) singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    let scale = a
+        .iter()
+        .flat_map(|r| r.iter())
+        .fold(0.0f64, |m, x| m.max(x.abs()));
+    for i in 0..n {
+        let p = (i..n).max_by(|j, k| a[*j][i].abs().partial_cmp(&a[*k][i].abs()).unwrap())?;
+        if a[p][i].abs() <= scale * 1.0e-12 {
+            return None;
+        }
+        a.swap(i, p);
+        b.swap(i, p);
+        for j in i + 1..n {
+            let f = a[j][i] / a[i][i];
+            let (top, bottom) = a.split_at_mut(j);
+            for (x, y) in bottom[0][i..].iter_mut().zip(top[i][i..].iter()) {
+                *x -= f * y
+            }
+            b[j] -= f * b[i]
+        }
+    }
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        let s: f64 = (i + 1..n).map(|k| a[i][k] * x[k]).sum();
+        x[i] = (b[i] - s) / a[i][i]
+    }
+    Some(x)
+}
+
+/// Regress the log2 ratios of observed counts to GC predictions on the covariates, using bins
+/// from contigs used for normalization with a GC prediction, a non-zero count and all covariates
+/// present.  Bins where the observed count is more than 2 fold from the prediction (i.e., with
+/// copy number changes) are not used.  Returns None if no covariates are selected or the
+/// regression is not possible
+pub fn fit_covariates(
+    cfg: &Config,
+    gc_data: &GcData,
+    gc_bins: &GcBins,
+    rc: &RawCounts,
+    pred: &[Option<f64>],
+) -> Option<CovariateFit> {
+    let k = cfg.covariates().len();
+    if k == 0 {
+        return None;
+    }
+    // Accumulate normal equations for design (1, x_1, ... x_k)
+    let mut a = vec![vec![0.0; k + 1]; k + 1];
+    let mut b = vec![0.0; k + 1];
+    let mut n = 0;
+    let mut x = Vec::with_capacity(k);
+    let mut z = vec![1.0; k + 1];
+    for contig in cfg
+        .ctg_hash()
+        .values()
+        .filter(|c| c.use_for_normalization())
+    {
+        let ctg = contig.name();
+        let (cts, gc) = match (rc.get(ctg), gc_bins.get(ctg)) {
+            (Some(c), Some(g)) => (c, g),
+            _ => continue,
+        };
+        let cov = ctg_covariates(cfg, gc_data, ctg);
+        for (i, (c, g)) in cts.iter().zip(gc.iter()).enumerate() {
+            let p = match g.and_then(|ix| pred[ix as usize]) {
+                Some(p) if *c > 0 => p,
+                _ => continue,
+            };
+            let y = ((*c as f64) / p).log2();
+            if y.abs() > MAX_ABS_LOG_RATIO || bin_covariates(&cov, i, &mut x).is_none() {
+                continue;
+            }
+            z[1..].copy_from_slice(&x);
+            for (j, zj) in z.iter().enumerate() {
+                for (l, zl) in z.iter().enumerate() {
+                    a[j][l] += zj * zl
+                }
+                b[j] += zj * y
+            }
+            n += 1
+        }
+    }
+    if n <= k + 1 {
+        warn!(
+            "Too few bins ({}) for covariate regression (block size {})",
+            n,
+            gc_data.block_size()
+        );
+        return None;
+    }
+    let means: Vec<f64> = a[0][1..].iter().map(|s| s / (n as f64)).collect();
+    let beta = match solve(a, b) {
+        Some(beta) => beta,
+        None => {
+            warn!(
+                "Covariate regression failed (block size {}): covariates are collinear or constant",
+                gc_data.block_size()
+            );
+            return None;
+        }
+    };
+    let coef = beta[1..].to_vec();
+    let intercept = beta[0]
+        + coef
+            .iter()
+            .zip(means.iter())
+            .map(|(b, m)| b * m)
+            .sum::<f64>();
+    let names = cfg
+        .covariates()
+        .iter()
+        .map(|i| Arc::clone(&gc_data.covariate_names()[*i]))
+        .collect();
+    Some(CovariateFit {
+        names,
+        n,
+        intercept,
+        coef,
+        means,
+    })
+}
@@ -1,5 +1,59 @@
 use std::{collections::HashMap, sync::Arc};
 
-pub type Coverage = Vec<(usize, Option<f64>)>;
+use crate::config::Config;
+
+/// Raw count, normalized coverage and outlier flags per bin
+pub type Coverage = Vec<(usize, Option<f64>, u8)>;
 pub type RawCounts = HashMap<Arc<str>, Vec<usize>>;
 pub type NormCov = HashMap<Arc<str>, Coverage>;
+/// Bins with missing data per contig (i.e., bins not present in an existing coverage file)
+pub type MissingBins = HashMap<Arc<str>, Vec<bool>>;
+
+/// Add counts from b to a.  Contigs present in both must have the same number of bins
+pub fn add_counts(a: &mut RawCounts, b: &RawCounts) -> anyhow::Result<()> {
+    for (ctg, w) in b.iter() {
+        match a.get_mut(ctg) {
+            Some(v) => {
+                if v.len() != w.len() {
+                    return Err(anyhow!(
+                        "Number of bins for contig {} differs ({} and {})",
+                        ctg,
+                        v.len(),
+                        w.len()
+                    ));
+                }
+                for (x, y) in v.iter_mut().zip(w.iter()) {
+                    *x += *y
+                }
+            }
+            None => {
+                a.insert(Arc::clone(ctg), w.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Total counts and total length of contigs used for normalization
+pub fn norm_totals(cfg: &Config, rc: &RawCounts) -> (usize, usize) {
+    let gc_data = &cfg.gc_data()[0];
+    cfg.ctg_hash()
+        .values()
+        .filter(|c| c.use_for_normalization())
+        .fold((0, 0), |(tot, len), c| {
+            match (rc.get(c.name()), gc_data.ctg_data(c.name())) {
+                (Some(v), Some(gc)) => (tot + v.iter().sum::<usize>(), len + gc.seq_len()),
+                _ => (tot, len),
+            }
+        })
+}
+
+/// Mean coverage across contigs used for normalization
+pub fn mean_coverage(cfg: &Config, rc: &RawCounts) -> f64 {
+    let (tot, len) = norm_totals(cfg, rc);
+    if len > 0 {
+        (tot as f64) / (len as f64)
+    } else {
+        0.0
+    }
+}
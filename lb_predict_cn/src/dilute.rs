@@ -0,0 +1,266 @@
+/// In-silico dilution series
+///
+/// Reads from a tumour and a matched normal input file are mixed at known fractions and depths
+/// to give coverage profiles for validation and limit of detection experiments.  Two passes are
+/// made through each input file: the first to get the mean coverage of each file, and the second
+/// to sub-sample the reads and count the coverage for all mixtures simultaneously.
+///
+/// The tumour fraction is the fraction of the (autosomal) coverage from the tumour file, and the
+/// depth is the mean raw coverage across the contigs used for normalization.  The tumour and normal
+/// reads are sampled with probabilities fraction * depth / tumour depth and
+/// (1 - fraction) * depth / normal depth respectively.
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use r_htslib::*;
+
+use crate::{
+    cli::DiluteTask,
+    config::Config,
+    coverage::{add_counts, mean_coverage, norm_totals, RawCounts},
+    input::open_input,
+    normalize::normalize_sample,
+    output::{output_gc_curves, output_sample_cfg, output_sample_qc, setup_output},
+    pool::pool_map,
+    reader::{read_coverage_data, read_sampled_coverage_data},
+    subsample::ReadSampler,
+};
+
+/// A mixture of the tumour and normal inputs
+pub struct Mixture {
+    name: String,
+    fraction: f64,
+    depth: f64,
+}
+
+impl Mixture {
+    pub fn new(fraction: f64, depth: f64) -> Self {
+        Self {
+            name: format!("mix_{}_{}", fraction, depth),
+            fraction,
+            depth,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Run f on the tumour and normal input files in parallel
+fn for_inputs<T: Send, F: Fn(&Path, &str) -> anyhow::Result<T> + Sync>(
+    task: &DiluteTask,
+    f: F,
+) -> anyhow::Result<(T, T)> {
+    let inputs = vec![
+        (task.tumour.as_path(), "tumour"),
+        (task.normal.as_path(), "normal"),
+    ];
+    let mut res = pool_map(2, inputs, "reader", |(p, src)| f(p, src))?.into_iter();
+    let (t, n) = (res.next().unwrap(), res.next().unwrap());
+    Ok((
+        t.with_context(|| format!("Error reading tumour file {}", task.tumour.display()))?,
+        n.with_context(|| format!("Error reading normal file {}", task.normal.display()))?,
+    ))
+}
+
+fn sample_dir(cfg: &Config, name: &str) -> PathBuf {
+    let mut p = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    p.push(name);
+    p
+}
+
+/// Write the mixture parameters and the achieved depths and fractions
+fn write_dilution_table(
+    task: &DiluteTask,
+    depths: (f64, f64),
+    probs: &[(f64, f64)],
+    achieved: &[Option<(f64, f64)>],
+) -> anyhow::Result<()> {
+    let cfg = &task.cfg;
+    let mut p = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    p.push("dilution.txt");
+    let mut wrt = BufWriter::new(
+        fs::File::create(&p)
+            .with_context(|| format!("problem creating output file {}", p.display()))?,
+    );
+    writeln!(
+        wrt,
+        "# tumour: {} (mean coverage {:.4})",
+        task.tumour.display(),
+        depths.0
+    )?;
+    writeln!(
+        wrt,
+        "# normal: {} (mean coverage {:.4})",
+        task.normal.display(),
+        depths.1
+    )?;
+    writeln!(wrt, "# seed: {}", task.seed)?;
+    writeln!(
+        wrt,
+        "name\ttumour_fraction\tdepth\ttumour_prob\tnormal_prob\tachieved_tumour_fraction\tachieved_depth"
+    )?;
+    for ((m, (pt, pn)), a) in task.mixtures.iter().zip(probs).zip(achieved) {
+        write!(
+            wrt,
+            "{}\t{}\t{}\t{:.6}\t{:.6}",
+            m.name, m.fraction, m.depth, pt, pn
+        )?;
+        match a {
+            Some((af, ad)) => writeln!(wrt, "\t{:.6}\t{:.4}", af, ad)?,
+            None => writeln!(wrt, "\tNA\tNA")?,
+        }
+    }
+    Ok(())
+}
+
+/// Combine the tumour and normal counts for a mixture, normalize and output.  Returns the
+/// achieved tumour fraction and depth
+fn process_mixture(
+    cfg: &Config,
+    ix: usize,
+    mut rc: RawCounts,
+    h: &RawCounts,
+) -> anyhow::Result<(f64, f64)> {
+    let name = cfg.sample_list()[ix].name();
+    let (tumour_tot, _) = norm_totals(cfg, &rc);
+    add_counts(&mut rc, h)?;
+    let (tot, _) = norm_totals(cfg, &rc);
+    let depth = mean_coverage(cfg, &rc);
+    let frac = if tot > 0 {
+        (tumour_tot as f64) / (tot as f64)
+    } else {
+        0.0
+    };
+    debug!(
+        "Mixture {}: achieved tumour fraction {:.6}, depth {:.4}",
+        name, frac, depth
+    );
+    let (nc, curves) = normalize_sample(cfg, rc, None, None)
+        .with_context(|| format!("Error normalizing mixture {}", name))?;
+    output_gc_curves(cfg, ix, &curves, None)?;
+    output_sample_qc(cfg, ix, None, &nc)?;
+    for (level, mut nc) in nc.into_iter().enumerate() {
+        for (ctg, cov) in nc.drain() {
+            output_sample_cfg(cfg, ix, level, &ctg, cov, None)?
+        }
+    }
+    Ok((frac, depth))
+}
+
+/// Generate dilution series (dilute subcommand).  If processing fails for a mixture, the error
+/// is logged and the remaining mixtures are processed
+pub fn dilute(task: &DiluteTask) -> anyhow::Result<()> {
+    let cfg = &task.cfg;
+    let tpool = HtsThreadPool::new(cfg.hts_threads());
+    let tpool_ref = tpool.as_ref();
+
+    setup_output(cfg)?;
+
+    // First pass: get mean coverage of inputs
+    info!("Reading input files to get mean coverage");
+    let (rc_t, rc_n) = for_inputs(task, |p, _| {
+        let mut hts = open_input(p, true, cfg.reference(), tpool_ref)?;
+        read_coverage_data(cfg, &mut hts, None, None, None).map(|(rc, _, _)| rc)
+    })?;
+    for (ctg, v) in rc_t.iter() {
+        if let Some(w) = rc_n.get(ctg) {
+            if v.len() != w.len() {
+                return Err(anyhow!(
+                    "Contig {} has different lengths in tumour and normal files",
+                    ctg
+                ));
+            }
+        }
+    }
+    let depths = (mean_coverage(cfg, &rc_t), mean_coverage(cfg, &rc_n));
+    drop((rc_t, rc_n));
+    info!(
+        "Mean coverage of tumour: {:.4}, normal: {:.4}",
+        depths.0, depths.1
+    );
+
+    // Get sampling probabilities
+    let mut probs = Vec::with_capacity(task.mixtures.len());
+    for m in task.mixtures.iter() {
+        let pt = if m.fraction > 0.0 {
+            m.fraction * m.depth / depths.0
+        } else {
+            0.0
+        };
+        let pn = if m.fraction < 1.0 {
+            (1.0 - m.fraction) * m.depth / depths.1
+        } else {
+            0.0
+        };
+        if pt > 1.0 || pn > 1.0 {
+            return Err(anyhow!(
+                "Depth {} with tumour fraction {} is not possible with the input coverage (tumour: {:.4}, normal: {:.4})",
+                m.depth,
+                m.fraction,
+                depths.0,
+                depths.1
+            ));
+        }
+        probs.push((pt, pn))
+    }
+
+    // Second pass: sub-sample reads for each mixture
+    info!("Sub-sampling reads for {} mixtures", task.mixtures.len());
+    let sampler = ReadSampler::new(task.seed);
+    let (mut rc_t, mut rc_n) = for_inputs(task, |p, src| {
+        let pv: Vec<_> = probs
+            .iter()
+            .map(|(pt, pn)| if src == "tumour" { *pt } else { *pn })
+            .collect();
+        let mut hts = open_input(p, true, cfg.reference(), tpool_ref)?;
+        if task.read_list {
+            let mut wrt = Vec::with_capacity(task.mixtures.len());
+            for m in task.mixtures.iter() {
+                let p = sample_dir(cfg, &m.name).join(format!("{}_reads.txt", src));
+                wrt.push(BufWriter::new(fs::File::create(&p).with_context(|| {
+                    format!("problem creating output file {}", p.display())
+                })?))
+            }
+            let v = read_sampled_coverage_data(cfg, &mut hts, &sampler, &pv, Some(&mut wrt))?;
+            for w in wrt.iter_mut() {
+                w.flush()?
+            }
+            Ok(v)
+        } else {
+            read_sampled_coverage_data::<fs::File>(cfg, &mut hts, &sampler, &pv, None)
+        }
+    })?;
+
+    // Combine counts, normalize and output
+    let mut achieved = Vec::with_capacity(task.mixtures.len());
+    let mut failed = Vec::new();
+    for (ix, (rc, h)) in rc_t.drain(..).zip(rc_n.drain(..)).enumerate() {
+        match process_mixture(cfg, ix, rc, &h) {
+            Ok(a) => achieved.push(Some(a)),
+            Err(e) => {
+                let name = task.mixtures[ix].name();
+                error!("Error processing mixture {}: {:#}", name, e);
+                failed.push(name);
+                achieved.push(None)
+            }
+        }
+    }
+
+    write_dilution_table(task, depths, &probs, &achieved)?;
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Processing failed for {} of {} mixtures: {}",
+            failed.len(),
+            task.mixtures.len(),
+            failed.join(", ")
+        ))
+    }
+}
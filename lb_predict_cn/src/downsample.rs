@@ -0,0 +1,250 @@
+/// Downsampling of samples to a target depth
+///
+/// The depth of each sample (the fragments passing the read filters and the mean coverage across
+/// the contigs used for normalization) is estimated from the index statistics if the input file
+/// is indexed.  The number of mapped reads per contig is taken from the index, and the number of
+/// passing fragments and the coverage per mapped read are found by reading the smallest contig
+/// used for normalization.  If the index statistics are not available, the depth is found from
+/// a first pass through the input file.  The sampling fraction required to reach the target is
+/// then used when reading the data for the sample, with the reads being selected using a hash
+/// of the read name (see ReadSampler) so that both reads of a pair are kept or dropped together.
+use std::{
+    fmt, fs,
+    io::{BufWriter, Write},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use r_htslib::*;
+
+use crate::{
+    config::Config,
+    coverage::{mean_coverage, RawCounts},
+    input::{input_ctg_name, open_input},
+    pool::pool_map,
+    qc::ReadStats,
+    reader::{read_ctg_depth, read_sample_depth},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum DownsampleTarget {
+    Fragments(usize),
+    Coverage(f64),
+}
+
+impl fmt::Display for DownsampleTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fragments(x) => write!(f, "{} fragments", x),
+            Self::Coverage(x) => write!(f, "{} mean coverage", x),
+        }
+    }
+}
+
+/// Depth of a sample before downsampling, whether this was estimated from the index
+/// statistics and the sampling fraction needed to reach the target depth (None if the
+/// sample is already below the target)
+pub struct SampleDepth {
+    fragments: usize,
+    coverage: f64,
+    estimated: bool,
+    fraction: Option<f64>,
+}
+
+impl SampleDepth {
+    pub fn fraction(&self) -> Option<f64> {
+        self.fraction
+    }
+}
+
+/// Mapped reads for a contig from the input file with the contig length and whether
+/// the contig is used for normalization
+struct CtgMapped {
+    ctg: Arc<str>,
+    mapped: u64,
+    seq_len: usize,
+    normalize: bool,
+}
+
+/// Estimate the number of passing fragments and the mean coverage of a sample from the
+/// index statistics, reading the smallest contig used for normalization to get the
+/// fragments and coverage per mapped read.  Returns None if the index statistics
+/// are not available
+fn estimate_depth(cfg: &Config, hts: &mut Hts) -> anyhow::Result<Option<(usize, f64)>> {
+    let gc_data = &cfg.gc_data()[0];
+    let mut v = Vec::with_capacity(cfg.ctg_hash().len());
+    for (ctg, contig) in cfg.ctg_hash().iter() {
+        let Some(name) = input_ctg_name(hts, contig) else {
+            continue;
+        };
+        let Some((mapped, _)) = hts.name2tid(name).and_then(|tid| hts.idx_get_stat(tid)) else {
+            return Ok(None);
+        };
+        if let Some(gc) = gc_data.ctg_data(ctg) {
+            v.push(CtgMapped {
+                ctg: Arc::clone(ctg),
+                mapped,
+                seq_len: gc.seq_len(),
+                normalize: contig.use_for_normalization(),
+            })
+        }
+    }
+
+    // Contig used to find the fragments and coverage per mapped read
+    let Some(cal) = v
+        .iter()
+        .filter(|c| c.normalize && c.mapped > 0)
+        .min_by_key(|c| c.seq_len)
+    else {
+        return Ok(None);
+    };
+    debug!(
+        "Estimating depth from index statistics using contig {} ({} mapped reads)",
+        cal.ctg, cal.mapped
+    );
+    let (rc, cal_fragments) = read_ctg_depth(cfg, hts, &cal.ctg)?;
+    let cal_bases: usize = rc.get(&cal.ctg).map(|c| c.iter().sum()).unwrap_or(0);
+
+    let mapped: u64 = v.iter().map(|c| c.mapped).sum();
+    let (norm_mapped, norm_len) = v
+        .iter()
+        .filter(|c| c.normalize)
+        .fold((0, 0), |(m, l), c| (m + c.mapped, l + c.seq_len));
+    let cal_mapped = cal.mapped as f64;
+    let fragments = ((mapped as f64) * (cal_fragments as f64) / cal_mapped).round() as usize;
+    let coverage = (norm_mapped as f64) * (cal_bases as f64) / (cal_mapped * (norm_len as f64));
+    Ok(Some((fragments, coverage)))
+}
+
+/// Get the number of passing fragments and the mean coverage of a sample, using the index
+/// statistics if available, otherwise with a pass through the input file
+fn get_depth(
+    cfg: &Config,
+    sample_idx: usize,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<(usize, f64, bool)> {
+    let sample = &cfg.sample_list()[sample_idx];
+    match open_input(sample.input_path(), false, cfg.reference(), tpool) {
+        Ok(mut hts) => {
+            if let Some((fragments, coverage)) = estimate_depth(cfg, &mut hts)? {
+                return Ok((fragments, coverage, true));
+            }
+            debug!(
+                "Index statistics not available for sample {}",
+                sample.name()
+            )
+        }
+        Err(e) => debug!(
+            "Could not open sample {} with index: {:#}",
+            sample.name(),
+            e
+        ),
+    }
+    let mut hts = open_input(sample.input_path(), true, cfg.reference(), tpool)?;
+    let (rc, fragments) = read_sample_depth(cfg, &mut hts)?;
+    Ok((fragments, mean_coverage(cfg, &rc), false))
+}
+
+fn sample_depth(
+    cfg: &Config,
+    target: DownsampleTarget,
+    sample_idx: usize,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<SampleDepth> {
+    let sample = &cfg.sample_list()[sample_idx];
+    debug!("Getting depth of sample {}", sample.name());
+    let (fragments, coverage, estimated) = get_depth(cfg, sample_idx, tpool)?;
+    let fraction = match target {
+        DownsampleTarget::Fragments(x) => (x as f64) / (fragments as f64),
+        DownsampleTarget::Coverage(x) => x / coverage,
+    };
+    let fraction = if fraction < 1.0 {
+        Some(fraction)
+    } else {
+        warn!(
+            "Sample {} (fragments: {}, mean coverage: {:.4}) is below the downsampling target",
+            sample.name(),
+            fragments,
+            coverage
+        );
+        None
+    };
+    debug!(
+        "Sample {}: fragments: {}, mean coverage: {:.4} (estimated: {}), sampling fraction: {:?}",
+        sample.name(),
+        fragments,
+        coverage,
+        estimated,
+        fraction
+    );
+    Ok(SampleDepth {
+        fragments,
+        coverage,
+        estimated,
+        fraction,
+    })
+}
+
+/// Get depth of all samples and the required sampling fractions
+pub fn get_sample_depths(
+    cfg: &Config,
+    target: DownsampleTarget,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<Vec<SampleDepth>> {
+    info!("Getting sample depths for downsampling");
+    let ns = cfg.sample_list().len();
+    pool_map(cfg.n_readers(), (0..ns).collect(), "depth", |ix| {
+        sample_depth(cfg, target, ix, tpool).with_context(|| {
+            format!(
+                "Error getting depth of sample {}",
+                cfg.sample_list()[ix].name()
+            )
+        })
+    })?
+    .into_iter()
+    .collect()
+}
+
+/// Report the depth achieved after downsampling (the passing fragments kept and the mean
+/// coverage).  This is logged and written to the file downsample.txt in the sample output directory
+pub fn report_downsampling(
+    cfg: &Config,
+    sample_idx: usize,
+    depth: &SampleDepth,
+    rc: &RawCounts,
+    st: &ReadStats,
+) -> anyhow::Result<()> {
+    let name = cfg.sample_list()[sample_idx].name();
+    let achieved = mean_coverage(cfg, rc);
+    let kept = st.fragments();
+    let fraction = depth.fraction.unwrap_or(1.0);
+    info!(
+        "Sample {}: sampling fraction {:.6}, fragments {}, mean coverage {:.4} (before downsampling {}, {:.4})",
+        name, fraction, kept, achieved, depth.fragments, depth.coverage
+    );
+
+    let mut p = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    p.push(name);
+    p.push("downsample.txt");
+    let mut wrt = BufWriter::new(
+        fs::File::create(&p)
+            .with_context(|| format!("problem creating output file {}", p.display()))?,
+    );
+    writeln!(wrt, "target\t{}", cfg.downsample().unwrap())?;
+    writeln!(wrt, "seed\t{}", cfg.seed())?;
+    writeln!(
+        wrt,
+        "depth_source\t{}",
+        if depth.estimated {
+            "index_estimate"
+        } else {
+            "first_pass"
+        }
+    )?;
+    writeln!(wrt, "fragments\t{}", depth.fragments)?;
+    writeln!(wrt, "mean_coverage\t{:.4}", depth.coverage)?;
+    writeln!(wrt, "sampling_fraction\t{:.6}", fraction)?;
+    writeln!(wrt, "achieved_fragments\t{}", kept)?;
+    writeln!(wrt, "achieved_mean_coverage\t{:.4}", achieved)?;
+    Ok(())
+}
@@ -0,0 +1,350 @@
+/// Fragment level GC bias correction
+///
+/// GC bias arises from the GC content of the individual fragments rather than the GC content of
+/// the bins, so the bias can be better corrected at the fragment level (Benjamini & Speed, 2012).
+/// For each sample a first pass is made through the input file, where the GC content of each
+/// fragment passing the read filters is found from the reference, giving a table of the observed
+/// fragment counts by GC content and fragment length.  The expected counts are found by sampling
+/// windows from the reference (from the contigs used for normalization) at regular intervals.
+/// When the coverage is counted, each fragment is then weighted by the ratio of the expected and
+/// observed frequencies for its GC and length class.  The GC frequencies are normalized
+/// separately for each length class, so the correction does not change the fragment
+/// length distribution.
+///
+/// The reference sequences used to find the fragment GC content are shared between all threads
+/// (see RefSeqs), so a contig sequence is loaded once and used by all samples being read at the
+/// same time, in both the first pass and when the coverage is counted.
+///
+/// Only paired reads are corrected (as the fragment length is not known for unpaired reads).
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::Context;
+use r_htslib::*;
+
+use crate::{
+    config::Config,
+    contig::Contig,
+    gc::{base_class, RefReader},
+    input::open_input,
+    pool::pool_map,
+    reader::read_fragment_gc,
+    twobit::is_two_bit,
+};
+
+/// Number of fragment GC bins (GC percentage 0-100)
+pub const N_FRAG_GC_BINS: usize = 101;
+
+/// Size of fragment length classes
+const LEN_BIN_SIZE: usize = 10;
+
+/// Maximum fragment length considered if no maximum template length is set
+const DEFAULT_MAX_FRAG_LEN: usize = 1000;
+
+/// Distance between sampled windows for the expected counts
+const EXPECTED_STRIDE: usize = 5000;
+
+/// Minimum observed count in a cell of the table for the weight to be estimated
+const MIN_OBS: f64 = 10.0;
+
+/// Maximum weight for a fragment
+const MAX_WEIGHT: f64 = 10.0;
+
+fn max_frag_len(cfg: &Config) -> usize {
+    cfg.max_template_len().unwrap_or(DEFAULT_MAX_FRAG_LEN)
+}
+
+/// GC bin for a set of base class counts (see base_class()).  Returns None if less than
+/// 90% of the bases are valid
+fn gc_pct(counts: &[usize; 3], len: usize) -> Option<usize> {
+    let tot = counts[1] + counts[2];
+    if tot > 0 && (tot as f64) >= 0.9 * (len as f64) {
+        Some(((100 * counts[2]) as f64 / (tot as f64)).round() as usize)
+    } else {
+        None
+    }
+}
+
+/// Counts of fragments per length class and GC bin
+pub struct FragTable {
+    counts: Vec<[f64; N_FRAG_GC_BINS]>,
+}
+
+impl FragTable {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            counts: vec![[0.0; N_FRAG_GC_BINS]; max_frag_len(cfg).div_ceil(LEN_BIN_SIZE) + 1],
+        }
+    }
+
+    pub fn add(&mut self, len: usize, gc: usize) {
+        if let Some(v) = self.counts.get_mut(len / LEN_BIN_SIZE) {
+            v[gc] += 1.0
+        }
+    }
+
+    fn add_table(&mut self, other: &Self) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            for (x, y) in a.iter_mut().zip(b.iter()) {
+                *x += *y
+            }
+        }
+    }
+}
+
+/// Sample windows of each length class from a contig sequence
+fn add_expected_counts(tab: &mut FragTable, seq: &[u8]) {
+    let n_len = tab.counts.len();
+    let max_len = n_len * LEN_BIN_SIZE;
+    let mut x = 0;
+    while x + LEN_BIN_SIZE <= seq.len() {
+        // Count bases incrementally, recording the counts at the midpoint of each length class
+        let mut ct = [0; 3];
+        let mut l = 0;
+        for (i, c) in seq[x..(x + max_len).min(seq.len())].iter().enumerate() {
+            ct[base_class(*c)] += 1;
+            if i + 1 == l * LEN_BIN_SIZE + LEN_BIN_SIZE / 2 {
+                if let Some(g) = gc_pct(&ct, i + 1) {
+                    tab.counts[l][g] += 1.0
+                }
+                l += 1;
+            }
+        }
+        x += EXPECTED_STRIDE
+    }
+}
+
+fn expected_task(cfg: &Config, contig: &Contig) -> anyhow::Result<FragTable> {
+    trace!("Getting expected fragment GC counts for {}", contig.name());
+    let reference = cfg.reference();
+    let mut rdr = RefReader::open(reference, is_two_bit(reference))?;
+    let mut tab = FragTable::new(cfg);
+    rdr.with_ctg_seq(contig, |s| add_expected_counts(&mut tab, s))?;
+    Ok(tab)
+}
+
+/// Get expected fragment counts by length class and GC from the contigs used for normalization
+fn expected_table(cfg: &Config) -> anyhow::Result<FragTable> {
+    debug!("Calculating expected fragment GC distribution from reference");
+    let ctgs: Vec<_> = cfg
+        .ctg_hash()
+        .values()
+        .filter(|c| c.use_for_normalization())
+        .collect();
+    let res = pool_map(cfg.n_tasks(), ctgs, "fragment GC", |c| {
+        expected_task(cfg, c)
+    })?;
+    let mut tab = FragTable::new(cfg);
+    for t in res {
+        tab.add_table(&t.with_context(|| {
+            "Error calculating expected fragment GC distribution from reference"
+        })?)
+    }
+    Ok(tab)
+}
+
+/// Fragment GC weights for a sample
+pub struct GcModel {
+    weights: Vec<[f64; N_FRAG_GC_BINS]>,
+}
+
+impl GcModel {
+    fn from_tables(obs: &FragTable, exp: &FragTable) -> Self {
+        let weights = obs
+            .counts
+            .iter()
+            .zip(exp.counts.iter())
+            .map(|(o, e)| {
+                let o_tot: f64 = o.iter().sum();
+                let e_tot: f64 = e.iter().sum();
+                let mut w = [1.0; N_FRAG_GC_BINS];
+                for (w, (x, y)) in w.iter_mut().zip(o.iter().zip(e.iter())) {
+                    if *x >= MIN_OBS && *y > 0.0 {
+                        *w = ((y / e_tot) / (x / o_tot)).min(MAX_WEIGHT)
+                    }
+                }
+                w
+            })
+            .collect();
+        Self { weights }
+    }
+
+    fn weight(&self, len: usize, gc: usize) -> f64 {
+        self.weights
+            .get(len / LEN_BIN_SIZE)
+            .map(|w| w[gc])
+            .unwrap_or(1.0)
+    }
+}
+
+/// Contig sequences (as base classes) shared between the fragment GC look ups of all threads.
+/// A contig sequence is loaded from the reference the first time it is requested, and is kept
+/// for as long as any look up is using it
+pub struct RefSeqs {
+    seqs: HashMap<Arc<str>, Mutex<Weak<Vec<u8>>>>,
+}
+
+impl RefSeqs {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            seqs: cfg
+                .ctg_hash()
+                .keys()
+                .map(|ctg| (Arc::clone(ctg), Mutex::new(Weak::new())))
+                .collect(),
+        }
+    }
+
+    /// Get the sequence of ctg, loading it from the reference if it is not currently in use
+    fn get(&self, cfg: &Config, ctg: &Arc<str>) -> anyhow::Result<Arc<Vec<u8>>> {
+        let slot = self.seqs.get(ctg).expect("Unknown contig");
+        // Other threads requesting the same contig wait here until it is loaded
+        let mut w = slot.lock().unwrap();
+        if let Some(seq) = w.upgrade() {
+            return Ok(seq);
+        }
+        trace!("Loading reference sequence for {}", ctg);
+        let contig = cfg.ctg_hash().get(ctg).expect("Unknown contig");
+        let reference = cfg.reference();
+        let mut rdr = RefReader::open(reference, is_two_bit(reference))?;
+        let mut seq = Vec::new();
+        rdr.with_ctg_seq(contig, |s| {
+            seq.extend(s.iter().map(|c| base_class(*c) as u8))
+        })?;
+        let seq = Arc::new(seq);
+        *w = Arc::downgrade(&seq);
+        Ok(seq)
+    }
+}
+
+/// Look up of fragment GC from the reference.  The sequence of the current contig
+/// is taken from the shared RefSeqs
+pub struct FragGcLookup<'a> {
+    seqs: &'a RefSeqs,
+    ctg: Option<Arc<str>>,
+    seq: Arc<Vec<u8>>,
+}
+
+impl<'a> FragGcLookup<'a> {
+    pub fn new(seqs: &'a RefSeqs) -> Self {
+        Self {
+            seqs,
+            ctg: None,
+            seq: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Get GC bin of the fragment [start, start + len) on ctg
+    pub fn gc(
+        &mut self,
+        cfg: &Config,
+        ctg: &Arc<str>,
+        start: usize,
+        len: usize,
+    ) -> anyhow::Result<Option<usize>> {
+        if self.ctg.as_ref() != Some(ctg) {
+            // Release the previous contig before getting the new one
+            self.seq = Arc::new(Vec::new());
+            self.seq = self.seqs.get(cfg, ctg)?;
+            self.ctg = Some(Arc::clone(ctg))
+        }
+        Ok(self.seq.get(start..start + len).and_then(|s| {
+            let mut ct = [0; 3];
+            for c in s {
+                ct[*c as usize] += 1
+            }
+            gc_pct(&ct, len)
+        }))
+    }
+}
+
+/// Start and length of the fragment for a paired read
+pub fn fragment(rec: &BamRec) -> Option<(usize, usize)> {
+    let tlen = rec.template_len();
+    if (rec.flag() & BAM_FPAIRED) == 0 || tlen == 0 {
+        None
+    } else if tlen > 0 {
+        rec.pos().map(|x| (x, tlen as usize))
+    } else {
+        rec.mpos().map(|x| (x, tlen.unsigned_abs()))
+    }
+}
+
+/// Calculates fragment weights when counting coverage
+pub struct FragWeighter<'a> {
+    model: &'a GcModel,
+    lookup: FragGcLookup<'a>,
+}
+
+impl<'a> FragWeighter<'a> {
+    pub fn new(model: &'a GcModel, seqs: &'a RefSeqs) -> Self {
+        Self {
+            model,
+            lookup: FragGcLookup::new(seqs),
+        }
+    }
+
+    pub fn weight(&mut self, cfg: &Config, ctg: &Arc<str>, rec: &BamRec) -> anyhow::Result<f64> {
+        Ok(match fragment(rec) {
+            Some((x, l)) => self
+                .lookup
+                .gc(cfg, ctg, x, l)?
+                .map(|g| self.model.weight(l, g))
+                .unwrap_or(1.0),
+            None => 1.0,
+        })
+    }
+}
+
+fn model_task(
+    cfg: &Config,
+    exp: &FragTable,
+    seqs: &RefSeqs,
+    tpool: Option<&HtsThreadPool>,
+    ix: usize,
+) -> anyhow::Result<GcModel> {
+    let sample = &cfg.sample_list()[ix];
+    debug!(
+        "Getting fragment GC distribution for sample {}",
+        sample.name()
+    );
+    let obs = open_input(sample.input_path(), true, cfg.reference(), tpool)
+        .and_then(|mut hts| read_fragment_gc(cfg, &mut hts, seqs))
+        .with_context(|| {
+            format!(
+                "Error getting fragment GC distribution for sample {}",
+                sample.name()
+            )
+        })?;
+    Ok(GcModel::from_tables(&obs, exp))
+}
+
+/// Fragment GC models for all samples with the shared reference sequences
+pub struct FragGc {
+    models: Vec<GcModel>,
+    seqs: RefSeqs,
+}
+
+impl FragGc {
+    /// Fragment weighter for a sample
+    pub fn weighter(&self, sample_idx: usize) -> FragWeighter<'_> {
+        FragWeighter::new(&self.models[sample_idx], &self.seqs)
+    }
+}
+
+/// Get fragment GC models for all samples.  The expected table is calculated once from the
+/// reference and used for all samples
+pub fn get_gc_models(cfg: &Config, tpool: Option<&HtsThreadPool>) -> anyhow::Result<FragGc> {
+    let exp = expected_table(cfg)?;
+    let seqs = RefSeqs::new(cfg);
+    info!("Getting fragment GC distribution of samples");
+    let ns = cfg.sample_list().len();
+    let models = pool_map(cfg.n_readers(), (0..ns).collect(), "fragment GC", |ix| {
+        model_task(cfg, &exp, &seqs, tpool, ix)
+    })?
+    .into_iter()
+    .collect::<anyhow::Result<_>>()?;
+    Ok(FragGc { models, seqs })
+}
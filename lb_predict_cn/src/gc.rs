@@ -1,13 +1,84 @@
-use std::{collections::HashMap, io::BufRead, path::Path, sync::Arc, thread};
+use std::{collections::HashMap, fs, io::BufRead, path::Path, sync::Arc, thread};
 
 use anyhow::Context;
 use compress_io::compress::CompressIo;
 use crossbeam_channel::{unbounded, Receiver};
 use r_htslib::Faidx;
 
-use crate::contig::Contig;
+use crate::{
+    contig::{contig_alias_lookup, Contig},
+    md5::Md5,
+    twobit::{is_two_bit, TwoBit},
+};
 
-pub const N_GC_BINS: u32 = 128;
+/// Default number of GC bins
+pub const DEFAULT_N_GC_BINS: u32 = 128;
+
+/// Default minimum fraction of valid (non N) bases for a block to have a GC bin
+pub const DEFAULT_MIN_VALID_FRAC: f64 = 0.9;
+
+/// Parameters for calculation of GC bins
+///
+/// n_gc_bins - number of GC bins (the GC fraction is split into n_gc_bins equal bins)
+/// min_valid_frac - minimum fraction of valid bases in a block for the GC bin to be calculated
+/// window_ext - the GC content is calculated over the block extended by window_ext bases on either side
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcParams {
+    n_gc_bins: u32,
+    min_valid_frac: f64,
+    window_ext: u32,
+}
+
+impl Default for GcParams {
+    fn default() -> Self {
+        Self {
+            n_gc_bins: DEFAULT_N_GC_BINS,
+            min_valid_frac: DEFAULT_MIN_VALID_FRAC,
+            window_ext: 0,
+        }
+    }
+}
+
+impl GcParams {
+    pub fn new(n_gc_bins: u32, min_valid_frac: f64, window_ext: u32) -> Self {
+        Self {
+            n_gc_bins,
+            min_valid_frac,
+            window_ext,
+        }
+    }
+
+    pub fn n_gc_bins(&self) -> u32 {
+        self.n_gc_bins
+    }
+
+    pub fn min_valid_frac(&self) -> f64 {
+        self.min_valid_frac
+    }
+
+    pub fn window_ext(&self) -> u32 {
+        self.window_ext
+    }
+
+    /// Minimum number of valid bases for a window of length l (including any extension)
+    fn min_gc_count(&self, l: usize) -> u32 {
+        (self.min_valid_frac * (l as f64)) as u32
+    }
+
+    /// Returns the GC bin corresponding to a set of counts
+    fn gc_bin(&self, counts: &[u32; 3], min_gc_count: u32) -> Option<u32> {
+        let tot = counts[1] + counts[2];
+        if tot >= min_gc_count && tot > 0 {
+            Some(
+                (((counts[2] as f64 / tot as f64) * (self.n_gc_bins as f64)).floor() as u32)
+                    .min(self.n_gc_bins - 1),
+            )
+        } else {
+            None
+        }
+    }
+}
 
 const MTAB: [usize; 256] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -20,78 +91,210 @@ const MTAB: [usize; 256] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
 ];
 
+/// Classify base as other (0), A/T (1) or C/G (2)
+pub fn base_class(c: u8) -> usize {
+    MTAB[c as usize]
+}
+
+fn add_counts(a: &mut [u32; 3], b: &[u32; 3]) {
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x += *y
+    }
+}
+
+/// Accumulates base counts per block for a contig.  The counts are kept at the
+/// finest block size so that the GC bins for coarser block sizes can be generated
+/// by aggregation.  If the GC window is extended past the blocks, the counts for the
+/// first and last window_ext bases of each block are also kept (the extension can not
+/// be larger than the block size).  The number of CpG dinucleotides per block is also
+/// counted (a CpG is assigned to the block containing the G)
 struct GcBuilder {
     ctg: Arc<str>,
-    data: Vec<Option<u32>>,
-    counts: [u32; 3],
+    counts: Vec<[u32; 3]>,
+    head: Vec<[u32; 3]>,
+    tail: Vec<[u32; 3]>,
+    cpg: Vec<u32>,
+    prev: u8,
     block_size: u32,
-    min_gc_count: u32,
+    params: GcParams,
     current_pos: usize,
+    md5: Option<Md5>,
 }
 
 impl GcBuilder {
-    // Returns the bin corresponding to a set of counts
-    fn bin(&self) -> Option<u32> {
-        let tot = self.counts[1] + self.counts[2];
-        if tot >= self.min_gc_count {
-            Some(
-                (((self.counts[2] as f64 / tot as f64) * (N_GC_BINS as f64)).floor() as u32)
-                    .min(N_GC_BINS - 1),
-            )
-        } else {
-            None
-        }
-    }
-
-    fn new(ctg: &Arc<str>, block_size: u32) -> Self {
+    fn new(ctg: &Arc<str>, block_size: u32, params: GcParams, md5: bool) -> Self {
+        assert!(params.window_ext <= block_size);
         Self {
             ctg: Arc::clone(ctg),
-            data: Vec::new(),
-            counts: [0; 3],
+            counts: Vec::new(),
+            head: Vec::new(),
+            tail: Vec::new(),
+            cpg: Vec::new(),
+            prev: 0,
             current_pos: 0,
             block_size,
-            min_gc_count: (0.9 * (block_size as f64)) as u32,
+            params,
+            md5: if md5 { Some(Md5::default()) } else { None },
         }
     }
 
     fn add_u8(&mut self, c: u8) {
-        let ix = self.current_pos / (self.block_size as usize);
-        if ix != self.data.len() {
-            self.update_vec();
+        let bs = self.block_size as usize;
+        let ix = self.current_pos / bs;
+        let ext = self.params.window_ext as usize;
+        if ix == self.counts.len() {
+            self.counts.push([0; 3]);
+            self.cpg.push(0);
+            if ext > 0 {
+                self.head.push([0; 3]);
+                self.tail.push([0; 3]);
+            }
+        }
+        let cl = MTAB[c as usize];
+        self.counts[ix][cl] += 1;
+        let c = c.to_ascii_uppercase();
+        if c == b'G' && self.prev == b'C' {
+            self.cpg[ix] += 1
+        }
+        self.prev = c;
+        if ext > 0 {
+            let p = self.current_pos % bs;
+            if p < ext {
+                self.head[ix][cl] += 1
+            }
+            if p >= bs - ext {
+                self.tail[ix][cl] += 1
+            }
         }
-        self.counts[MTAB[c as usize]] += 1;
         self.current_pos += 1;
     }
 
-    fn add_str(&mut self, s: &str) {
-        for c in s.as_bytes() {
+    fn add_slice(&mut self, s: &[u8]) {
+        for c in s {
             self.add_u8(*c)
         }
+        if let Some(m) = self.md5.as_mut() {
+            m.update_upper(s)
+        }
     }
 
-    fn update_vec(&mut self) {
-        self.data.push(self.bin());
-        self.counts = [0; 3];
+    /// Generate GcCtgData for each of the requested block sizes (which must be
+    /// multiples of the block size of the builder)
+    fn finish(self, block_sizes: &[u32]) -> Vec<GcCtgData> {
+        let md5 = self.md5.map(|m| m.finish());
+        let ext = self.params.window_ext as usize;
+        let n = self.counts.len();
+        let seq_len = self.current_pos;
+        block_sizes
+            .iter()
+            .map(|bs| {
+                let k = (bs / self.block_size) as usize;
+                let bs = *bs as usize;
+                // Number of bases in block j (the last block can be partial)
+                let block_len = |j: usize| bs.min(seq_len - j * bs);
+                // CpG density (CpGs per valid base) per block
+                let cpg = self
+                    .counts
+                    .chunks(k)
+                    .zip(self.cpg.chunks(k))
+                    .enumerate()
+                    .map(|(j, (v, w))| {
+                        let valid: u32 = v.iter().map(|c| c[1] + c[2]).sum();
+                        if valid >= self.params.min_gc_count(block_len(j)) && valid > 0 {
+                            Some((w.iter().sum::<u32>() as f32) / (valid as f32))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                let data = self
+                    .counts
+                    .chunks(k)
+                    .enumerate()
+                    .map(|(j, v)| {
+                        let mut ct = [0; 3];
+                        for c in v {
+                            add_counts(&mut ct, c)
+                        }
+                        // Add bases from the end of the previous block and
+                        // the start of the next block.  The window length only
+                        // includes the extensions where these exist
+                        let mut l = block_len(j);
+                        if ext > 0 {
+                            if j > 0 {
+                                add_counts(&mut ct, &self.tail[j * k - 1]);
+                                l += ext
+                            }
+                            if (j + 1) * k < n {
+                                add_counts(&mut ct, &self.head[(j + 1) * k]);
+                                l += ext.min(seq_len - (j + 1) * bs)
+                            }
+                        }
+                        self.params.gc_bin(&ct, self.params.min_gc_count(l))
+                    })
+                    .collect();
+                GcCtgData {
+                    name: Arc::clone(&self.ctg),
+                    data,
+                    covariates: vec![cpg],
+                    seq_len: self.current_pos,
+                    md5,
+                }
+            })
+            .collect()
     }
 }
 
+/// Per block data for a contig: the GC bin and any additional covariates (the first
+/// covariate is always the CpG density)
 pub struct GcCtgData {
     name: Arc<str>,
     data: Vec<Option<u32>>,
+    covariates: Vec<Vec<Option<f32>>>,
+    seq_len: usize,
+    md5: Option<[u8; 16]>,
 }
 
 impl GcCtgData {
-    pub fn gc(&self, ix: usize) -> Option<u32> {
-        self.data.get(ix).and_then(|x| *x)
-    }
-
-    fn from_builder(mut gcb: GcBuilder) -> Self {
-        gcb.update_vec();
+    pub fn new(
+        name: Arc<str>,
+        data: Vec<Option<u32>>,
+        cpg: Vec<Option<f32>>,
+        seq_len: usize,
+        md5: Option<[u8; 16]>,
+    ) -> Self {
         Self {
-            name: gcb.ctg,
-            data: gcb.data,
+            name,
+            data,
+            covariates: vec![cpg],
+            seq_len,
+            md5,
         }
     }
+
+    pub fn name(&self) -> &Arc<str> {
+        &self.name
+    }
+
+    /// GC bins for all blocks of the contig
+    pub fn data(&self) -> &[Option<u32>] {
+        &self.data
+    }
+
+    /// Values of covariate i for all blocks of the contig
+    pub fn covariate(&self, i: usize) -> &[Option<f32>] {
+        &self.covariates[i]
+    }
+
+    /// Length of contig in reference
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    /// MD5 digest of the reference sequence (if calculated)
+    pub fn md5(&self) -> Option<&[u8; 16]> {
+        self.md5.as_ref()
+    }
 }
 
 /// Read in next line.
@@ -104,52 +307,144 @@ fn get_next_line<R: BufRead>(rdr: &mut R, buf: &mut String) -> anyhow::Result<bo
     }
 }
 
+/// Name of the CpG density covariate (always the first covariate)
+pub const CPG_COVARIATE: &str = "cpg";
+
+/// GC data (plus other covariates) for all contigs for one block size
 pub struct GcData {
+    block_size: u32,
+    params: GcParams,
+    covariate_names: Vec<Arc<str>>,
     chash: HashMap<Arc<str>, GcCtgData>,
 }
 
 impl GcData {
+    pub fn from_ctg_data(block_size: u32, params: GcParams, v: Vec<GcCtgData>) -> Self {
+        let chash = v.into_iter().map(|c| (Arc::clone(&c.name), c)).collect();
+        Self {
+            block_size,
+            params,
+            covariate_names: vec![Arc::from(CPG_COVARIATE)],
+            chash,
+        }
+    }
+
+    /// Names of the covariates
+    pub fn covariate_names(&self) -> &[Arc<str>] {
+        &self.covariate_names
+    }
+
+    /// Add a covariate.  The values for each contig must have one entry per block;
+    /// contigs not present in ctg_values have the covariate set to missing.  Returns the
+    /// index of the new covariate
+    pub fn add_covariate(
+        &mut self,
+        name: &str,
+        mut ctg_values: HashMap<Arc<str>, Vec<Option<f32>>>,
+    ) -> anyhow::Result<usize> {
+        if self.covariate_names.iter().any(|s| s.as_ref() == name) {
+            return Err(anyhow!("Duplicate covariate name {}", name));
+        }
+        for (ctg, c) in self.chash.iter_mut() {
+            let v = ctg_values
+                .remove(ctg)
+                .unwrap_or_else(|| vec![None; c.data.len()]);
+            assert_eq!(v.len(), c.data.len());
+            c.covariates.push(v)
+        }
+        self.covariate_names.push(Arc::from(name));
+        Ok(self.covariate_names.len() - 1)
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Parameters used to calculate the GC bins
+    pub fn params(&self) -> &GcParams {
+        &self.params
+    }
+
+    pub fn n_gc_bins(&self) -> usize {
+        self.params.n_gc_bins as usize
+    }
+
     pub fn ctg_data(&self, ctg: &str) -> Option<&GcCtgData> {
         self.chash.get(ctg)
     }
 
+    pub fn contigs(&self) -> impl Iterator<Item = &GcCtgData> {
+        self.chash.values()
+    }
+
+    /// Calculate GC data for each of the block sizes in a single pass through the reference.
+    /// The block sizes should be sorted in increasing order, and all should be multiples of the first.
+    /// If md5 is set then the MD5 digests of the contig sequences are also calculated
     pub fn from_reference<S: AsRef<Path>>(
         fname: S,
-        block_size: u32,
+        block_sizes: &[u32],
+        params: GcParams,
         nt: usize,
         ctg_hash: &HashMap<Arc<str>, Contig>,
-    ) -> anyhow::Result<Self> {
+        md5: bool,
+    ) -> anyhow::Result<Vec<Self>> {
         debug!(
-            "Reading reference sequence from {} and calculating gc bins with block size {}",
+            "Reading reference sequence from {} and calculating gc bins with block sizes {:?}",
             fname.as_ref().display(),
-            block_size
+            block_sizes
         );
 
-        if nt == 1 {
-            single_threaded_read(fname, block_size, ctg_hash)
+        let chash = if is_two_bit(&fname) {
+            // 2bit files allow random access without a separate index
+            trace!("2bit reference: use multithreaded reading");
+            multi_threaded_read(fname, block_sizes, params, nt, ctg_hash, md5, true)
+        } else if nt == 1 {
+            single_threaded_read(fname, block_sizes, params, ctg_hash, md5)
         } else {
             // Check if the reference has an index
             trace!("Test for faidx index");
             match Faidx::load(&fname) {
                 Ok(_) => {
                     trace!("Index found: use multithreaded reading");
-                    multi_threaded_read(fname, block_size, nt, ctg_hash)
+                    multi_threaded_read(fname, block_sizes, params, nt, ctg_hash, md5, false)
                 }
                 Err(e) => {
                     trace!("Couldn't open file for indexed reading: {}", e);
-                    single_threaded_read(fname, block_size, ctg_hash)
+                    single_threaded_read(fname, block_sizes, params, ctg_hash, md5)
                 }
             }
+        }?;
+
+        // Split data by block size
+        let mut v: Vec<_> = block_sizes
+            .iter()
+            .map(|bs| GcData {
+                block_size: *bs,
+                params,
+                covariate_names: vec![Arc::from(CPG_COVARIATE)],
+                chash: HashMap::with_capacity(chash.len()),
+            })
+            .collect();
+        for (k, ctg_data) in chash {
+            for (gc, c) in v.iter_mut().zip(ctg_data) {
+                gc.chash.insert(Arc::clone(&k), c);
+            }
         }
+        Ok(v)
     }
 }
 
+type CtgGcHash = HashMap<Arc<str>, Vec<GcCtgData>>;
+
 fn multi_threaded_read<S: AsRef<Path>>(
     fname: S,
-    block_size: u32,
+    block_sizes: &[u32],
+    params: GcParams,
     nt: usize,
     ctg_hash: &HashMap<Arc<str>, Contig>,
-) -> anyhow::Result<GcData> {
+    md5: bool,
+    two_bit: bool,
+) -> anyhow::Result<CtgGcHash> {
     let fname = fname.as_ref();
     let mut v = Vec::with_capacity(nt);
     // Everything runs within a scope so that we can pass references to the threads
@@ -166,13 +461,13 @@ fn multi_threaded_read<S: AsRef<Path>>(
         let jobs: Vec<_> = (0..nt)
             .map(|i| {
                 let r = rcv.clone();
-                sc.spawn(move || reader(fname, block_size, i + 1, r))
+                sc.spawn(move || reader(fname, two_bit, block_sizes, params, md5, i + 1, r))
             })
             .collect();
         drop(rcv);
 
         // Send required contigs to child threads
-        for ctg in ctg_hash.keys() {
+        for ctg in ctg_hash.values() {
             if snd.send(ctg).is_err() {
                 error!("Error sending message to child readers");
                 break;
@@ -201,29 +496,148 @@ fn multi_threaded_read<S: AsRef<Path>>(
     }
 
     debug!("Finished reading reference and calculating gc bins");
-    Ok(GcData { chash })
+    Ok(chash)
+}
+
+/// Random access reader for reference sequences
+pub enum RefReader {
+    Faidx(Faidx, HashMap<String, usize>),
+    TwoBit(TwoBit),
+}
+
+/// Read the contig lengths from the faidx index of a FASTA file
+fn fai_lengths(fname: &Path) -> anyhow::Result<HashMap<String, usize>> {
+    let mut fai = fname.as_os_str().to_owned();
+    fai.push(".fai");
+    let fai = Path::new(&fai);
+    let s = fs::read_to_string(fai)
+        .with_context(|| format!("Error reading index file {}", fai.display()))?;
+    s.lines()
+        .filter_map(|l| {
+            let mut it = l.split('\t');
+            it.next().zip(it.next())
+        })
+        .map(|(name, len)| {
+            len.parse::<usize>()
+                .map(|l| (name.to_owned(), l))
+                .with_context(|| format!("Invalid length for contig {} in {}", name, fai.display()))
+        })
+        .collect()
+}
+
+impl RefReader {
+    pub fn open(fname: &Path, two_bit: bool) -> anyhow::Result<Self> {
+        if two_bit {
+            Ok(Self::TwoBit(TwoBit::open(fname)?))
+        } else {
+            let faidx = Faidx::load(fname)
+                .with_context(|| format!("Error opening file {}", fname.display()))?;
+            Ok(Self::Faidx(faidx, fai_lengths(fname)?))
+        }
+    }
+
+    /// Find the name used for a contig in the reference, trying the contig name and then any
+    /// aliases (checking the sequence length for the built in aliases)
+    fn ref_ctg_name<'a>(&mut self, contig: &'a Contig) -> anyhow::Result<Option<&'a str>> {
+        for c in contig.names() {
+            let len = match self {
+                Self::Faidx(_, lens) => lens.get(c).copied(),
+                Self::TwoBit(tb) => tb.seq_len(c)?,
+            };
+            if len.map(|l| contig.matches_seq(c, l)).unwrap_or(false) {
+                return Ok(Some(c));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fetch sequence for contig, trying the contig name and then any aliases,
+    /// and pass to f
+    pub fn with_ctg_seq<F: FnOnce(&[u8])>(&mut self, contig: &Contig, f: F) -> anyhow::Result<()> {
+        let ctg = contig.name();
+        let name = self
+            .ref_ctg_name(contig)?
+            .with_context(|| format!("Contig {} not found in reference", ctg))?;
+        match self {
+            Self::Faidx(faidx, _) => {
+                let s = faidx
+                    .fetch_seq(name, 0, None)
+                    .with_context(|| format!("Error fetching sequence for contig {}", ctg))?;
+                f(s.seq())
+            }
+            Self::TwoBit(tb) => {
+                let s = tb
+                    .fetch_seq(name)?
+                    .with_context(|| format!("Error fetching sequence for contig {}", ctg))?;
+                f(&s)
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch sequence for contig and add to GcBuilder
+    fn add_ctg_seq(&mut self, contig: &Contig, gcb: &mut GcBuilder) -> anyhow::Result<()> {
+        self.with_ctg_seq(contig, |s| gcb.add_slice(s))
+    }
+}
+
+/// Calculate GC bins for a set of regions (0 offset, half open) per contig.  This requires
+/// random access to the reference, so the reference must be an indexed FASTA file or a 2bit file.
+/// As for the genomic bins, the regions are extended by the window extension (if set) and regions
+/// with too few valid bases have no GC bin
+pub fn region_gc_bins<S: AsRef<Path>>(
+    fname: S,
+    regions: &HashMap<Arc<str>, Vec<(usize, usize)>>,
+    params: GcParams,
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+) -> anyhow::Result<HashMap<Arc<str>, Vec<Option<u32>>>> {
+    let fname = fname.as_ref();
+    debug!(
+        "Calculating GC content of regions from reference {}",
+        fname.display()
+    );
+    let mut rdr = RefReader::open(fname, is_two_bit(fname))?;
+    let mut chash = HashMap::with_capacity(regions.len());
+    for (ctg, v) in regions.iter() {
+        let contig = ctg_hash.get(ctg).expect("Unknown contig");
+        let mut gc = Vec::with_capacity(v.len());
+        rdr.with_ctg_seq(contig, |s| {
+            let ext = params.window_ext as usize;
+            for (start, end) in v.iter() {
+                let mut ct = [0; 3];
+                let (x, y) = (
+                    start.saturating_sub(ext).min(s.len()),
+                    (end + ext).min(s.len()),
+                );
+                for c in &s[x..y] {
+                    ct[MTAB[*c as usize]] += 1
+                }
+                gc.push(params.gc_bin(&ct, params.min_gc_count(y - x)))
+            }
+        })?;
+        chash.insert(Arc::clone(ctg), gc);
+    }
+    Ok(chash)
 }
 
 fn reader(
     fname: &Path,
-    block_size: u32,
+    two_bit: bool,
+    block_sizes: &[u32],
+    params: GcParams,
+    md5: bool,
     ix: usize,
-    r: Receiver<&Arc<str>>,
-) -> anyhow::Result<HashMap<Arc<str>, GcCtgData>> {
+    r: Receiver<&Contig>,
+) -> anyhow::Result<CtgGcHash> {
     trace!("Starting up GC reader thread {}", ix);
-    let faidx =
-        Faidx::load(fname).with_context(|| format!("Error opening file {}", fname.display()))?;
+    let mut rdr = RefReader::open(fname, two_bit)?;
     let mut chash = HashMap::new();
-    while let Ok(ctg) = r.recv() {
+    while let Ok(contig) = r.recv() {
+        let ctg = contig.name();
         trace!("GC reader {} processing contig {}", ix, ctg);
-        let mut gcb = GcBuilder::new(ctg, block_size);
-        let s = faidx
-            .fetch_seq(ctg, 0, None)
-            .with_context(|| format!("Error fetching sequence for contig {}", ctg))?;
-        for c in s.seq().iter() {
-            gcb.add_u8(*c)
-        }
-        store_ctg_data(gcb, &mut chash);
+        let mut gcb = GcBuilder::new(ctg, block_sizes[0], params, md5);
+        rdr.add_ctg_seq(contig, &mut gcb)?;
+        store_ctg_data(gcb, block_sizes, &mut chash);
         trace!("GC reader {} finished processing contig {}", ix, ctg);
     }
     trace!("Closing down GC reader thread {}", ix);
@@ -232,20 +646,26 @@ fn reader(
 
 fn single_threaded_read<S: AsRef<Path>>(
     fname: S,
-    block_size: u32,
+    block_sizes: &[u32],
+    params: GcParams,
     ctg_hash: &HashMap<Arc<str>, Contig>,
-) -> anyhow::Result<GcData> {
+    md5: bool,
+) -> anyhow::Result<CtgGcHash> {
     trace!("Opening reference file for reading");
     let mut rdr = CompressIo::new()
         .path(&fname)
         .bufreader()
         .with_context(|| format!("Error opening reference file {}", fname.as_ref().display()))?;
 
+    // Look up table to match reference contig names to the contig list
+    let lookup = contig_alias_lookup(ctg_hash);
+
     trace!("Reading from reference file");
     let mut buf = String::new();
     let mut line = 0;
     let mut chash = HashMap::new();
-    let mut gcb: Option<GcBuilder> = None;
+    // The builder for the current contig along with its name in the reference
+    let mut gcb: Option<(GcBuilder, String)> = None;
     while get_next_line(&mut rdr, &mut buf).with_context(|| {
         format!(
             "Error after reading {} lines from {}",
@@ -257,30 +677,53 @@ fn single_threaded_read<S: AsRef<Path>>(
         if buf.starts_with('>') {
             // New contig
             if let Some(ctg) = buf.trim_start_matches('>').split_ascii_whitespace().next() {
-                if let Some(b) = gcb.take() {
-                    store_ctg_data(b, &mut chash)
+                if let Some((b, name)) = gcb.take() {
+                    store_ref_ctg_data(b, &name, block_sizes, ctg_hash, &mut chash)
                 }
-                if let Some((k, _)) = ctg_hash.get_key_value(ctg) {
-                    gcb = Some(GcBuilder::new(k, block_size));
+                if let Some(k) = lookup.get(ctg) {
+                    gcb = Some((
+                        GcBuilder::new(k, block_sizes[0], params, md5),
+                        ctg.to_owned(),
+                    ));
                     trace!("Processing ctg {}", ctg);
                 }
             } else {
                 return Err(anyhow!("Missing contig name at line {}", line));
             }
-        } else if let Some(b) = gcb.as_mut() {
-            b.add_str(buf.trim_end())
+        } else if let Some((b, _)) = gcb.as_mut() {
+            b.add_slice(buf.trim_end().as_bytes())
         }
     }
-    if let Some(b) = gcb.take() {
-        store_ctg_data(b, &mut chash)
+    if let Some((b, name)) = gcb.take() {
+        store_ref_ctg_data(b, &name, block_sizes, ctg_hash, &mut chash)
     }
     debug!("Finished reading reference and calculating gc bins");
-    Ok(GcData { chash })
+    Ok(chash)
+}
+
+/// Store gc data for a contig read sequentially from the reference.  As the length of the
+/// sequence is only known at the end, this is where aliases restricted to a sequence length
+/// are checked; sequences that do not match are skipped
+fn store_ref_ctg_data(
+    b: GcBuilder,
+    name: &str,
+    block_sizes: &[u32],
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+    chash: &mut CtgGcHash,
+) {
+    let contig = ctg_hash.get(&b.ctg).expect("Unknown contig");
+    if contig.matches_seq(name, b.current_pos) {
+        store_ctg_data(b, block_sizes, chash)
+    } else {
+        debug!(
+            "Reference sequence {} (length {}) skipped as it does not match contig {}",
+            name, b.current_pos, b.ctg
+        )
+    }
 }
 
-fn store_ctg_data(b: GcBuilder, chash: &mut HashMap<Arc<str>, GcCtgData>) {
-    let ctg_data = GcCtgData::from_builder(b);
-    let c = Arc::clone(&ctg_data.name);
+fn store_ctg_data(b: GcBuilder, block_sizes: &[u32], chash: &mut CtgGcHash) {
+    let c = Arc::clone(&b.ctg);
     trace!("Storing gc data for contig {}", c);
-    chash.insert(c, ctg_data);
+    chash.insert(c, b.finish(block_sizes));
 }
@@ -0,0 +1,370 @@
+/// Persistent cache of GC data
+///
+/// Calculating the GC content of the genomic bins requires a complete scan of the reference,
+/// but the results only depend on the reference, the contig list, the block size and the GC
+/// parameters (number of GC bins, minimum valid fraction and window extension).  The
+/// GcData can therefore be stored in a compact binary file and reused on subsequent runs.
+/// The cache is written to a temporary file which is then renamed, so concurrent runs never
+/// see a partially written cache.
+///
+/// The cache file has the following structure (all integers little endian):
+///
+///   magic number "LBGC" and version (u32)
+///   block size (u32), number of GC bins (u32)
+///   minimum valid fraction (f64), window extension (u32)
+///   reference key (MD5 digest of the reference file contents, 16 bytes)
+///   reference file size (u64) and modification time (u64, nanoseconds since the epoch)
+///   number of contigs (u32)
+///   for each contig:
+///     name length (u32) followed by name
+///     sequence length (u64)
+///     MD5 flag (u8) followed by the MD5 digest if the flag is set
+///     number of blocks (u64) followed by the GC bin (u16) per block (u16::MAX for missing)
+///     CpG density (f32) per block (NaN for missing)
+///
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+
+use crate::{
+    cli::GcCacheTask,
+    contig::Contig,
+    gc::{GcCtgData, GcData, GcParams},
+    md5::Md5,
+};
+
+const MAGIC: &[u8; 4] = b"LBGC";
+const VERSION: u32 = 6;
+const MISSING: u16 = u16::MAX;
+
+/// Key used to identify a reference file.  This is the MD5 digest of the file contents, so a
+/// copy of the reference will match the cache and any change to the reference will result in
+/// the cache being regenerated.  Calculating the digest requires reading the whole reference
+/// file, so the size and modification time of the file are also stored, and the digest is only
+/// calculated (once) if these do not match the cache
+pub struct ReferenceKey<'a> {
+    path: &'a Path,
+    size: u64,
+    mtime: u64,
+    digest: Option<[u8; 16]>,
+}
+
+impl<'a> ReferenceKey<'a> {
+    pub fn new(path: &'a Path) -> anyhow::Result<Self> {
+        let md = fs::metadata(path)
+            .with_context(|| format!("Could not access reference file {}", path.display()))?;
+        // A modification time of 0 (not available) never matches the cache
+        let mtime = md
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(Self {
+            path,
+            size: md.len(),
+            mtime,
+            digest: None,
+        })
+    }
+
+    /// MD5 digest of the reference file, calculated on the first call
+    fn digest(&mut self) -> anyhow::Result<[u8; 16]> {
+        if let Some(d) = self.digest {
+            return Ok(d);
+        }
+        debug!("Calculating checksum of reference {}", self.path.display());
+        let mut rdr = fs::File::open(self.path)
+            .with_context(|| format!("Could not open reference file {}", self.path.display()))?;
+        let mut m = Md5::default();
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            let n = rdr
+                .read(&mut buf)
+                .with_context(|| format!("Error reading reference file {}", self.path.display()))?;
+            if n == 0 {
+                break;
+            }
+            m.update(&buf[..n])
+        }
+        let d = m.finish();
+        self.digest = Some(d);
+        Ok(d)
+    }
+
+    /// Check whether the key from a cache file matches the reference
+    fn matches(&mut self, digest: &[u8; 16], size: u64, mtime: u64) -> anyhow::Result<bool> {
+        if size != self.size {
+            Ok(false)
+        } else if mtime == self.mtime && mtime != 0 {
+            Ok(true)
+        } else {
+            Ok(&self.digest()? == digest)
+        }
+    }
+}
+
+/// Default location of cache file (in the same directory as the reference)
+pub fn default_cache_path(reference: &Path, block_size: u32) -> PathBuf {
+    let mut p = OsString::from(reference.as_os_str());
+    p.push(format!(".{}.gcc", block_size));
+    PathBuf::from(p)
+}
+
+fn read_u32<R: Read>(rdr: &mut R) -> anyhow::Result<u32> {
+    let mut b = [0; 4];
+    rdr.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64<R: Read>(rdr: &mut R) -> anyhow::Result<u64> {
+    let mut b = [0; 8];
+    rdr.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// Write GC data to cache file.  The data are written to a temporary file in the same
+/// directory, which is then renamed to the cache file
+pub fn write_gc_cache(path: &Path, key: &mut ReferenceKey, gc_data: &GcData) -> anyhow::Result<()> {
+    debug!("Writing GC cache to {}", path.display());
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(format!(".{}.tmp", process::id()));
+    let tmp = PathBuf::from(tmp);
+    let res = write_gc_cache_file(&tmp, key, gc_data).and_then(|_| {
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Could not rename {} to {}", tmp.display(), path.display()))
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+fn write_gc_cache_file(
+    path: &Path,
+    key: &mut ReferenceKey,
+    gc_data: &GcData,
+) -> anyhow::Result<()> {
+    let digest = key.digest()?;
+    let mut wrt = BufWriter::new(
+        fs::File::create(path)
+            .with_context(|| format!("Could not create GC cache file {}", path.display()))?,
+    );
+    wrt.write_all(MAGIC)?;
+    wrt.write_all(&VERSION.to_le_bytes())?;
+    wrt.write_all(&gc_data.block_size().to_le_bytes())?;
+    let params = gc_data.params();
+    wrt.write_all(&params.n_gc_bins().to_le_bytes())?;
+    wrt.write_all(&params.min_valid_frac().to_le_bytes())?;
+    wrt.write_all(&params.window_ext().to_le_bytes())?;
+    wrt.write_all(&digest)?;
+    wrt.write_all(&key.size.to_le_bytes())?;
+    wrt.write_all(&key.mtime.to_le_bytes())?;
+    let mut ctgs: Vec<_> = gc_data.contigs().collect();
+    ctgs.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+    wrt.write_all(&(ctgs.len() as u32).to_le_bytes())?;
+    for c in ctgs {
+        wrt.write_all(&(c.name().len() as u32).to_le_bytes())?;
+        wrt.write_all(c.name().as_bytes())?;
+        wrt.write_all(&(c.seq_len() as u64).to_le_bytes())?;
+        match c.md5() {
+            Some(d) => {
+                wrt.write_all(&[1])?;
+                wrt.write_all(d)?
+            }
+            None => wrt.write_all(&[0])?,
+        }
+        wrt.write_all(&(c.data().len() as u64).to_le_bytes())?;
+        for x in c.data() {
+            let y = x.map(|x| x as u16).unwrap_or(MISSING);
+            wrt.write_all(&y.to_le_bytes())?
+        }
+        for x in c.covariate(0) {
+            wrt.write_all(&x.unwrap_or(f32::NAN).to_le_bytes())?
+        }
+    }
+    wrt.flush()?;
+    Ok(())
+}
+
+/// Read GC data from cache file.  Returns None if the cache does not match the reference,
+/// the block size, the GC parameters or the contig list, or if MD5 digests are required
+/// and not present
+pub fn read_gc_cache(
+    path: &Path,
+    key: &mut ReferenceKey,
+    block_size: u32,
+    params: GcParams,
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+    md5: bool,
+) -> anyhow::Result<Option<GcData>> {
+    debug!("Reading GC cache from {}", path.display());
+    let mut rdr = BufReader::new(
+        fs::File::open(path)
+            .with_context(|| format!("Could not open GC cache file {}", path.display()))?,
+    );
+    let mut magic = [0; 4];
+    rdr.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow!("File {} is not a GC cache file", path.display()));
+    }
+    let version = read_u32(&mut rdr)?;
+    if version != VERSION {
+        debug!("GC cache version does not match");
+        return Ok(None);
+    }
+    let bs = read_u32(&mut rdr)?;
+    let n_gc_bins = read_u32(&mut rdr)?;
+    let min_valid_frac = f64::from_bits(read_u64(&mut rdr)?);
+    let window_ext = read_u32(&mut rdr)?;
+    let mut cache_key = [0; 16];
+    rdr.read_exact(&mut cache_key)?;
+    let ref_size = read_u64(&mut rdr)?;
+    let ref_mtime = read_u64(&mut rdr)?;
+    if bs != block_size || GcParams::new(n_gc_bins, min_valid_frac, window_ext) != params {
+        debug!("GC cache parameters do not match");
+        return Ok(None);
+    }
+    if !key.matches(&cache_key, ref_size, ref_mtime)? {
+        debug!("GC cache does not match reference");
+        return Ok(None);
+    }
+    let n_ctgs = read_u32(&mut rdr)?;
+    let mut v = Vec::with_capacity(ctg_hash.len());
+    for _ in 0..n_ctgs {
+        let l = read_u32(&mut rdr)? as usize;
+        let mut name = vec![0; l];
+        rdr.read_exact(&mut name)?;
+        let name = String::from_utf8(name).with_context(|| "Invalid contig name")?;
+        let seq_len = read_u64(&mut rdr)? as usize;
+        let mut flag = [0; 1];
+        rdr.read_exact(&mut flag)?;
+        let digest = if flag[0] != 0 {
+            let mut d = [0; 16];
+            rdr.read_exact(&mut d)?;
+            Some(d)
+        } else {
+            None
+        };
+        let n = read_u64(&mut rdr)? as usize;
+        let mut buf = vec![0; n << 1];
+        rdr.read_exact(&mut buf)?;
+        let mut cbuf = vec![0; n << 2];
+        rdr.read_exact(&mut cbuf)?;
+
+        // Only keep contigs from the contig list
+        if let Some((k, _)) = ctg_hash.get_key_value(name.as_str()) {
+            if md5 && digest.is_none() {
+                debug!("GC cache does not have MD5 digests");
+                return Ok(None);
+            }
+            let data = buf
+                .chunks_exact(2)
+                .map(|b| {
+                    let x = u16::from_le_bytes([b[0], b[1]]);
+                    if x == MISSING {
+                        None
+                    } else {
+                        Some(x as u32)
+                    }
+                })
+                .collect();
+            let cpg = cbuf
+                .chunks_exact(4)
+                .map(|b| {
+                    let x = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                    if x.is_nan() {
+                        None
+                    } else {
+                        Some(x)
+                    }
+                })
+                .collect();
+            v.push(GcCtgData::new(Arc::clone(k), data, cpg, seq_len, digest))
+        }
+    }
+    if v.len() != ctg_hash.len() {
+        debug!("GC cache does not contain all contigs");
+        return Ok(None);
+    }
+    Ok(Some(GcData::from_ctg_data(block_size, params, v)))
+}
+
+/// Get GC data for each block size, using the cache files (one per block size) if present and valid.
+/// If any of the caches cannot be used, the GC data are calculated from the reference and the caches
+/// are (re)written.
+pub fn gc_data_with_cache(
+    caches: Option<&[PathBuf]>,
+    reference: &Path,
+    block_sizes: &[u32],
+    params: GcParams,
+    nt: usize,
+    ctg_hash: &HashMap<Arc<str>, Contig>,
+    md5: bool,
+) -> anyhow::Result<Vec<GcData>> {
+    // The reference key is only needed if there are caches to check or write
+    let mut key = match caches {
+        Some(_) => match ReferenceKey::new(reference) {
+            Ok(k) => Some(k),
+            Err(e) => {
+                warn!("GC cache not used: {:#}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    if let (Some(caches), Some(key)) = (caches, key.as_mut()) {
+        let mut v = Vec::with_capacity(caches.len());
+        for (p, bs) in caches.iter().zip(block_sizes) {
+            if !p.exists() {
+                break;
+            }
+            match read_gc_cache(p, key, *bs, params, ctg_hash, md5) {
+                Ok(Some(g)) => {
+                    info!("GC data read from cache {}", p.display());
+                    v.push(g)
+                }
+                Ok(None) => {
+                    info!("GC cache {} out of date; regenerating", p.display());
+                    break;
+                }
+                Err(e) => {
+                    warn!("Could not read GC cache {}: {:#}", p.display(), e);
+                    break;
+                }
+            }
+        }
+        if v.len() == block_sizes.len() {
+            return Ok(v);
+        }
+    }
+    let gc_data = GcData::from_reference(reference, block_sizes, params, nt, ctg_hash, md5)?;
+    if let (Some(caches), Some(key)) = (caches, key.as_mut()) {
+        for (p, gc) in caches.iter().zip(gc_data.iter()) {
+            match write_gc_cache(p, key, gc) {
+                Ok(_) => info!("GC data written to cache {}", p.display()),
+                Err(e) => warn!("Could not write GC cache {}: {:#}", p.display(), e),
+            }
+        }
+    }
+    Ok(gc_data)
+}
+
+/// Write GC cache files (gc subcommand)
+pub fn make_gc_cache(task: &GcCacheTask) -> anyhow::Result<()> {
+    let mut key = ReferenceKey::new(&task.reference)?;
+    for (p, gc) in task.caches.iter().zip(task.gc_data.iter()) {
+        write_gc_cache(p, &mut key, gc)?;
+        info!("GC data written to cache {}", p.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+/// GC models shared between samples
+///
+/// For low depth samples the GC model fitted to a single sample can be unstable.  Instead,
+/// a GC model can be taken from a previous run (i.e., the GC model files written for a
+/// reference sample or panel) or fitted to the pooled data from all samples in the run.  In
+/// either case the predictions of the shared model are rescaled to the depth of each sample
+/// before normalization.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use utils::get_next_line;
+
+use crate::{gc::GcParams, normalize::GcCurve};
+
+/// A GC model for each block size, plus one for the targets (if present)
+pub struct SharedGcModel {
+    levels: Vec<GcCurve>,
+    targets: Option<GcCurve>,
+}
+
+impl SharedGcModel {
+    pub fn new(levels: Vec<GcCurve>, targets: Option<GcCurve>) -> Self {
+        Self { levels, targets }
+    }
+
+    pub fn level(&self, level: usize) -> &GcCurve {
+        &self.levels[level]
+    }
+
+    pub fn levels(&self) -> &[GcCurve] {
+        &self.levels
+    }
+
+    pub fn targets(&self) -> Option<&GcCurve> {
+        self.targets.as_ref()
+    }
+
+    /// Read in GC models from files (as written by output_gc_curves()).  There must be
+    /// one model for each block size; a model for the targets is optional.  The models must
+    /// have been fitted with the same GC binning parameters as the current run
+    pub fn from_files<S: AsRef<Path>>(
+        files: &[S],
+        block_sizes: &[u32],
+        params: &GcParams,
+    ) -> anyhow::Result<Self> {
+        let mut models = HashMap::new();
+        let mut targets = None;
+        for f in files {
+            let f = f.as_ref();
+            let (bs, curve) = read_gc_model(f, params)?;
+            match bs {
+                Some(bs) => {
+                    if models.insert(bs, curve).is_some() {
+                        return Err(anyhow!("Multiple GC models given for block size {}", bs));
+                    }
+                }
+                None => {
+                    if targets.replace(curve).is_some() {
+                        return Err(anyhow!("Multiple GC models given for targets"));
+                    }
+                }
+            }
+        }
+        let levels = block_sizes
+            .iter()
+            .map(|bs| {
+                models
+                    .remove(bs)
+                    .ok_or_else(|| anyhow!("No GC model given for block size {}", bs))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !models.is_empty() {
+            warn!("GC models for unused block sizes ignored")
+        }
+        Ok(Self { levels, targets })
+    }
+}
+
+/// Check a GC binning parameter from the metadata of a GC model file against the current run
+fn check_gc_param<T: std::str::FromStr + PartialEq + std::fmt::Display>(
+    fname: &Path,
+    line: usize,
+    name: &str,
+    v: &str,
+    expected: T,
+) -> anyhow::Result<()> {
+    match v.parse::<T>() {
+        Ok(x) if x == expected => Ok(()),
+        Ok(_) => Err(anyhow!(
+            "{}:{} Incompatible {} ({}, expected {})",
+            fname.display(),
+            line,
+            name,
+            v,
+            expected
+        )),
+        Err(_) => Err(anyhow!(
+            "{}:{} Error reading {}",
+            fname.display(),
+            line,
+            name
+        )),
+    }
+}
+
+/// Read in GC model file, returning the block size (or None for a target model) and
+/// the model predictions.  The GC binning parameters (number of GC bins, minimum valid
+/// fraction and window extension) must be given in the file and match params
+fn read_gc_model(fname: &Path, params: &GcParams) -> anyhow::Result<(Option<u32>, GcCurve)> {
+    debug!("Reading GC model from {}", fname.display());
+    let mut rdr = CompressIo::new()
+        .path(fname)
+        .bufreader()
+        .with_context(|| format!("Error opening GC model file {}", fname.display()))?;
+
+    let mut buf = String::new();
+    let mut line = 0;
+    let mut block_size = None;
+    let n_gc_bins = params.n_gc_bins() as usize;
+    let mut pred = vec![None; n_gc_bins];
+    // Which of n_gc_bins, min_valid_frac and window_ext have been seen
+    let mut seen = [false; 3];
+
+    while let Some(fields) = get_next_line(&mut rdr, &mut buf).with_context(|| {
+        format!(
+            "Error after reading {} lines from {}",
+            line,
+            fname.display()
+        )
+    })? {
+        line += 1;
+        if let Some(s) = fields[0].strip_prefix('#') {
+            // Metadata lines
+            match s.split_once(':').map(|(k, v)| (k.trim(), v.trim())) {
+                Some(("block_size", "targets")) => block_size = Some(None),
+                Some(("block_size", v)) => {
+                    block_size = Some(Some(v.parse::<u32>().with_context(|| {
+                        format!("{}:{} Error reading block size", fname.display(), line)
+                    })?))
+                }
+                Some(("n_gc_bins", v)) => {
+                    check_gc_param(fname, line, "number of GC bins", v, n_gc_bins)?;
+                    seen[0] = true
+                }
+                Some(("min_valid_frac", v)) => {
+                    check_gc_param(
+                        fname,
+                        line,
+                        "minimum valid fraction",
+                        v,
+                        params.min_valid_frac(),
+                    )?;
+                    seen[1] = true
+                }
+                Some(("window_ext", v)) => {
+                    check_gc_param(fname, line, "GC window extension", v, params.window_ext())?;
+                    seen[2] = true
+                }
+                _ => (),
+            }
+        } else if fields[0] != "gc_bin" && !fields[0].is_empty() {
+            if fields.len() < 9 {
+                return Err(anyhow!("{}:{} Too few columns", fname.display(), line));
+            }
+            let ix = fields[0]
+                .parse::<usize>()
+                .ok()
+                .filter(|x| *x < n_gc_bins)
+                .ok_or_else(|| anyhow!("{}:{} Invalid GC bin", fname.display(), line))?;
+            if fields[8] != "NA" {
+                let p = fields[8].parse::<f64>().with_context(|| {
+                    format!("{}:{} Error reading prediction", fname.display(), line)
+                })?;
+                pred[ix] = Some(p)
+            }
+        }
+    }
+    let block_size = block_size
+        .ok_or_else(|| anyhow!("Missing block size in GC model file {}", fname.display()))?;
+    for (s, desc) in seen.iter().zip([
+        "number of GC bins",
+        "minimum valid fraction",
+        "GC window extension",
+    ]) {
+        if !s {
+            return Err(anyhow!(
+                "Missing {} in GC model file {}",
+                desc,
+                fname.display()
+            ));
+        }
+    }
+    if pred.iter().all(|p| p.is_none()) {
+        return Err(anyhow!(
+            "No predictions found in GC model file {}",
+            fname.display()
+        ));
+    }
+    Ok((block_size, GcCurve::from_pred(pred)))
+}
@@ -3,6 +3,88 @@ use std::path::Path;
 use anyhow::Context;
 use r_htslib::*;
 
+use crate::{contig::Contig, twobit::is_two_bit};
+
+/// Find the name used for a contig in an input file, trying first the contig name and then
+/// any aliases (checking the sequence length for the built in aliases).  Returns None if the
+/// contig is not present in the input file
+pub fn input_ctg_name<'a>(hts: &Hts, contig: &'a Contig) -> Option<&'a str> {
+    contig.names().find(|s| {
+        hts.seq_length(s)
+            .map(|l| contig.matches_seq(s, l))
+            .unwrap_or(false)
+    })
+}
+
+/// Sequence information from an @SQ header line
+pub struct SeqInfo {
+    pub name: String,
+    pub len: Option<usize>,
+    pub md5: Option<String>,
+}
+
+/// Get sequence information from the @SQ lines of the header
+pub fn header_seq_info(hts: &Hts) -> Vec<SeqInfo> {
+    let mut v = Vec::new();
+    if let Some(hdr) = hts.header() {
+        for l in hdr.text().lines().filter(|l| l.starts_with("@SQ\t")) {
+            let mut name = None;
+            let mut len = None;
+            let mut md5 = None;
+            for f in l.split('\t').skip(1) {
+                if let Some((tag, val)) = f.split_once(':') {
+                    match tag {
+                        "SN" => name = Some(val.to_owned()),
+                        "LN" => len = val.parse::<usize>().ok(),
+                        "M5" => md5 = Some(val.to_ascii_lowercase()),
+                        _ => (),
+                    }
+                }
+            }
+            if let Some(name) = name {
+                v.push(SeqInfo { name, len, md5 })
+            }
+        }
+    }
+    v
+}
+
+/// Check whether a SAM/BAM/CRAM file is CRAM (from the file extension)
+pub fn is_cram(p: &Path) -> bool {
+    p.extension().map(|e| e == "cram").unwrap_or(false)
+}
+
+/// Open output SAM/BAM/CRAM file.  The output is BAM unless cram is set.  CRAM output
+/// requires a FASTA reference
+pub fn open_output<P: AsRef<Path>>(
+    name: P,
+    cram: bool,
+    reference: &Path,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<Hts> {
+    let name = name.as_ref();
+    debug!("Opening output file {}", name.display());
+    if cram && is_two_bit(reference) {
+        return Err(anyhow!(
+            "Cannot write CRAM file {} with 2bit reference {}",
+            name.display(),
+            reference.display()
+        ));
+    }
+
+    let mut fmt = HtsFormat::default();
+    fmt.opt_add(format!("reference={}", reference.display()))?;
+    let mode = if cram { "wc" } else { "wb" };
+    let mut hts = Hts::open_format(Some(name), mode, &fmt)
+        .with_context(|| format!("Failed to open output file {}", name.display()))?;
+
+    if let Some(tp) = tpool {
+        debug!("Attach thread pool to file");
+        hts.hts_file_mut().set_thread_pool(tp)?
+    }
+    Ok(hts)
+}
+
 pub fn open_input<P: AsRef<Path>>(
     name: P,
     no_index: bool,
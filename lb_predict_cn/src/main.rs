@@ -1,15 +1,35 @@
+mod alias;
+mod bed;
+mod bin_reads;
 mod cli;
 mod config;
 mod contig;
 mod controller;
+mod covariates;
 mod coverage;
+mod dilute;
+mod downsample;
+mod frag_gc;
 mod gc;
+mod gc_cache;
+mod gc_model;
 mod input;
+mod md5;
 mod normalize;
+mod outliers;
 mod output;
+mod pool;
 mod process;
+mod qc;
 mod reader;
+mod rebin;
 mod sample;
+mod subsample;
+mod tag;
+mod targets;
+mod training;
+mod twobit;
+mod validate;
 
 #[macro_use]
 extern crate log;
@@ -19,6 +39,10 @@ extern crate anyhow;
 use anyhow::Context;
 
 fn main() -> anyhow::Result<()> {
-    let cfg = cli::handle_cli().with_context(|| "Error processing command line arguments")?;
-    process::process_samples(&cfg)
+    match cli::handle_cli().with_context(|| "Error processing command line arguments")? {
+        cli::Task::Process(cfg) => process::process_samples(&cfg),
+        cli::Task::MakeGcCache(task) => gc_cache::make_gc_cache(&task),
+        cli::Task::Rebin(task) => rebin::rebin_samples(&task),
+        cli::Task::Dilute(task) => dilute::dilute(&task),
+    }
 }
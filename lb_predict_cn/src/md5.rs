@@ -0,0 +1,166 @@
+const S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+const K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// Minimal MD5 implementation (RFC 1321) used to check reference sequences against the
+/// M5 tags in SAM/BAM/CRAM headers.  Following the SAM specification, the digest is
+/// calculated on the upper case sequence
+pub struct Md5 {
+    state: [u32; 4],
+    buf: [u8; 64],
+    buf_len: usize,
+    len: u64,
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            buf: [0; 64],
+            buf_len: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Md5 {
+    fn process_block(&mut self) {
+        compress(&mut self.state, &self.buf);
+        self.buf_len = 0;
+    }
+
+    fn add_u8(&mut self, c: u8) {
+        self.buf[self.buf_len] = c;
+        self.buf_len += 1;
+        if self.buf_len == 64 {
+            self.process_block()
+        }
+    }
+
+    /// Add data to digest.  Complete 64 byte blocks are processed directly from s, and only
+    /// the partial blocks at the start and end are copied to the block buffer
+    pub fn update(&mut self, mut s: &[u8]) {
+        self.len += s.len() as u64;
+        if self.buf_len > 0 {
+            let k = (64 - self.buf_len).min(s.len());
+            self.buf[self.buf_len..self.buf_len + k].copy_from_slice(&s[..k]);
+            self.buf_len += k;
+            s = &s[k..];
+            if self.buf_len < 64 {
+                return;
+            }
+            self.process_block()
+        }
+        let mut blocks = s.chunks_exact(64);
+        for b in &mut blocks {
+            compress(&mut self.state, b)
+        }
+        let r = blocks.remainder();
+        self.buf[..r.len()].copy_from_slice(r);
+        self.buf_len = r.len();
+    }
+
+    /// Add sequence to digest, converting to upper case
+    pub fn update_upper(&mut self, s: &[u8]) {
+        for c in s {
+            self.add_u8(c.to_ascii_uppercase())
+        }
+        self.len += s.len() as u64;
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bits = self.len.wrapping_mul(8);
+        self.add_u8(0x80);
+        while self.buf_len != 56 {
+            self.add_u8(0)
+        }
+        for c in bits.to_le_bytes() {
+            self.add_u8(c)
+        }
+        let mut digest = [0; 16];
+        for (i, s) in self.state.iter().enumerate() {
+            digest[i * 4..i * 4 + 4].copy_from_slice(&s.to_le_bytes())
+        }
+        digest
+    }
+}
+
+/// MD5 compression function applied to a 64 byte block
+fn compress(state: &mut [u32; 4], block: &[u8]) {
+    let mut m = [0u32; 16];
+    for (x, b) in m.iter_mut().zip(block.chunks_exact(4)) {
+        *x = u32::from_le_bytes(b.try_into().unwrap())
+    }
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..64 {
+        let (f, g) = match i >> 4 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) & 15),
+            2 => (b ^ c ^ d, (3 * i + 5) & 15),
+            _ => (c ^ (b | !d), (7 * i) & 15),
+        };
+        let f = f.wrapping_add(a).wrapping_add(K[i]).wrapping_add(m[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(S[i]));
+    }
+    for (s, x) in state.iter_mut().zip([a, b, c, d]) {
+        *s = s.wrapping_add(x)
+    }
+}
+
+/// Format digest as lower case hex string (as used in M5 tags)
+pub fn md5_hex(d: &[u8; 16]) -> String {
+    d.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(s: &[u8]) -> String {
+        let mut m = Md5::default();
+        m.update(s);
+        md5_hex(&m.finish())
+    }
+
+    #[test]
+    fn rfc1321_vectors() {
+        assert_eq!(digest(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(digest(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(digest(&[b'a'; 1000]), "cabe45dcc9ae5b66ba86600cca6b8ba8");
+    }
+
+    #[test]
+    fn split_updates() {
+        let s: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        for k in [1, 63, 64, 65, 200] {
+            let mut m = Md5::default();
+            for c in s.chunks(k) {
+                m.update(c)
+            }
+            assert_eq!(md5_hex(&m.finish()), digest(&s));
+        }
+    }
+
+    #[test]
+    fn upper_case() {
+        let mut m = Md5::default();
+        m.update_upper(b"abc");
+        assert_eq!(md5_hex(&m.finish()), digest(b"ABC"));
+    }
+}
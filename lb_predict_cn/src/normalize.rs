@@ -1,24 +1,74 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::Arc,
+};
 
-use crate::{config::Config, coverage::*, gc::N_GC_BINS};
+use anyhow::Context;
 
-/// Collect counts per GC bin from the requested chromosomes
-fn collect_bin_data(cfg: &Config, rc: &RawCounts) -> Vec<Vec<f64>> {
-    let mut bin_counts: Vec<Vec<f64>> = vec![Vec::new(); N_GC_BINS as usize];
+use utils::robust_sd;
+
+use crate::{
+    config::Config,
+    covariates::{bin_covariates, ctg_covariates, fit_covariates, CovariateFit},
+    coverage::*,
+    gc::{GcCtgData, GcData, GcParams},
+    gc_model::SharedGcModel,
+    outliers::{normalized_outliers, OutlierMode, RawOutliers},
+    targets::{Targets, MIN_OFF_TARGET_FRACTION},
+    training::TrainMask,
+};
+
+/// Minimum predicted mean coverage of a target for a GC bin to be used
+const MIN_TARGET_PRED: f64 = 1.0;
+
+/// Number of training bins used for the running median of the normalized coverage
+/// when excluding deviant bins
+const REFIT_WINDOW: usize = 11;
+
+pub type GcBins<'a> = HashMap<Arc<str>, Cow<'a, [Option<u32>]>>;
+
+/// Collect counts (converted to f64 using f) per GC bin from the requested chromosomes.
+/// If train is set, only the bins in the training mask are used
+fn collect_bin_data<T: Copy, F: Fn(T) -> f64>(
+    cfg: &Config,
+    gc_bins: &GcBins,
+    rc: &HashMap<Arc<str>, Vec<T>>,
+    train: Option<&TrainMask>,
+    f: F,
+) -> Vec<Vec<f64>> {
+    let mut bin_counts: Vec<Vec<f64>> = vec![Vec::new(); cfg.gc_data()[0].n_gc_bins()];
     for contig in cfg
         .ctg_hash()
         .values()
         .filter(|c| c.use_for_normalization())
     {
         let ctg = contig.name();
-        if let Some(raw_cts) = rc.get(ctg) {
-            let gc = cfg
-                .gc_data()
-                .ctg_data(ctg)
-                .expect("Missing GC data for contig");
-            for (ix, ct) in raw_cts.iter().enumerate() {
-                if let Some(j) = gc.gc(ix) {
-                    bin_counts[j as usize].push(*ct as f64);
+        if let (Some(raw_cts), Some(gc)) = (rc.get(ctg), gc_bins.get(ctg)) {
+            match train {
+                Some(t) => {
+                    if let Some(mask) = t.get(ctg) {
+                        for ((ct, g), _) in raw_cts
+                            .iter()
+                            .zip(gc.iter())
+                            .zip(mask.iter())
+                            .filter(|(_, m)| **m)
+                        {
+                            if let Some(j) = g {
+                                bin_counts[*j as usize].push(f(*ct));
+                            }
+                        }
+                    }
+                }
+                None => {
+                    for (ct, g) in raw_cts.iter().zip(gc.iter()) {
+                        if let Some(j) = g {
+                            bin_counts[*j as usize].push(f(*ct));
+                        }
+                    }
                 }
             }
         }
@@ -26,142 +76,209 @@ fn collect_bin_data(cfg: &Config, rc: &RawCounts) -> Vec<Vec<f64>> {
     bin_counts
 }
 
+/// Weighting of the observations (GC bins) in the LOESS fit
+#[derive(Debug, Clone, Copy)]
+pub enum LoessWeight {
+    Count,           // Number of blocks in the GC bin
+    InverseVariance, // Inverse of estimated variance of the median (from the quartiles)
+}
+
+impl FromStr for LoessWeight {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "count" => Ok(Self::Count),
+            "inv-var" | "inverse-variance" => Ok(Self::InverseVariance),
+            _ => Err("no match"),
+        }
+    }
+}
+
+impl fmt::Display for LoessWeight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Count => write!(f, "count"),
+            Self::InverseVariance => write!(f, "inv-var"),
+        }
+    }
+}
+
 struct Obs {
     n: usize,  // Number of observations
     ix: usize, // Original GC bin
     quartiles: [f64; 3],
+    weight: f64,
 }
 
 impl Obs {
-    fn new(ix: usize, v: &mut [f64]) -> Option<Self> {
+    fn new(ix: usize, v: &mut [f64], weighting: LoessWeight) -> Option<Self> {
         let n = v.len();
         if n == 0 {
             None
         } else {
             v.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
             let quartiles = [v[n >> 2], v[n >> 1], v[(n * 3) >> 2]];
-            Some(Self { n, ix, quartiles })
+            let weight = match weighting {
+                LoessWeight::Count => n as f64,
+                LoessWeight::InverseVariance => {
+                    // Variance of the median is proportional to var / n
+                    let iqr = quartiles[2] - quartiles[0];
+                    let sd = robust_sd(iqr, n).unwrap_or(iqr / 1.35).max(1.0);
+                    (n as f64) / (sd * sd)
+                }
+            };
+            Some(Self {
+                n,
+                ix,
+                quartiles,
+                weight,
+            })
         }
     }
 
-    fn weight(&self) -> f64 {
-        self.n as f64
-        //        let sd = ((self.quartiles[2] - self.quartiles[0]).max(1) as f64) / 1.35;
-        //        (self.n as f64) / (sd * sd)
+    // The median is used as the response
+    fn y(&self) -> f64 {
+        self.quartiles[1]
     }
 }
 
+/// Maximum degree of the local polynomial in the LOESS fit
+pub const MAX_LOESS_DEGREE: usize = 2;
+
+const N_COEF: usize = MAX_LOESS_DEGREE + 1;
+
 // Accumulate regression equations
-// X is a n x 3 matrix; W and Y are n x 1 vectors
-// X'WX is symmetric 3 x 3 matrix and X'WX[3,1] = X'WX[2,2] = X'WX[1,3]
-// We calculate just the lower triangle of X'WX
+// X is a n x (d + 1) matrix where d is the degree of the local polynomial;
+// W and Y are n x 1 vectors.  X'WX is a symmetric (d + 1) x (d + 1) matrix,
+// and we calculate just the lower triangle
 struct Accum {
-    xwx: [f64; 6],
-    xwy: [f64; 3],
+    xwx: [[f64; N_COEF]; N_COEF],
+    xwy: [f64; N_COEF],
+    n_coef: usize,
     x0: f64,
     win_size: f64,
 }
 
 impl Accum {
-    fn new(x0: usize, win_size: usize) -> Self {
+    fn new(x0: usize, win_size: usize, degree: usize) -> Self {
         Self {
-            xwx: [0.0; 6],
-            xwy: [0.0; 3],
+            xwx: [[0.0; N_COEF]; N_COEF],
+            xwy: [0.0; N_COEF],
+            n_coef: degree + 1,
             x0: x0 as f64,
             win_size: win_size as f64,
         }
     }
 
-    // Accumulate contribution of observation to LS matrices
-    fn accum(&mut self, o: &Obs) {
+    // Accumulate contribution of observation to LS matrices; rw is the robustness weight
+    fn accum(&mut self, o: &Obs, rw: f64) {
         let x = (o.ix as f64) - self.x0;
         let d = x.abs() / self.win_size;
         // sanity check
         assert!(d <= 1.0);
         // tricube kernel
         let z = 1.0 - d * d * d;
-        // weight = (1/estimate variance) * tricubic kernel
-        let w = o.weight() * z * z * z;
-        let x2 = x * x;
-        let x3 = x * x2;
-        let x4 = x2 * x2;
-        // Use the median as our y value
-        let y = o.quartiles[1];
-        // Accumulate lower triangle of XWX
-        self.xwx[0] += w; // Sigma w
-        self.xwx[1] += w * x; // w * x
-        self.xwx[2] += w * x2; // w * x^2
-        self.xwx[3] += w * x2; // w * x^2
-        self.xwx[4] += w * x3; // w * x^3
-        self.xwx[5] += w * x4; // w * x^4
-        self.xwy[0] += w * y; // w * y
-        self.xwy[1] += w * x * y; // w * x * y
-        self.xwy[2] += w * x2 * y; // w * x^2 * y
-    }
-
-    // Calculate Choleshy decomposition of X'WX matrix
-    // Decomposition stored in place
-    fn chol(&mut self) {
-        let x = &mut self.xwx;
-        assert!(x[0] > 0.0, "Matrix not PD");
-        x[0] = x[0].sqrt(); // L[0,0]
-        x[1] /= x[0]; // L[1,0]
-        let z = x[2] - x[1] * x[1];
-        assert!(z > 0.0, "Matrix not PD");
-        x[2] = z.sqrt(); // L[1,1]
-        x[3] /= x[0]; // L[2,0]
-        x[4] = (x[4] - x[1] * x[3]) / x[2]; // L[2,1]
-        let z = x[5] - x[3] * x[3] - x[4] * x[4];
-        assert!(z > 0.0, "Matrix not PD");
-        x[5] = z.sqrt(); // L[2;2]
-    }
-
-    fn solve(&mut self, b: &mut [f64]) {
-        self.chol();
-        let c = &mut self.xwx;
-        let y = &mut self.xwy;
-        let a0 = y[0] / c[0];
-        let a1 = (y[1] - c[1] * a0) / c[2];
-        let a2 = (y[2] - c[3] * a0 - c[4] * a1) / c[5];
-        b[2] = a2 / c[5];
-        b[1] = (a1 - c[4] * b[2]) / c[2];
-        b[0] = (a0 - c[3] * b[2] - c[1] * b[1]) / c[0];
+        // weight = observation weight * robustness weight * tricubic kernel
+        let w = o.weight * rw * z * z * z;
+        // Powers of x
+        let mut xp = [1.0; N_COEF];
+        for k in 1..self.n_coef {
+            xp[k] = xp[k - 1] * x
+        }
+        let y = o.y();
+        for (i, (row, xy)) in self
+            .xwx
+            .iter_mut()
+            .zip(self.xwy.iter_mut())
+            .take(self.n_coef)
+            .enumerate()
+        {
+            for (a, xj) in row[..=i].iter_mut().zip(xp.iter()) {
+                *a += w * xp[i] * xj
+            }
+            *xy += w * xp[i] * y
+        }
+    }
+
+    // Calculate Cholesky decomposition of X'WX matrix
+    // Decomposition stored in place.  Returns None if the matrix is not PD
+    fn chol(&mut self) -> Option<()> {
+        let a = &mut self.xwx;
+        for j in 0..self.n_coef {
+            let z = a[j][j] - a[j][..j].iter().map(|x| x * x).sum::<f64>();
+            if !(z > 0.0 && z.is_finite()) {
+                return None;
+            }
+            let d = z.sqrt();
+            a[j][j] = d;
+            for i in j + 1..self.n_coef {
+                let z = a[i][j]
+                    - a[i][..j]
+                        .iter()
+                        .zip(a[j][..j].iter())
+                        .map(|(x, y)| x * y)
+                        .sum::<f64>();
+                a[i][j] = z / d
+            }
+        }
+        Some(())
+    }
+
+    // Solve X'WX beta = X'WY
+    fn solve(&mut self) -> Option<[f64; N_COEF]> {
+        self.chol()?;
+        let n = self.n_coef;
+        let c = &self.xwx;
+        // Solve L a = X'WY
+        let mut a = [0.0; N_COEF];
+        for i in 0..n {
+            let z: f64 = c[i][..i].iter().zip(a.iter()).map(|(x, y)| x * y).sum();
+            a[i] = (self.xwy[i] - z) / c[i][i]
+        }
+        // Solve L' b = a
+        let mut b = [0.0; N_COEF];
+        for i in (0..n).rev() {
+            let z: f64 = (i + 1..n).map(|k| c[k][i] * b[k]).sum();
+            b[i] = (a[i] - z) / c[i][i]
+        }
+        Some(b)
     }
 }
 
 struct Fit {
-    x: isize,       // centre point of regression
-    beta: [f64; 3], // regression coefficients
+    x: isize,            // centre point of regression
+    beta: [f64; N_COEF], // regression coefficients
 }
 
 impl Fit {
     // Fit local regression with the observations in obs at the position
-    // given by the observation obs[i]
-    fn fit_local_regression(obs: &[Obs], i: usize) -> Self {
+    // given by the observation obs[i], using the robustness weights rw.
+    // Returns None if the regression matrix is singular
+    fn fit_local_regression(obs: &[Obs], rw: &[f64], i: usize, degree: usize) -> Option<Self> {
         // x coordinate of location where we are performing the fit
         let x0 = obs[i].ix;
         // window size (max distance from index location)
         let d = (obs.last().unwrap().ix - x0).max(x0 - obs[0].ix);
 
-        let mut ls = Accum::new(x0, d);
+        let mut ls = Accum::new(x0, d, degree);
 
         // Accumulate Least square matrices
-        for o in obs {
-            ls.accum(o);
+        for (o, w) in obs.iter().zip(rw) {
+            ls.accum(o, *w);
         }
-        // Get Cholesky decomposition of XWX (in place)
-        let mut beta = [0.0; 3];
-        ls.solve(&mut beta);
-        Fit {
+        // Solve using Cholesky decomposition of XWX (in place)
+        let beta = ls.solve()?;
+        Some(Fit {
             x: x0 as isize,
             beta,
-        }
+        })
     }
 
-    fn pred(&self, pos: isize) -> Option<f64> {
+    fn pred(&self, pos: isize, min_pred: f64) -> Option<f64> {
         let x = (pos - self.x) as f64;
-        let y = self.beta[0] + x * self.beta[1] + x * x * self.beta[2];
-        if y < 10.0 {
+        let y = self.beta.iter().rev().fold(0.0, |s, b| s * x + b);
+        if y < min_pred {
             None
         } else {
             Some(y)
@@ -169,81 +286,864 @@ impl Fit {
     }
 }
 
-fn smooth(mut bc: Vec<Vec<f64>>) -> Vec<Option<f64>> {
+// Fit local model with region_size points in each window
+// with the fitted point being as close as possible to the
+// centre of the window.  Returns None if any of the local fits fail
+fn fit_all(obs: &[Obs], rw: &[f64], region_size: usize, degree: usize) -> Option<Vec<Fit>> {
+    let mut left = 0;
+    let mut right = region_size - 1;
+    let l = obs.len();
+    let mut fit = Vec::with_capacity(l);
+    for i in 0..l {
+        fit.push(Fit::fit_local_regression(
+            &obs[left..=right],
+            &rw[left..=right],
+            i - left,
+            degree,
+        )?);
+        // Update window for next point if this brings the next point closer to the centre
+        // i.e., if right - (i + 1) < (i + 1) - left
+        if right + left < 2 * (i + 1) && right < l - 1 {
+            left += 1;
+            right += 1;
+        }
+    }
+    Some(fit)
+}
+
+// Update robustness weights using the bisquare function of the residuals scaled
+// by 6 times the median absolute residual (Cleveland, 1979).  Returns false if
+// the median absolute residual is zero, in which case the weights are not changed
+fn robustness_weights(obs: &[Obs], fit: &[Fit], rw: &mut [f64]) -> bool {
+    let res: Vec<f64> = obs
+        .iter()
+        .zip(fit.iter())
+        .map(|(o, f)| o.y() - f.beta[0])
+        .collect();
+    let mut a: Vec<f64> = res.iter().map(|r| r.abs()).collect();
+    a.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let s = 6.0 * a[a.len() >> 1];
+    if s > 0.0 {
+        for (w, r) in rw.iter_mut().zip(res) {
+            let u = r / s;
+            *w = if u.abs() < 1.0 {
+                let z = 1.0 - u * u;
+                z * z
+            } else {
+                0.0
+            }
+        }
+        true
+    } else {
+        false
+    }
+}
+
+// Fit local model of the given degree, followed by the requested number of robustness iterations
+// Returns the local fits and the robustness weights used for the final fit
+fn fit_model(
+    cfg: &Config,
+    obs: &[Obs],
+    region_size: usize,
+    degree: usize,
+) -> Option<(Vec<Fit>, Vec<f64>)> {
+    let mut rw = vec![1.0; obs.len()];
+    let mut fit = fit_all(obs, &rw, region_size, degree)?;
+
+    // Robustness iterations
+    for _ in 0..cfg.loess_iterations() {
+        if !robustness_weights(obs, &fit, &mut rw) {
+            break;
+        }
+        fit = fit_all(obs, &rw, region_size, degree)?;
+    }
+    Some((fit, rw))
+}
+
+/// Fitted GC model for a sample at one block size (or for the targets)
+pub struct GcCurve {
+    obs: Vec<Obs>,          // GC bins with data
+    rw: Vec<f64>,           // Robustness weights of obs from final fit
+    pred: Vec<Option<f64>>, // Predicted counts per GC bin
+    covariate_fit: Option<CovariateFit>,
+}
+
+impl GcCurve {
+    /// GC model with predictions only (i.e., read in from a file)
+    pub fn from_pred(pred: Vec<Option<f64>>) -> Self {
+        Self {
+            obs: Vec::new(),
+            rw: Vec::new(),
+            pred,
+            covariate_fit: None,
+        }
+    }
+
+    pub fn pred(&self) -> &[Option<f64>] {
+        &self.pred
+    }
+
+    /// Number of (genomic or target) bins used for training the model
+    pub fn n_training_bins(&self) -> usize {
+        self.obs.iter().map(|o| o.n).sum()
+    }
+
+    /// Regression of residuals on additional covariates (if performed)
+    pub fn covariate_fit(&self) -> Option<&CovariateFit> {
+        self.covariate_fit.as_ref()
+    }
+
+    /// Write table with one line per GC bin with data or a prediction.  Columns are the GC bin,
+    /// the midpoint of the GC bin, the number of observations, the quartiles of the counts,
+    /// the weight and final robustness weight in the LOESS fit, the LOESS prediction and the
+    /// scaling factor applied to the counts to get the normalized coverage.  The prediction and
+    /// scaling factor are from the GC model only; if additional covariates are used then the
+    /// per bin adjustments are not included (these are given in the covariates file).  The table
+    /// is preceded by metadata lines giving the sample name, the block size (or targets) and
+    /// the GC binning parameters, so that the file can be read in as a shared GC model
+    pub fn write<W: Write>(
+        &self,
+        wrt: &mut W,
+        sample: &str,
+        block_size: Option<u32>,
+        params: &GcParams,
+    ) -> io::Result<()> {
+        writeln!(wrt, "# sample: {}", sample)?;
+        match block_size {
+            Some(bs) => writeln!(wrt, "# block_size: {}", bs)?,
+            None => writeln!(wrt, "# block_size: targets")?,
+        }
+        let n_gc_bins = self.pred.len();
+        writeln!(wrt, "# n_gc_bins: {}", n_gc_bins)?;
+        writeln!(wrt, "# min_valid_frac: {}", params.min_valid_frac())?;
+        writeln!(wrt, "# window_ext: {}", params.window_ext())?;
+        writeln!(wrt, "# n_training_bins: {}", self.n_training_bins())?;
+        if self.covariate_fit.is_some() {
+            writeln!(
+                wrt,
+                "# scale: GC model only (covariate adjustments are in the covariates file)"
+            )?;
+        }
+        writeln!(
+            wrt,
+            "gc_bin\tgc\tn\tq1\tmedian\tq3\tweight\trobustness_weight\tpred\tscale"
+        )?;
+        let mut obs = self.obs.iter().zip(self.rw.iter()).peekable();
+        for (ix, p) in self.pred.iter().enumerate() {
+            let o = obs.next_if(|(o, _)| o.ix == ix);
+            if o.is_none() && p.is_none() {
+                continue;
+            }
+            write!(
+                wrt,
+                "{}\t{:.4}",
+                ix,
+                ((ix as f64) + 0.5) / (n_gc_bins as f64)
+            )?;
+            match o {
+                Some((o, rw)) => write!(
+                    wrt,
+                    "\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+                    o.n, o.quartiles[0], o.quartiles[1], o.quartiles[2], o.weight, rw
+                )?,
+                None => write!(wrt, "\tNA\tNA\tNA\tNA\tNA\tNA")?,
+            }
+            match p {
+                Some(p) => writeln!(wrt, "\t{:.4}\t{:.6}", p, 2.0 / p)?,
+                None => writeln!(wrt, "\tNA\tNA")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fit GC model, returning the fitted curve.  If the local fit fails
+/// (because too few GC bins have data or the regression matrix is singular) then
+/// lower degree fits are tried.  An error is returned if no fit is possible
+fn smooth(cfg: &Config, mut bc: Vec<Vec<f64>>, min_pred: f64) -> anyhow::Result<GcCurve> {
     let n = bc.len();
 
-    // Get median and weights (from inverse of estimated samples variance / n)
+    // Get median and weights
     let obs: Vec<_> = bc
         .iter_mut()
         .enumerate()
-        .flat_map(|(ix, v)| Obs::new(ix, v))
+        .flat_map(|(ix, v)| Obs::new(ix, v, cfg.loess_weight()))
         .collect();
 
-    // Perform smoothing using a local quadratic function and a tricubic kernel
+    if obs.len() < 2 {
+        return Err(anyhow!(
+            "Too few GC bins with data ({}) to fit GC model",
+            obs.len()
+        ));
+    }
+
+    // Perform smoothing using a local polynomial and a tricubic kernel
 
     // Number of points in smoothing region
-    const REGION_SIZE: usize = 31;
+    let region_size = obs.len().min(cfg.loess_span());
 
-    let region_size = obs.len().min(REGION_SIZE);
-    // Can't fit local quadratic with less than 3 points...
-    assert!(region_size > 2);
+    // Need at least degree + 2 points as the furthest point in the window has zero weight
+    let max_degree = cfg.loess_degree().min(region_size - 2);
 
-    // Fit local model with region_size points in each window
-    // with the fitted point being as close as possible to the
-    // centre of the window
-    let mut left = 0;
-    let mut right = region_size - 1;
-    let l = obs.len();
-    let mut fit = Vec::with_capacity(l);
-    for i in 0..l {
-        fit.push(Fit::fit_local_regression(&obs[left..=right], i - left));
-        // Update window for next point
-        if right - i - 1 < i + 1 - left && right < l - 1 {
-            left += 1;
-            right += 1;
-        }
+    let (degree, (fit, rw)) = (0..=max_degree)
+        .rev()
+        .find_map(|d| fit_model(cfg, &obs, region_size, d).map(|f| (d, f)))
+        .ok_or_else(|| anyhow!("Could not fit GC model (regression matrix not PD)"))?;
+
+    if degree < cfg.loess_degree() {
+        warn!(
+            "Could not fit GC model with local polynomial of degree {}; using degree {}",
+            cfg.loess_degree(),
+            degree
+        )
     }
 
     // Storage for predictions
     let mut pred = vec![None; n];
 
-    pred[fit[0].x as usize] = Some(fit[0].beta[0]);
+    pred[fit[0].x as usize] = fit[0].pred(fit[0].x, min_pred);
     for f in fit.windows(2) {
         for x in f[0].x + 1..=f[1].x {
             let k = if f[1].x - x > x - f[0].x { 0 } else { 1 };
-            pred[x as usize] = f[k].pred(x)
+            pred[x as usize] = f[k].pred(x, min_pred)
         }
     }
-    pred
+    Ok(GcCurve {
+        obs,
+        rw,
+        pred,
+        covariate_fit: None,
+    })
 }
 
-/// Normalize coverage data for a sample based on GC content
-/// This is done by getting the median coverage per GC bin from
-/// contigs (normally the autosomes)
-pub fn normalize_sample(cfg: &Config, mut rc: RawCounts) -> NormCov {
-    // First collect counts per GC bin
-    let bin_counts = collect_bin_data(cfg, &rc);
+/// Get GC model for a sample from a shared model.  The predictions of the shared model are
+/// scaled by the median ratio of the observed counts to the predicted counts (over the bins
+/// in GC bins with predictions), so that as for a fitted model the median of the scaled counts
+/// is close to 1
+fn rescale_model(
+    cfg: &Config,
+    mut bc: Vec<Vec<f64>>,
+    model: &GcCurve,
+    min_pred: f64,
+) -> anyhow::Result<GcCurve> {
+    let mut ratios: Vec<f64> = bc
+        .iter()
+        .zip(model.pred.iter())
+        .filter_map(|(v, p)| p.filter(|p| *p > 0.0).map(|p| (v, p)))
+        .flat_map(|(v, p)| v.iter().map(move |c| c / p))
+        .collect();
+    if ratios.is_empty() {
+        return Err(anyhow!("No bins in GC bins with predictions from GC model"));
+    }
+    ratios.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let scale = ratios[ratios.len() >> 1];
+    if scale <= 0.0 {
+        return Err(anyhow!("Median count is zero for GC bins with predictions"));
+    }
+    let pred = model
+        .pred
+        .iter()
+        .map(|p| p.map(|p| p * scale).filter(|p| *p >= min_pred))
+        .collect();
+
+    let obs: Vec<_> = bc
+        .iter_mut()
+        .enumerate()
+        .flat_map(|(ix, v)| Obs::new(ix, v, cfg.loess_weight()))
+        .collect();
+    let rw = vec![1.0; obs.len()];
+    Ok(GcCurve {
+        obs,
+        rw,
+        pred,
+        covariate_fit: None,
+    })
+}
+
+/// Get GC model for a sample, either by fitting to the sample data or by rescaling a shared model
+fn fit_curve(
+    cfg: &Config,
+    bc: Vec<Vec<f64>>,
+    model: Option<&GcCurve>,
+    min_pred: f64,
+) -> anyhow::Result<GcCurve> {
+    match model {
+        Some(m) => rescale_model(cfg, bc, m, min_pred),
+        None => smooth(cfg, bc, min_pred),
+    }
+}
+
+/// Pool counts per GC bin from multiple samples.  The counts from each sample are scaled
+/// so that all samples have the same mean count (the average of the sample means)
+fn pool_bin_data(mut v: Vec<Vec<Vec<f64>>>) -> Vec<Vec<f64>> {
+    let means: Vec<Option<f64>> = v
+        .iter()
+        .map(|bc| {
+            let (n, tot) = bc.iter().fold((0, 0.0), |(n, t), x| {
+                (n + x.len(), t + x.iter().sum::<f64>())
+            });
+            if tot > 0.0 {
+                Some(tot / (n as f64))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let (n, tot) = means
+        .iter()
+        .flatten()
+        .fold((0, 0.0), |(n, t), m| (n + 1, t + m));
+    let mut pooled = vec![Vec::new(); v.first().map(|bc| bc.len()).unwrap_or(0)];
+    if n > 0 {
+        let mean = tot / (n as f64);
+        for (bc, m) in v.drain(..).zip(means) {
+            if let Some(m) = m {
+                let z = mean / m;
+                for (p, x) in pooled.iter_mut().zip(bc) {
+                    p.extend(x.iter().map(|c| c * z))
+                }
+            }
+        }
+    }
+    pooled
+}
+
+/// Aggregate raw counts from the smallest block size into blocks k times larger
+pub fn aggregate_counts(rc: &RawCounts, k: usize) -> RawCounts {
+    rc.iter()
+        .map(|(ctg, v)| (ctg.clone(), v.chunks(k).map(|c| c.iter().sum()).collect()))
+        .collect()
+}
+
+/// Aggregate missing bins from the smallest block size into blocks k times larger.  A block
+/// is missing if any of the bins it contains are missing
+pub fn aggregate_missing(m: &MissingBins, k: usize) -> MissingBins {
+    m.iter()
+        .map(|(ctg, v)| {
+            (
+                ctg.clone(),
+                v.chunks(k).map(|c| c.contains(&true)).collect(),
+            )
+        })
+        .collect()
+}
+
+fn ctg_gc_data<'a>(gc_data: &'a GcData, ctg: &str) -> anyhow::Result<&'a GcCtgData> {
+    gc_data.ctg_data(ctg).ok_or_else(|| {
+        anyhow!(
+            "Missing GC data for contig {} (block size {})",
+            ctg,
+            gc_data.block_size()
+        )
+    })
+}
+
+/// Mask bins that are mostly covered by targets plus padding, and scale the model counts of
+/// the remaining bins to account for the masked bases (the raw counts are not changed).
+/// Returns the GC bins for each contig with the masked bins set to None
+fn mask_targets<'a>(
+    targets: &Targets,
+    gc_data: &'a GcData,
+    mc: &mut RawCounts,
+) -> anyhow::Result<GcBins<'a>> {
+    let bs = gc_data.block_size() as usize;
+    let mut gc_bins = HashMap::with_capacity(mc.len());
+    for (ctg, cts) in mc.iter_mut() {
+        let gc = ctg_gc_data(gc_data, ctg)?;
+        let bins = match targets.ctg_targets(ctg) {
+            Some(t) => {
+                let mut bins = gc.data().to_vec();
+                let masked = t.masked_bases(gc.seq_len(), bs);
+                for (i, (c, m)) in cts.iter_mut().zip(masked).enumerate() {
+                    if m > 0 {
+                        let l = bs.min(gc.seq_len() - i * bs);
+                        let f = ((l - m) as f64) / (l as f64);
+                        if f < MIN_OFF_TARGET_FRACTION {
+                            bins[i] = None
+                        } else {
+                            *c = ((*c as f64) / f).round() as usize
+                        }
+                    }
+                }
+                Cow::Owned(bins)
+            }
+            None => Cow::Borrowed(gc.data()),
+        };
+        gc_bins.insert(Arc::clone(ctg), bins);
+    }
+    Ok(gc_bins)
+}
+
+/// Get GC bins for each contig, masking bins covered by targets if present and missing bins
+/// (if set)
+fn level_gc_bins<'a>(
+    cfg: &Config,
+    gc_data: &'a GcData,
+    rc: &mut RawCounts,
+    missing: Option<&MissingBins>,
+) -> anyhow::Result<GcBins<'a>> {
+    let mut gc_bins: GcBins = match cfg.targets() {
+        Some(t) => mask_targets(t, gc_data, rc)?,
+        None => rc
+            .keys()
+            .map(|ctg| {
+                ctg_gc_data(gc_data, ctg).map(|gc| (Arc::clone(ctg), Cow::Borrowed(gc.data())))
+            })
+            .collect::<anyhow::Result<_>>()?,
+    };
+    if let Some(m) = missing {
+        for (ctg, bins) in gc_bins.iter_mut() {
+            if let Some(v) = m.get(ctg).filter(|v| v.contains(&true)) {
+                for (b, _) in bins.to_mut().iter_mut().zip(v.iter()).filter(|(_, m)| **m) {
+                    *b = None
+                }
+            }
+        }
+    }
+    Ok(gc_bins)
+}
+
+/// Training mask for a block size (if training regions are set)
+fn level_train_mask<'a>(cfg: &'a Config, gc_data: &GcData) -> Option<&'a TrainMask> {
+    cfg.training_regions()
+        .and_then(|t| t.mask(gc_data.block_size()))
+}
+
+/// Mask with all bins on the contigs used for normalization used for training
+fn all_bins_mask(cfg: &Config, gc_bins: &GcBins) -> TrainMask {
+    gc_bins
+        .iter()
+        .filter(|(ctg, _)| use_for_normalization(cfg, ctg))
+        .map(|(ctg, g)| (Arc::clone(ctg), vec![true; g.len()]))
+        .collect()
+}
+
+fn use_for_normalization(cfg: &Config, ctg: &str) -> bool {
+    cfg.ctg_hash()
+        .get(ctg)
+        .map(|c| c.use_for_normalization())
+        .unwrap_or(false)
+}
+
+/// GC bins, bins used for training and outliers in the raw counts for one block size
+struct LevelData<'a> {
+    gc_bins: GcBins<'a>,
+    train: Option<Cow<'a, TrainMask>>,
+    outliers: Option<RawOutliers>,
+}
+
+/// Get GC bins for each contig (masking bins covered by targets if present and missing bins
+/// if set) and the counts per GC bin (from the training regions if set).  If outlier detection
+/// is requested, outlier bins in the raw counts are either excluded from the training bins or
+/// winsorized
+fn level_bin_data<'a>(
+    cfg: &'a Config,
+    gc_data: &'a GcData,
+    rc: &mut RawCounts,
+    missing: Option<&MissingBins>,
+) -> anyhow::Result<(LevelData<'a>, Vec<Vec<f64>>)> {
+    let gc_bins = level_gc_bins(cfg, gc_data, rc, missing)?;
+    let outliers = cfg
+        .outlier_mode()
+        .map(|_| RawOutliers::find(cfg, &gc_bins, rc));
+    let mut train = level_train_mask(cfg, gc_data).map(Cow::Borrowed);
+    if let (Some(OutlierMode::Flag), Some(o)) = (cfg.outlier_mode(), outliers.as_ref()) {
+        let t = train
+            .get_or_insert_with(|| Cow::Owned(all_bins_mask(cfg, &gc_bins)))
+            .to_mut();
+        for (ctg, fl) in o.flags().iter() {
+            if let Some(m) = t.get_mut(ctg) {
+                for (m, _) in m.iter_mut().zip(fl.iter()).filter(|(_, f)| **f != 0) {
+                    *m = false
+                }
+            }
+        }
+    }
+    let bin_counts = collect_bin_data(cfg, &gc_bins, rc, train.as_deref(), |c| c as f64);
+    Ok((
+        LevelData {
+            gc_bins,
+            train,
+            outliers,
+        },
+        bin_counts,
+    ))
+}
+
+/// Exclude bins from the training mask where the running median (over REFIT_WINDOW training
+/// bins) of the normalized coverage differs from 2 by more than the refit threshold.  Only
+/// contigs used for normalization are considered.  Returns the number of bins excluded
+fn exclude_deviant_bins(
+    cfg: &Config,
+    gc_bins: &GcBins,
+    rc: &RawCounts,
+    pred: &[Option<f64>],
+    train: &mut TrainMask,
+) -> usize {
+    let h = REFIT_WINDOW >> 1;
+    let mut n_excluded = 0;
+    let mut w = Vec::with_capacity(REFIT_WINDOW);
+    for (ctg, mask) in train
+        .iter_mut()
+        .filter(|(ctg, _)| use_for_normalization(cfg, ctg))
+    {
+        let (cts, gc) = match (rc.get(ctg), gc_bins.get(ctg)) {
+            (Some(c), Some(g)) => (c, g),
+            _ => continue,
+        };
+        // Normalized coverage for training bins with a prediction
+        let v: Vec<(usize, f64)> = cts
+            .iter()
+            .zip(gc.iter())
+            .enumerate()
+            .filter(|(i, _)| mask[*i])
+            .filter_map(|(i, (c, g))| {
+                g.and_then(|ix| pred[ix as usize])
+                    .map(|p| (i, ((2 * c) as f64) / p))
+            })
+            .collect();
+        for j in 0..v.len() {
+            w.clear();
+            w.extend(
+                v[j.saturating_sub(h)..(j + h + 1).min(v.len())]
+                    .iter()
+                    .map(|(_, z)| *z),
+            );
+            w.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            if (w[w.len() >> 1] - 2.0).abs() > cfg.gc_refit_threshold() {
+                mask[v[j].0] = false;
+                n_excluded += 1
+            }
+        }
+    }
+    n_excluded
+}
+
+/// Get GC model for one block size.  If refit iterations are requested, bins deviating
+/// strongly from the expected normalized coverage are excluded from the training bins and the
+/// model is refitted.  This is repeated until no more bins are excluded or the maximum number
+/// of iterations is reached
+fn fit_level_curve(
+    cfg: &Config,
+    gc_data: &GcData,
+    ld: &LevelData,
+    rc: &RawCounts,
+    bin_counts: Vec<Vec<f64>>,
+    model: Option<&GcCurve>,
+) -> anyhow::Result<GcCurve> {
+    let mut curve = fit_curve(cfg, bin_counts, model, cfg.min_gc_pred())?;
+    if cfg.gc_refit_iterations() > 0 {
+        let gc_bins = &ld.gc_bins;
+        let mut train = ld
+            .train
+            .as_deref()
+            .cloned()
+            .unwrap_or_else(|| all_bins_mask(cfg, gc_bins));
+        for it in 0..cfg.gc_refit_iterations() {
+            let n = exclude_deviant_bins(cfg, gc_bins, rc, curve.pred(), &mut train);
+            debug!(
+                "GC refit iteration {} (block size {}): {} bins excluded",
+                it + 1,
+                gc_data.block_size(),
+                n
+            );
+            if n == 0 {
+                break;
+            }
+            let bin_counts = collect_bin_data(cfg, gc_bins, rc, Some(&train), |c| c as f64);
+            curve = fit_curve(cfg, bin_counts, model, cfg.min_gc_pred())?;
+        }
+    }
+    debug!(
+        "GC model for block size {} trained on {} bins",
+        gc_data.block_size(),
+        curve.n_training_bins()
+    );
+    Ok(curve)
+}
+
+/// Per read GC correction weights at the smallest block size: the weight for each GC bin and
+/// the covariate fit (if additional covariates are used) giving the adjustment for each block
+pub struct ReadWeights {
+    gc: Vec<Option<f64>>,
+    covariate_fit: Option<CovariateFit>,
+}
+
+impl ReadWeights {
+    /// Weight for block i of a contig, where gc has the GC bins and cov the covariates of the
+    /// contig.  As for the normalized coverage, the covariate adjustment is not applied if any
+    /// of the covariates are missing for the block.  x is used as workspace for the covariates
+    pub fn weight(
+        &self,
+        gc: &[Option<u32>],
+        cov: &[&[Option<f32>]],
+        i: usize,
+        x: &mut Vec<f64>,
+    ) -> Option<f64> {
+        let w = gc
+            .get(i)
+            .copied()
+            .flatten()
+            .and_then(|ix| self.gc[ix as usize])?;
+        let adj = self
+            .covariate_fit
+            .as_ref()
+            .filter(|_| bin_covariates(cov, i, x).is_some())
+            .map(|f| f.adjustment(x))
+            .unwrap_or(1.0);
+        Some(w / adj)
+    }
+}
+
+/// Per read GC correction weights for each GC bin at the smallest block size.  The weight
+/// for a GC bin is the mean count per block divided by the predicted count for the GC bin,
+/// so the weighted counts have (approximately) the same mean as the raw counts.  If additional
+/// covariates are used, the weight for a block is also divided by the covariate adjustment.
+/// If model is set, this is used (after rescaling) instead of fitting a model to the sample
+pub fn gc_read_weights(
+    cfg: &Config,
+    rc: &RawCounts,
+    model: Option<&SharedGcModel>,
+) -> anyhow::Result<ReadWeights> {
+    let gc_data = &cfg.gc_data()[0];
+    let mut rc = rc.clone();
+    let (ld, bin_counts) = level_bin_data(cfg, gc_data, &mut rc, None)?;
+    let gc_bins = &ld.gc_bins;
+    let curve = fit_level_curve(
+        cfg,
+        gc_data,
+        &ld,
+        &rc,
+        bin_counts,
+        model.map(|m| m.level(0)),
+    )?;
+    let pred = curve.pred();
+    let covariate_fit = fit_covariates(cfg, gc_data, gc_bins, &rc, pred);
+
+    // Mean count of blocks used for normalization with a prediction
+    let (n, tot) = cfg
+        .ctg_hash()
+        .values()
+        .filter(|c| c.use_for_normalization())
+        .filter_map(|c| rc.get(c.name()).zip(gc_bins.get(c.name())))
+        .flat_map(|(v, gc)| v.iter().zip(gc.iter()))
+        .filter(|(_, g)| g.and_then(|ix| pred[ix as usize]).is_some())
+        .fold((0, 0), |(n, tot), (c, _)| (n + 1, tot + *c));
+    let mean = if n > 0 {
+        (tot as f64) / (n as f64)
+    } else {
+        0.0
+    };
+    Ok(ReadWeights {
+        gc: pred.iter().map(|p| p.map(|p| mean / p)).collect(),
+        covariate_fit,
+    })
+}
+
+/// Normalize coverage data for one block size based on GC content.  Bins marked in missing
+/// (if set) are not used for the GC model and have no normalized coverage.  Returns the
+/// normalized coverage and the GC model
+fn normalize_level(
+    cfg: &Config,
+    gc_data: &GcData,
+    mut rc: RawCounts,
+    missing: Option<&MissingBins>,
+    model: Option<&GcCurve>,
+) -> anyhow::Result<(NormCov, GcCurve)> {
+    // Get GC bins for each contig, masking bins covered by targets if present,
+    // and collect counts per GC bin.  The model counts used for the GC fit and the normalized
+    // coverage are scaled for bases masked by targets and winsorized if requested; the raw
+    // counts are output unchanged
+    let mut mc = rc.clone();
+    let (ld, bin_counts) = level_bin_data(cfg, gc_data, &mut mc, missing)?;
+    let gc_bins = &ld.gc_bins;
 
     // Get predictions of coverage per GC bin
-    let pred = smooth(bin_counts);
+    let mut curve = fit_level_curve(cfg, gc_data, &ld, &mc, bin_counts, model)
+        .with_context(|| format!("Error normalizing block size {}", gc_data.block_size()))?;
+
+    // Regress residuals on additional covariates if requested
+    curve.covariate_fit = fit_covariates(cfg, gc_data, gc_bins, &mc, curve.pred());
+    let pred = curve.pred();
+    let cov_fit = curve.covariate_fit();
 
-    // Use the smoothed GC estimates to normalize coverage
+    // Use the smoothed GC estimates (adjusted for the covariates) to normalize coverage
     let mut nc = HashMap::with_capacity(rc.len());
-    for (ctg, mut raw_cov) in rc.drain() {
+    let mut x = Vec::new();
+    for (ctg, raw_cov) in rc.drain() {
+        let gc = &gc_bins[&ctg];
+        let cts = &mc[&ctg];
+        let cov = ctg_covariates(cfg, gc_data, &ctg);
         let mut norm_cov = Vec::with_capacity(raw_cov.len());
-        for (i, c) in raw_cov.drain(..).enumerate() {
-            let gc = cfg
-                .gc_data()
-                .ctg_data(&ctg)
-                .expect("Missing GC data for contig");
-
+        for (i, (c, m)) in raw_cov.into_iter().zip(cts.iter()).enumerate() {
             let corr_cov = gc
-                .gc(i)
+                .get(i)
+                .and_then(|x| *x)
                 .and_then(|ix| pred[ix as usize])
-                .map(|p| ((2 * c) as f64) / p);
-            norm_cov.push((c, corr_cov))
+                .map(|p| {
+                    let adj = cov_fit
+                        .filter(|_| bin_covariates(&cov, i, &mut x).is_some())
+                        .map(|f| f.adjustment(&x))
+                        .unwrap_or(1.0);
+                    ((2 * m) as f64) / (p * adj)
+                });
+            let flag = ld.outliers.as_ref().map(|o| o.flag(&ctg, i)).unwrap_or(0);
+            norm_cov.push((c, corr_cov, flag))
         }
         nc.insert(ctg, norm_cov);
     }
-    nc
+    if cfg.outlier_mode().is_some() && cfg.normalized_outliers() {
+        normalized_outliers(cfg, &mut nc)
+    }
+    Ok((nc, curve))
+}
+
+/// Normalize coverage data for a sample based on GC content
+/// This is done by getting the median coverage per GC bin from
+/// contigs (normally the autosomes).  The counts (collected at the
+/// smallest block size) are aggregated for each larger block size, and
+/// the normalization is performed separately for each block size.
+/// If model is set, the shared GC model is used (after rescaling) instead of fitting a model
+/// to the sample.  If missing is set, bins with missing data at the smallest block size (and the
+/// larger blocks containing them) are excluded.  Returns the normalized coverage and the GC
+/// model for each block size
+pub fn normalize_sample(
+    cfg: &Config,
+    rc: RawCounts,
+    missing: Option<&MissingBins>,
+    model: Option<&SharedGcModel>,
+) -> anyhow::Result<(Vec<NormCov>, Vec<GcCurve>)> {
+    let gc_data = cfg.gc_data();
+    let base = gc_data[0].block_size();
+    let mut v = Vec::with_capacity(gc_data.len());
+    for (level, gc) in gc_data.iter().enumerate().skip(1) {
+        let k = (gc.block_size() / base) as usize;
+        let m = missing.map(|m| aggregate_missing(m, k));
+        v.push(normalize_level(
+            cfg,
+            gc,
+            aggregate_counts(&rc, k),
+            m.as_ref(),
+            model.map(|m| m.level(level)),
+        )?)
+    }
+    v.insert(
+        0,
+        normalize_level(cfg, &gc_data[0], rc, missing, model.map(|m| m.level(0)))?,
+    );
+    Ok(v.into_iter().unzip())
+}
+
+/// Fit GC models (one per block size, plus one for the targets if present) pooled across
+/// samples.  The counts for each sample are (binned counts, target counts)
+pub fn fit_pooled_model(
+    cfg: &Config,
+    samples: &[(usize, RawCounts, RawCounts)],
+) -> anyhow::Result<SharedGcModel> {
+    let gc_data = cfg.gc_data();
+    let base = gc_data[0].block_size();
+    let mut levels = Vec::with_capacity(gc_data.len());
+    for gc in gc_data.iter() {
+        let k = (gc.block_size() / base) as usize;
+        let v = samples
+            .iter()
+            .map(|(_, rc, _)| {
+                let mut rc = if k > 1 {
+                    aggregate_counts(rc, k)
+                } else {
+                    rc.clone()
+                };
+                level_bin_data(cfg, gc, &mut rc, None).map(|(_, bc)| bc)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        levels.push(smooth(cfg, pool_bin_data(v), 0.0).with_context(|| {
+            format!(
+                "Error fitting pooled GC model for block size {}",
+                gc.block_size()
+            )
+        })?)
+    }
+    let targets = match cfg.targets() {
+        Some(t) => {
+            let v = samples
+                .iter()
+                .map(|(_, _, tc)| {
+                    target_bin_data(t, tc).map(|(gc_bins, mean_cov)| {
+                        collect_bin_data(cfg, &gc_bins, &mean_cov, None, |z| z)
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some(
+                smooth(cfg, pool_bin_data(v), 0.0)
+                    .with_context(|| "Error fitting pooled GC model for targets")?,
+            )
+        }
+        None => None,
+    };
+    Ok(SharedGcModel::new(levels, targets))
+}
+
+/// Type for mean coverage per target
+type TargetCov = HashMap<Arc<str>, Vec<f64>>;
+
+/// Get GC bins and mean coverage for each target
+fn target_bin_data(
+    targets: &Targets,
+    tc: &RawCounts,
+) -> anyhow::Result<(GcBins<'static>, TargetCov)> {
+    let mut gc_bins = HashMap::with_capacity(tc.len());
+    let mut mean_cov = HashMap::with_capacity(tc.len());
+    for (ctg, cts) in tc.iter() {
+        let t = targets
+            .ctg_targets(ctg)
+            .ok_or_else(|| anyhow!("Missing targets for contig {}", ctg))?;
+        gc_bins.insert(
+            Arc::clone(ctg),
+            Cow::Owned(t.targets().iter().map(|x| x.gc()).collect()),
+        );
+        mean_cov.insert(
+            Arc::clone(ctg),
+            cts.iter()
+                .zip(t.targets())
+                .map(|(c, x)| (*c as f64) / (x.len() as f64))
+                .collect::<Vec<f64>>(),
+        );
+    }
+    Ok((gc_bins, mean_cov))
+}
+
+/// Normalize coverage per target based on the GC content of the targets.  This is done
+/// in the same way as for the bins, but using the mean coverage per target (as the targets
+/// vary in size).  If model is set, this is used (after rescaling) instead of fitting a model
+/// to the sample.  Returns the raw counts and normalized coverage for each target, and the
+/// GC model
+pub fn normalize_targets(
+    cfg: &Config,
+    targets: &Targets,
+    mut tc: RawCounts,
+    model: Option<&GcCurve>,
+) -> anyhow::Result<(NormCov, GcCurve)> {
+    let (gc_bins, mean_cov) = target_bin_data(targets, &tc)?;
+
+    let bin_counts = collect_bin_data(cfg, &gc_bins, &mean_cov, None, |z| z);
+    let curve = fit_curve(cfg, bin_counts, model, MIN_TARGET_PRED)
+        .with_context(|| "Error normalizing target coverage")?;
+    let pred = curve.pred();
+
+    let mut nc = HashMap::with_capacity(tc.len());
+    for (ctg, mut cts) in tc.drain() {
+        let norm_cov = cts
+            .drain(..)
+            .zip(gc_bins[&ctg].iter().zip(mean_cov[&ctg].iter()))
+            .map(|(c, (g, z))| {
+                (
+                    c,
+                    g.and_then(|ix| pred[ix as usize]).map(|p| 2.0 * z / p),
+                    0,
+                )
+            })
+            .collect();
+        nc.insert(ctg, norm_cov);
+    }
+    Ok((nc, curve))
 }
@@ -0,0 +1,191 @@
+/// Bin level outlier detection
+///
+/// Single bins with extreme coverage (e.g., from satellite repeats) add noise to the GC model
+/// and to the downstream statistics.  A bin is an outlier if it differs from the running median
+/// of the surrounding bins on the same contig by more than k times the running scale estimate
+/// (1.4826 x the median absolute deviation).  Outliers can either be flagged (and excluded from
+/// training the GC model) or winsorized (clipped to the running median +/- k x scale).  Detection
+/// is performed on the raw counts before the GC fit, and optionally on the normalized coverage.
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use crate::{
+    config::Config,
+    coverage::{NormCov, RawCounts},
+    normalize::GcBins,
+};
+
+/// Flag for bins that are outliers in the raw counts
+pub const RAW_OUTLIER: u8 = 1;
+
+/// Flag for bins that are outliers in the normalized coverage
+pub const NORM_OUTLIER: u8 = 2;
+
+/// Scale factor to convert MAD to a standard deviation for normal data
+const MAD_SCALE: f64 = 1.4826;
+
+/// Outlier flags per bin for each contig
+pub type OutlierFlags = HashMap<Arc<str>, Vec<u8>>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutlierMode {
+    Flag,
+    Winsorize,
+}
+
+impl FromStr for OutlierMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "flag" => Ok(Self::Flag),
+            "winsorize" | "winsorise" => Ok(Self::Winsorize),
+            _ => Err(format!("Unknown outlier mode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutlierMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Flag => "flag",
+            Self::Winsorize => "winsorize",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Median of a slice (which is sorted in place).  The slice must not be empty
+fn median(v: &mut [f64]) -> f64 {
+    v.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    v[v.len() >> 1]
+}
+
+/// Find outliers in a sequence of (bin index, value) pairs.  For each value, the median and scale
+/// are calculated over the window of values centred on it.  The scale is not allowed to go below
+/// min_scale(median).  Returns the bin index and the value clipped to the limits for each outlier
+fn find_outliers<F: Fn(f64) -> f64>(
+    v: &[(usize, f64)],
+    window: usize,
+    k: f64,
+    min_scale: F,
+) -> Vec<(usize, f64)> {
+    let h = window >> 1;
+    let mut w = Vec::with_capacity(window);
+    let mut d = Vec::with_capacity(window);
+    let mut out = Vec::new();
+    for j in 0..v.len() {
+        let r = &v[j.saturating_sub(h)..(j + h + 1).min(v.len())];
+        w.clear();
+        w.extend(r.iter().map(|(_, z)| *z));
+        let m = median(&mut w);
+        d.clear();
+        d.extend(r.iter().map(|(_, z)| (z - m).abs()));
+        let s = (MAD_SCALE * median(&mut d)).max(min_scale(m));
+        let (i, z) = v[j];
+        let (lo, hi) = (m - k * s, m + k * s);
+        if z < lo || z > hi {
+            out.push((i, z.clamp(lo, hi)))
+        }
+    }
+    out
+}
+
+/// Outliers in the raw counts for one block size: the flags for each bin
+pub struct RawOutliers {
+    flags: OutlierFlags,
+}
+
+impl RawOutliers {
+    /// Find outliers in the raw counts (for bins with a GC bin), winsorizing the counts
+    /// in place if requested.  The counts passed are the model counts, so the raw counts
+    /// output are not changed by winsorizing
+    pub fn find(cfg: &Config, gc_bins: &GcBins, rc: &mut RawCounts) -> Self {
+        let winsorize = cfg.outlier_mode() == Some(OutlierMode::Winsorize);
+        let mut flags = HashMap::with_capacity(rc.len());
+        let mut n = 0;
+        for (ctg, cts) in rc.iter_mut() {
+            let mut fl = vec![0; cts.len()];
+            if let Some(gc) = gc_bins.get(ctg) {
+                let v: Vec<(usize, f64)> = cts
+                    .iter()
+                    .zip(gc.iter())
+                    .enumerate()
+                    .filter(|(_, (_, g))| g.is_some())
+                    .map(|(i, (c, _))| (i, *c as f64))
+                    .collect();
+                // The scale is not allowed to go below the Poisson SD
+                let out = find_outliers(&v, cfg.outlier_window(), cfg.outlier_k(), |m| {
+                    m.sqrt().max(1.0)
+                });
+                n += out.len();
+                for (i, z) in out {
+                    fl[i] |= RAW_OUTLIER;
+                    if winsorize {
+                        cts[i] = z.round() as usize
+                    }
+                }
+            }
+            flags.insert(Arc::clone(ctg), fl);
+        }
+        debug!("{} outlier bins found in raw counts", n);
+        Self { flags }
+    }
+
+    pub fn flags(&self) -> &OutlierFlags {
+        &self.flags
+    }
+
+    /// Flag for bin i of contig ctg
+    pub fn flag(&self, ctg: &str, i: usize) -> u8 {
+        self.flags
+            .get(ctg)
+            .and_then(|v| v.get(i))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Median number of raw counts per unit of normalized coverage across all bins with
+/// counts and normalized coverage.  Returns None if there are no such bins
+fn counts_per_unit(nc: &NormCov) -> Option<f64> {
+    let mut v: Vec<f64> = nc
+        .values()
+        .flat_map(|cov| cov.iter())
+        .filter_map(|(c, z, _)| z.filter(|z| *z > 0.0 && *c > 0).map(|z| (*c as f64) / z))
+        .collect();
+    if v.is_empty() {
+        None
+    } else {
+        Some(median(&mut v))
+    }
+}
+
+/// Find outliers in the normalized coverage, setting the flags and winsorizing the coverage if requested
+pub fn normalized_outliers(cfg: &Config, nc: &mut NormCov) {
+    let winsorize = cfg.outlier_mode() == Some(OutlierMode::Winsorize);
+    // As for the raw counts, the scale is not allowed to go below the Poisson SD, converted
+    // to the normalized scale using the counts per unit of normalized coverage
+    let k = counts_per_unit(nc);
+    let min_scale = |m: f64| match k {
+        Some(k) => (m.max(0.0) * k).sqrt().max(1.0) / k,
+        None => 0.0,
+    };
+    let mut n = 0;
+    for cov in nc.values_mut() {
+        let v: Vec<(usize, f64)> = cov
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, z, _))| z.map(|z| (i, z)))
+            .collect();
+        let out = find_outliers(&v, cfg.outlier_window(), cfg.outlier_k(), min_scale);
+        n += out.len();
+        for (i, z) in out {
+            let x = &mut cov[i];
+            x.2 |= NORM_OUTLIER;
+            if winsorize {
+                x.1 = Some(z)
+            }
+        }
+    }
+    debug!("{} outlier bins found in normalized coverage", n);
+}
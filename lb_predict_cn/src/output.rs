@@ -2,19 +2,36 @@ use anyhow::Context;
 use std::{
     fs,
     io::{BufWriter, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::{config::Config, coverage::Coverage};
+use crate::{
+    bin_reads::{BinReads, BIN_READS_HEADER},
+    config::Config,
+    coverage::{Coverage, NormCov},
+    gc::GcParams,
+    gc_model::SharedGcModel,
+    normalize::GcCurve,
+    qc::{write_frag_lens, write_non_host_table, write_qc_table, CoverageQc, ReadStats},
+};
+
+/// Column names for the header line of the coverage files (written if extra columns are present)
+const COVERAGE_HEADER: [&str; 5] = [
+    "contig",
+    "position",
+    "copy_number",
+    "raw_coverage",
+    "outlier_flags",
+];
 
-fn get_file_path(cfg: &Config, sample_idx: usize, ctg: &str) -> PathBuf {
+fn get_file_path(cfg: &Config, sample_idx: usize, level: usize, ctg: &str) -> PathBuf {
     let mut p = if let Some(d) = cfg.output_dir() {
         d.to_owned()
     } else {
         PathBuf::new()
     };
     p.push(cfg.sample_list()[sample_idx].name());
-    let name = format!("{}_{}.txt", cfg.output_prefix(), ctg);
+    let name = format!("{}_{}.txt", cfg.level_prefix(level), ctg);
     p.push(&name);
     p
 }
@@ -43,20 +60,241 @@ pub fn setup_output(cfg: &Config) -> anyhow::Result<()> {
 pub fn output_sample_cfg(
     cfg: &Config,
     sample_idx: usize,
+    level: usize,
     ctg: &str,
     mut cov: Coverage,
+    bin_reads: Option<&[BinReads]>,
 ) -> anyhow::Result<()> {
-    let opath = get_file_path(cfg, sample_idx, ctg);
+    let opath = get_file_path(cfg, sample_idx, level, ctg);
     let mut wrt = BufWriter::new(
         fs::File::create(&opath)
             .with_context(|| format!("problem creating output file {}", opath.display()))?,
     );
-    let bs = cfg.block_size() as f64;
-    for (i, (rc, norm)) in cov.drain(..).enumerate() {
+    let bs = cfg.gc_data()[level].block_size() as f64;
+    // If outlier detection or per bin read statistics are requested, extra columns are written
+    // and the file starts with a header line.  The outlier flags are always in the fifth column
+    // (NA if outlier detection is not requested) with the read statistics (if requested) after
+    let flags = cfg.outlier_mode().is_some();
+    let read_stats = cfg.bin_read_stats();
+    if flags || read_stats {
+        write!(wrt, "{}", COVERAGE_HEADER.join("\t"))?;
+        if read_stats {
+            write!(wrt, "\t{}", BIN_READS_HEADER.join("\t"))?
+        }
+        writeln!(wrt)?
+    }
+    for (i, (rc, norm, flag)) in cov.drain(..).enumerate() {
         if let Some(z) = norm {
             let x = (((i as f64) + 0.5) * bs).round() as usize;
-            writeln!(wrt, "{}\t{}\t{:.4}\t{:.4}", ctg, x, z, (rc as f64) / bs)?
+            write!(wrt, "{}\t{}\t{:.4}\t{:.4}", ctg, x, z, (rc as f64) / bs)?;
+            if flags {
+                write!(wrt, "\t{}", flag)?
+            } else if read_stats {
+                write!(wrt, "\tNA")?
+            }
+            if read_stats {
+                bin_reads
+                    .and_then(|v| v.get(i))
+                    .copied()
+                    .unwrap_or_default()
+                    .write(&mut wrt)?
+            }
+            writeln!(wrt)?
         }
     }
     Ok(())
 }
+
+/// Output normalized coverage per target for a sample.  All contigs are written to a single
+/// file (sorted by contig name) with the target coordinates (as in the BED file), the target
+/// name (if present), the normalized coverage and the mean raw coverage
+pub fn output_sample_targets(
+    cfg: &Config,
+    sample_idx: usize,
+    mut cov: NormCov,
+) -> anyhow::Result<()> {
+    let targets = cfg.targets().expect("Missing targets");
+    let mut opath = if let Some(d) = cfg.output_dir() {
+        d.to_owned()
+    } else {
+        PathBuf::new()
+    };
+    opath.push(cfg.sample_list()[sample_idx].name());
+    opath.push(format!("{}_targets.txt", cfg.output_prefix()));
+    let mut wrt = BufWriter::new(
+        fs::File::create(&opath)
+            .with_context(|| format!("problem creating output file {}", opath.display()))?,
+    );
+    let mut ctgs: Vec<_> = cov.keys().cloned().collect();
+    ctgs.sort_unstable();
+    for ctg in ctgs {
+        let v = cov.remove(&ctg).unwrap();
+        let ct = targets
+            .ctg_targets(&ctg)
+            .expect("Missing targets for contig");
+        for (t, (rc, norm, _)) in ct.targets().iter().zip(v) {
+            if let Some(z) = norm {
+                writeln!(
+                    wrt,
+                    "{}\t{}\t{}\t{}\t{:.4}\t{:.4}",
+                    ctg,
+                    t.start(),
+                    t.end(),
+                    t.name().unwrap_or("."),
+                    z,
+                    (rc as f64) / (t.len() as f64)
+                )?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_gc_curve(
+    opath: &Path,
+    curve: &GcCurve,
+    sample: &str,
+    block_size: Option<u32>,
+    params: &GcParams,
+) -> anyhow::Result<()> {
+    let mut wrt = BufWriter::new(
+        fs::File::create(opath)
+            .with_context(|| format!("problem creating output file {}", opath.display()))?,
+    );
+    curve
+        .write(&mut wrt, sample, block_size, params)
+        .with_context(|| format!("Error writing to {}", opath.display()))
+}
+
+/// Output the GC models for a sample: one file per block size, plus one for the
+/// targets if present.  The files are written next to the coverage files, along with
+/// the fitted covariate coefficients if a covariate regression was performed
+pub fn output_gc_curves(
+    cfg: &Config,
+    sample_idx: usize,
+    curves: &[GcCurve],
+    target_curve: Option<&GcCurve>,
+) -> anyhow::Result<()> {
+    let name = cfg.sample_list()[sample_idx].name();
+    let mut dir = if let Some(d) = cfg.output_dir() {
+        d.to_owned()
+    } else {
+        PathBuf::new()
+    };
+    dir.push(name);
+    for (level, curve) in curves.iter().enumerate() {
+        let opath = dir.join(format!("{}_gc.txt", cfg.level_prefix(level)));
+        let bs = cfg.gc_data()[level].block_size();
+        write_gc_curve(&opath, curve, name, Some(bs), cfg.gc_data()[level].params())?;
+        if let Some(f) = curve.covariate_fit() {
+            let opath = dir.join(format!("{}_covariates.txt", cfg.level_prefix(level)));
+            let mut wrt =
+                BufWriter::new(fs::File::create(&opath).with_context(|| {
+                    format!("problem creating output file {}", opath.display())
+                })?);
+            f.write(&mut wrt, name, bs)
+                .with_context(|| format!("Error writing to {}", opath.display()))?
+        }
+    }
+    if let Some(curve) = target_curve {
+        let opath = dir.join(format!("{}_targets_gc.txt", cfg.output_prefix()));
+        write_gc_curve(&opath, curve, name, None, cfg.gc_data()[0].params())?
+    }
+    Ok(())
+}
+
+/// Output the QC table for a sample, calculating the coverage metrics for each block size
+/// from the normalized coverage.  The table is written next to the coverage files
+pub fn output_sample_qc(
+    cfg: &Config,
+    sample_idx: usize,
+    read_stats: Option<&ReadStats>,
+    nc: &[NormCov],
+) -> anyhow::Result<()> {
+    let name = cfg.sample_list()[sample_idx].name();
+    let cov_qc: Vec<_> = nc
+        .iter()
+        .zip(cfg.gc_data())
+        .map(|(h, gc)| (gc.block_size(), CoverageQc::new(cfg, gc.block_size(), h)))
+        .collect();
+    let mut opath = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    opath.push(name);
+    opath.push(format!("{}_qc.txt", cfg.output_prefix()));
+    let mut wrt = BufWriter::new(
+        fs::File::create(&opath)
+            .with_context(|| format!("problem creating output file {}", opath.display()))?,
+    );
+    write_qc_table(&mut wrt, cfg, name, read_stats, &cov_qc)
+        .with_context(|| format!("Error writing to {}", opath.display()))?;
+
+    let Some(stats) = read_stats else {
+        return Ok(());
+    };
+
+    // Fragment length distribution for the mitochondrial contig
+    if let Some(f) = cfg
+        .mito_contig()
+        .and_then(|m| stats.ctg_fragments(m))
+        .filter(|f| !f.lens().is_empty())
+    {
+        opath.set_file_name(format!("{}_mt_frag_len.txt", cfg.output_prefix()));
+        let mut wrt = BufWriter::new(
+            fs::File::create(&opath)
+                .with_context(|| format!("problem creating output file {}", opath.display()))?,
+        );
+        writeln!(wrt, "length\tcount")
+            .and_then(|_| write_frag_lens(&mut wrt, None, f))
+            .with_context(|| format!("Error writing to {}", opath.display()))?
+    }
+
+    // Fragment counts and length distributions for the non-host contigs
+    let mut non_host: Vec<_> = cfg
+        .ctg_hash()
+        .values()
+        .filter(|c| c.non_host())
+        .map(|c| c.name())
+        .collect();
+    if !non_host.is_empty() {
+        non_host.sort_unstable();
+        opath.set_file_name(format!("{}_non_host.txt", cfg.output_prefix()));
+        let mut wrt = BufWriter::new(
+            fs::File::create(&opath)
+                .with_context(|| format!("problem creating output file {}", opath.display()))?,
+        );
+        write_non_host_table(&mut wrt, cfg, &non_host, stats)
+            .with_context(|| format!("Error writing to {}", opath.display()))?;
+
+        opath.set_file_name(format!("{}_non_host_frag_len.txt", cfg.output_prefix()));
+        let mut wrt = BufWriter::new(
+            fs::File::create(&opath)
+                .with_context(|| format!("problem creating output file {}", opath.display()))?,
+        );
+        writeln!(wrt, "contig\tlength\tcount")
+            .and_then(|_| {
+                for ctg in non_host.iter() {
+                    if let Some(f) = stats.ctg_fragments(ctg) {
+                        write_frag_lens(&mut wrt, Some(ctg), f)?
+                    }
+                }
+                Ok(())
+            })
+            .with_context(|| format!("Error writing to {}", opath.display()))?
+    }
+    Ok(())
+}
+
+/// Output the pooled GC models.  These are written to the top level output directory, and
+/// can be used as shared GC models for later runs
+pub fn output_pooled_model(cfg: &Config, model: &SharedGcModel) -> anyhow::Result<()> {
+    let dir = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    for (level, curve) in model.levels().iter().enumerate() {
+        let opath = dir.join(format!("{}_pooled_gc.txt", cfg.level_prefix(level)));
+        let gc = &cfg.gc_data()[level];
+        write_gc_curve(&opath, curve, "pooled", Some(gc.block_size()), gc.params())?
+    }
+    if let Some(curve) = model.targets() {
+        let opath = dir.join(format!("{}_targets_pooled_gc.txt", cfg.output_prefix()));
+        write_gc_curve(&opath, curve, "pooled", None, cfg.gc_data()[0].params())?
+    }
+    Ok(())
+}
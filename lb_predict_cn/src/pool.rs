@@ -0,0 +1,62 @@
+/// Simple worker pool for running independent tasks in parallel
+///
+/// The items are sent over a channel to up to nt scoped worker threads, which apply the task
+/// to each item received and return the results tagged with the index of the item.  The
+/// results are then collected in the same order as the items.
+use std::thread;
+
+use crossbeam_channel::{unbounded, Receiver};
+
+fn worker<T, R, F: Fn(T) -> R>(f: &F, r: Receiver<(usize, T)>) -> Vec<(usize, R)> {
+    let mut v = Vec::new();
+    while let Ok((ix, x)) = r.recv() {
+        v.push((ix, f(x)))
+    }
+    v
+}
+
+/// Apply f to each item using up to nt threads, returning the results in the order of the
+/// items.  name is used to identify the threads in error messages
+pub fn pool_map<T, R, F>(nt: usize, items: Vec<T>, name: &str, f: F) -> anyhow::Result<Vec<R>>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let n = items.len();
+    let nt = nt.min(n).max(1);
+    let f = &f;
+    let mut res = Vec::with_capacity(nt);
+    thread::scope(|sc| {
+        let (snd, rcv) = unbounded();
+        let jobs: Vec<_> = (0..nt)
+            .map(|_| {
+                let r = rcv.clone();
+                sc.spawn(move || worker(f, r))
+            })
+            .collect();
+        drop(rcv);
+        for x in items.into_iter().enumerate() {
+            if snd.send(x).is_err() {
+                break;
+            }
+        }
+        drop(snd);
+        for jh in jobs {
+            res.push(jh.join())
+        }
+    });
+
+    let mut v: Vec<Option<R>> = (0..n).map(|_| None).collect();
+    for (i, x) in res.into_iter().enumerate() {
+        match x {
+            Ok(y) => {
+                for (ix, z) in y {
+                    v[ix] = Some(z)
+                }
+            }
+            Err(_) => return Err(anyhow!("Error joining {} thread {}", name, i + 1)),
+        }
+    }
+    Ok(v.into_iter().map(|z| z.expect("Missing result")).collect())
+}
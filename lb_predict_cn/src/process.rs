@@ -5,17 +5,61 @@ use crossbeam_channel::{bounded, Receiver, Sender};
 use r_htslib::*;
 
 use crate::{
-    config::Config, controller::*, input::open_input, normalize::normalize_sample, output::*,
+    bin_reads::bin_reads_per_level,
+    config::Config,
+    controller::*,
+    coverage::{NormCov, RawCounts},
+    downsample::{get_sample_depths, report_downsampling, SampleDepth},
+    frag_gc::{get_gc_models, FragGc},
+    gc_model::SharedGcModel,
+    input::open_input,
+    normalize::{fit_pooled_model, gc_read_weights, normalize_sample, normalize_targets},
+    output::*,
     reader::read_coverage_data,
+    subsample::ReadSampler,
+    tag::write_tagged_input,
+    validate::validate_inputs,
 };
 
+/// GC normalization of a sample, writing the GC models (plus the tagged copy of the
+/// input file if requested).  If a shared GC model (pooled or from the command line) is
+/// given then this is used instead of fitting a model to the sample.
+/// Errors are returned to the controller so that the remaining samples can be processed
+fn normalize(
+    cfg: &Config,
+    i: usize,
+    rc: RawCounts,
+    tc: RawCounts,
+    model: Option<&SharedGcModel>,
+    frag_gc: Option<&FragGc>,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<(Vec<NormCov>, Option<NormCov>)> {
+    if cfg.gc_tag().is_some() {
+        let w = gc_read_weights(cfg, &rc, model)?;
+        write_tagged_input(cfg, i, &w, frag_gc, tpool)?
+    }
+    let (t, tcurve) = match cfg.targets() {
+        Some(t) => {
+            let (nc, curve) = normalize_targets(cfg, t, tc, model.and_then(|m| m.targets()))?;
+            (Some(nc), Some(curve))
+        }
+        None => (None, None),
+    };
+    let (h, curves) = normalize_sample(cfg, rc, None, model)?;
+    output_gc_curves(cfg, i, &curves, tcurve.as_ref())?;
+    Ok((h, t))
+}
+
 fn process_task(
     cfg: &Config,
     ix: usize,
     tpool: Option<&HtsThreadPool>,
+    depths: Option<&[SampleDepth]>,
+    frag_gc: Option<&FragGc>,
     snd: Sender<JobRequest>,
     recv: Receiver<Option<Job>>,
 ) -> anyhow::Result<()> {
+    let sampler = ReadSampler::new(cfg.seed());
     debug!("Process task {} starting up", ix);
     let mut sample_idx = None;
     let mut hts = None;
@@ -45,20 +89,70 @@ fn process_task(
                     ctg,
                     cfg.sample_list()[sample_idx.unwrap()].name()
                 );
-                let h = read_coverage_data(cfg, hts.as_mut().unwrap(), ctg.as_ref())?;
-                Completed::RawCounts(i, h)
+                // Sampling fraction if sample is being downsampled
+                let sampling = depths.and_then(|d| d[i].fraction()).map(|p| (&sampler, p));
+                // Fragment GC weights if fragment GC correction is being performed
+                let mut weighter = frag_gc.map(|f| f.weighter(i));
+                let (h, t, st) = read_coverage_data(
+                    cfg,
+                    hts.as_mut().unwrap(),
+                    ctg.as_ref(),
+                    sampling,
+                    weighter.as_mut(),
+                )?;
+                Completed::RawCounts(i, h, t, st)
+            }
+            JobType::FitPooledModel(v) => {
+                info!("Fitting pooled GC model for {} samples", v.len());
+                match fit_pooled_model(cfg, &v)
+                    .and_then(|m| output_pooled_model(cfg, &m).map(|_| m))
+                {
+                    Ok(m) => Completed::PooledModel(m, v),
+                    Err(e) => {
+                        error!("Fitting pooled GC model failed: {:#}", e);
+                        Completed::Failed(
+                            v.iter().map(|(i, _, _)| *i).collect(),
+                            format!("{:#}", e),
+                        )
+                    }
+                }
             }
-            JobType::NormalizeSample(rc) => {
+            JobType::NormalizeSample(rc, tc, mut st, pooled) => {
                 debug!(
                     "Task {} normalizing sample {}",
                     ix,
                     cfg.sample_list()[i].name()
                 );
-                let h = normalize_sample(cfg, rc);
-                Completed::NormalizedCounts(i, h)
+                let model = pooled.as_deref().or_else(|| cfg.gc_model());
+                // Errors from the downsampling report, normalization or the QC output fail
+                // this sample only
+                let res = depths
+                    .map_or(Ok(()), |d| report_downsampling(cfg, i, &d[i], &rc, &st))
+                    .and_then(|_| normalize(cfg, i, rc, tc, model, frag_gc, tpool))
+                    .and_then(|(h, t)| output_sample_qc(cfg, i, Some(&st), &h).map(|_| (h, t)));
+                match res {
+                    Ok((h, t)) => {
+                        let br = cfg
+                            .bin_read_stats()
+                            .then(|| bin_reads_per_level(cfg, &st.take_bin_reads()));
+                        Completed::NormalizedCounts(i, h, t, br)
+                    }
+                    Err(e) => {
+                        error!(
+                            "Processing failed for sample {}: {:#}",
+                            cfg.sample_list()[i].name(),
+                            e
+                        );
+                        Completed::Failed(vec![i], format!("{:#}", e))
+                    }
+                }
+            }
+            JobType::OutputSampleCtg(sample_idx, level, ctg, cov, br) => {
+                output_sample_cfg(cfg, sample_idx, level, &ctg, cov, br.as_deref())?;
+                Completed::None
             }
-            JobType::OutputSampleCtg(sample_idx, ctg, cov) => {
-                output_sample_cfg(cfg, sample_idx, &ctg, cov)?;
+            JobType::OutputSampleTargets(sample_idx, cov) => {
+                output_sample_targets(cfg, sample_idx, cov)?;
                 Completed::None
             }
             JobType::Wait => {
@@ -79,6 +173,11 @@ fn process_task(
 
 /// Create child threads to process samples
 pub fn process_samples(cfg: &Config) -> anyhow::Result<()> {
+    // Check input files before starting
+    if cfg.validate() {
+        validate_inputs(cfg)?
+    }
+
     // Set up Hts thread pool
     debug!(
         "Setting up hts thread pool with {} threads",
@@ -90,6 +189,21 @@ pub fn process_samples(cfg: &Config) -> anyhow::Result<()> {
     // Set up output
     setup_output(cfg)?;
 
+    // Get sampling fractions if downsampling
+    let depths = match cfg.downsample() {
+        Some(target) => Some(get_sample_depths(cfg, target, tpool_ref)?),
+        None => None,
+    };
+    let depths_ref = depths.as_deref();
+
+    // Get fragment GC models if required
+    let frag_gc = if cfg.fragment_gc() {
+        Some(get_gc_models(cfg, tpool_ref)?)
+    } else {
+        None
+    };
+    let frag_gc_ref = frag_gc.as_ref();
+
     let mut res = Vec::new();
     thread::scope(|sc| {
         let nt = cfg.n_tasks();
@@ -106,7 +220,9 @@ pub fn process_samples(cfg: &Config) -> anyhow::Result<()> {
                 let (s, r) = bounded(1);
                 send_job.push(s);
                 let s = send_ctrl.clone();
-                sc.spawn(move || process_task(cfg, ix + 1, tpool_ref, s, r))
+                sc.spawn(move || {
+                    process_task(cfg, ix + 1, tpool_ref, depths_ref, frag_gc_ref, s, r)
+                })
             })
             .collect();
 
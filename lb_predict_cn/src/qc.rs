@@ -0,0 +1,656 @@
+/// Sample level QC metrics
+///
+/// Read level counters (reads seen, reads removed by each of the read filters and bases removed
+/// by the base quality filter) are collected while the input files are read.  The read counters
+/// and the duplicate spectrum cover all reads on contigs from the contig list, before any
+/// downsampling; the base counts only cover the reads kept.  Coverage metrics
+/// are calculated for each block size from the normalized coverage, using the bins with a
+/// normalized value on the contigs used for normalization:
+///
+///   mean and median raw coverage (count / block size)
+///   fraction of bins (over all contigs) without a normalized value
+///   median absolute deviation (MAD) of the normalized copy number
+///   derivative log ratio spread (DLRS): the robust SD of the differences in log2 copy number
+///   between adjacent bins, divided by sqrt(2)
+///   Gini coefficient of the raw coverage, and the fraction of bins within 20% of the median
+///
+/// If a mitochondrial contig is present, the mean raw coverage of the contig and the number of
+/// mtDNA copies per diploid genome are also calculated.  The copy number is taken from the
+/// normalized coverage of the mitochondrial bins, so the GC content of each bin is handled by
+/// the GC model of the sample in the same way as for the other contigs.  Partial bins (at the
+/// end of the contig) are scaled by the fraction of the bin covered.  The fragment length
+/// distribution of read pairs on the mitochondrial contig is collected while reading.
+///
+/// The number of fragments passing the filters is also collected for each contig.  For contigs
+/// marked as non-host in the contig file (i.e., viral genomes), the fragment counts per million
+/// host fragments and the fragment length distributions are reported.
+///
+/// The library complexity is estimated from the duplicate spectrum (the number of distinct
+/// fragment positions seen once, twice etc.).  The expected number of distinct fragments
+/// at higher sequencing depth is extrapolated from the Good-Toulmin power series using
+/// a continued fraction (rational function) approximation as in Preseq (Daley & Smith, 2013).
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    mem,
+    sync::Arc,
+};
+
+use utils::robust_sd;
+
+use crate::{
+    bin_reads::{BinReadCounts, BinReads},
+    config::Config,
+    coverage::NormCov,
+};
+
+/// Reason for a read being removed by the read filters
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterReason {
+    Flags,
+    Mapq,
+    Duplicate,
+    TemplateLen,
+}
+
+/// Maximum number of terms of the Good-Toulmin series used for the complexity extrapolation
+const MAX_SERIES_TERMS: usize = 100;
+
+/// Number of steps used when checking the extrapolated complexity curve
+const CURVE_STEPS: usize = 100;
+
+/// Tolerance (as a fraction of the observed distinct fragments) for small decreases or
+/// increases in slope of the extrapolated complexity curve
+const CURVE_TOLERANCE: f64 = 0.005;
+
+/// Depths (relative to the observed depth) for which the complexity is extrapolated
+pub const COMPLEXITY_FOLDS: [f64; 3] = [2.0, 5.0, 10.0];
+
+/// Duplicate spectrum: the number of distinct fragment positions seen j times.  Fragments
+/// are identified by their position, the mate position (for paired reads) and the strand.
+/// The input must be sorted on genomic position.
+#[derive(Debug, Default, Clone)]
+pub struct DupSpectrum {
+    current: Option<(usize, usize)>,
+    groups: HashMap<(Option<usize>, bool), usize>,
+    hist: Vec<usize>,
+}
+
+impl DupSpectrum {
+    fn add(&mut self, tid: usize, pos: usize, mpos: Option<usize>, reverse: bool) {
+        if self.current != Some((tid, pos)) {
+            self.flush();
+            self.current = Some((tid, pos))
+        }
+        *self.groups.entry((mpos, reverse)).or_insert(0) += 1
+    }
+
+    /// Add the fragments at the current position to the spectrum
+    fn flush(&mut self) {
+        for (_, n) in self.groups.drain() {
+            if self.hist.len() < n {
+                self.hist.resize(n, 0)
+            }
+            self.hist[n - 1] += 1
+        }
+        self.current = None
+    }
+
+    fn combine(&mut self, other: &Self) {
+        if self.hist.len() < other.hist.len() {
+            self.hist.resize(other.hist.len(), 0)
+        }
+        for (x, y) in self.hist.iter_mut().zip(other.hist.iter()) {
+            *x += *y
+        }
+    }
+
+    /// Number of distinct fragments
+    pub fn distinct(&self) -> usize {
+        self.hist.iter().sum()
+    }
+
+    /// Expected number of distinct fragments at each of the given depths (relative to the
+    /// observed depth).  Returns None if there are too few duplicates or if no stable
+    /// approximation can be found
+    pub fn extrapolate(&self, folds: &[f64]) -> Option<Vec<f64>> {
+        // The Good-Toulmin series for the number of new fragments seen when sequencing an extra
+        // fraction t is sum_j (-1)^(j+1) n_j t^j.  The series is truncated at the first zero count
+        let c: Vec<f64> = self
+            .hist
+            .iter()
+            .take(MAX_SERIES_TERMS)
+            .take_while(|n| **n > 0)
+            .enumerate()
+            .map(|(k, n)| {
+                if (k & 1) == 0 {
+                    *n as f64
+                } else {
+                    -(*n as f64)
+                }
+            })
+            .collect();
+        let a = qd_coefficients(&c);
+        let t_max = folds.iter().fold(1.0f64, |m, x| m.max(*x)) - 1.0;
+        let n = self.distinct() as f64;
+
+        // Use the largest number of terms giving a curve that is (within tolerance) increasing
+        // and concave.  The folds should be in increasing order
+        (1..=a.len()).rev().find_map(|d| {
+            let f = |t: f64| t * c[0] / eval_cf(&a[..d], t);
+            let tol = CURVE_TOLERANCE * n;
+            let mut prev = (0.0, f64::INFINITY);
+            for i in 1..=CURVE_STEPS {
+                let y = f(t_max * (i as f64) / (CURVE_STEPS as f64));
+                let dy = y - prev.0;
+                if !y.is_finite() || dy < -tol || dy > prev.1 + tol {
+                    return None;
+                }
+                prev = (y, dy)
+            }
+            Some(
+                folds
+                    .iter()
+                    .scan(n, |m, x| {
+                        *m = m.max(n + f(x - 1.0));
+                        Some(*m)
+                    })
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Coefficients of the continued fraction c_0 / (1 - a_1 t / (1 - a_2 t / (1 - ...))) corresponding
+/// to the power series sum_k c_k t^k, calculated using the quotient-difference algorithm.  The
+/// coefficients are returned up to the first non-finite value
+fn qd_coefficients(c: &[f64]) -> Vec<f64> {
+    let mut a = Vec::new();
+    if c.len() < 2 {
+        return a;
+    }
+    let mut q: Vec<f64> = c.windows(2).map(|w| w[1] / w[0]).collect();
+    let mut e = vec![0.0; c.len()];
+    while !q.is_empty() {
+        a.push(q[0]);
+        let e1: Vec<f64> = (0..q.len() - 1)
+            .map(|k| q[k + 1] - q[k] + e[k + 1])
+            .collect();
+        if e1.is_empty() {
+            break;
+        }
+        a.push(e1[0]);
+        q = (0..e1.len() - 1)
+            .map(|k| q[k + 1] * e1[k + 1] / e1[k])
+            .collect();
+        e = e1;
+    }
+    let n = a.iter().take_while(|x| x.is_finite()).count();
+    a.truncate(n);
+    a
+}
+
+/// Evaluate the denominator of the continued fraction with coefficients a at t
+fn eval_cf(a: &[f64], t: f64) -> f64 {
+    a.iter().rev().fold(1.0, |r, x| 1.0 - x * t / r)
+}
+
+/// Fragments passing the filters on a contig, with the counts per fragment length of read pairs
+/// (for contigs where the fragment lengths are collected)
+#[derive(Debug, Default, Clone)]
+pub struct CtgFragments {
+    n: usize,
+    lens: Vec<usize>,
+}
+
+impl CtgFragments {
+    fn add(&mut self, n: usize, lens: &[usize]) {
+        self.n += n;
+        if self.lens.len() < lens.len() {
+            self.lens.resize(lens.len(), 0)
+        }
+        for (x, y) in self.lens.iter_mut().zip(lens.iter()) {
+            *x += *y
+        }
+    }
+
+    /// Counts of fragments per length
+    pub fn lens(&self) -> &[usize] {
+        &self.lens
+    }
+
+    /// Median fragment length
+    fn median_len(&self) -> Option<usize> {
+        let n: usize = self.lens.iter().sum();
+        let mut k = 0;
+        self.lens.iter().position(|x| {
+            k += *x;
+            k > n >> 1
+        })
+    }
+}
+
+/// Read level counters for a sample
+#[derive(Debug, Default, Clone)]
+pub struct ReadStats {
+    reads: usize,
+    flags: usize,
+    mapq: usize,
+    duplicates: usize,
+    template_len: usize,
+    low_qual_bases: usize,
+    spectrum: DupSpectrum,
+    ctg_fragments: HashMap<Arc<str>, CtgFragments>,
+    bin_reads: BinReadCounts,
+}
+
+impl ReadStats {
+    /// Count a read, along with the reason it was removed (if any)
+    pub fn add_read(&mut self, filtered: Option<FilterReason>) {
+        self.reads += 1;
+        match filtered {
+            Some(FilterReason::Flags) => self.flags += 1,
+            Some(FilterReason::Mapq) => self.mapq += 1,
+            Some(FilterReason::Duplicate) => self.duplicates += 1,
+            Some(FilterReason::TemplateLen) => self.template_len += 1,
+            None => (),
+        }
+    }
+
+    pub fn add_low_qual_bases(&mut self, n: usize) {
+        self.low_qual_bases += n
+    }
+
+    /// Add the number of fragments for a contig, along with the counts per fragment length if collected
+    pub fn add_ctg_fragments(&mut self, ctg: &Arc<str>, n: usize, lens: Option<&[usize]>) {
+        self.ctg_fragments
+            .entry(Arc::clone(ctg))
+            .or_default()
+            .add(n, lens.unwrap_or(&[]))
+    }
+
+    pub fn ctg_fragments(&self, ctg: &str) -> Option<&CtgFragments> {
+        self.ctg_fragments.get(ctg)
+    }
+
+    /// Add the per bin read statistics for a contig
+    pub fn add_bin_reads(&mut self, ctg: &Arc<str>, v: Vec<BinReads>) {
+        self.bin_reads.insert(Arc::clone(ctg), v);
+    }
+
+    /// Remove the per bin read statistics (for output with the coverage)
+    pub fn take_bin_reads(&mut self) -> BinReadCounts {
+        mem::take(&mut self.bin_reads)
+    }
+
+    /// Number of fragments on all contigs
+    pub fn fragments(&self) -> usize {
+        self.ctg_fragments.values().map(|f| f.n).sum()
+    }
+
+    /// Number of fragments on contigs not marked as non-host
+    pub fn host_fragments(&self, cfg: &Config) -> usize {
+        self.ctg_fragments
+            .iter()
+            .filter(|(ctg, _)| {
+                cfg.ctg_hash()
+                    .get(*ctg)
+                    .map(|c| !c.non_host())
+                    .unwrap_or(true)
+            })
+            .map(|(_, f)| f.n)
+            .sum()
+    }
+
+    /// Add a fragment position to the duplicate spectrum
+    pub fn add_position(&mut self, tid: usize, pos: usize, mpos: Option<usize>, reverse: bool) {
+        self.spectrum.add(tid, pos, mpos, reverse)
+    }
+
+    /// Finish collecting the duplicate spectrum.  Must be called after all reads have been counted
+    pub fn finish(&mut self) {
+        self.spectrum.flush()
+    }
+
+    /// Add counts from another set of counters (i.e., from another contig of the same sample)
+    pub fn combine(&mut self, other: &Self) {
+        self.reads += other.reads;
+        self.flags += other.flags;
+        self.mapq += other.mapq;
+        self.duplicates += other.duplicates;
+        self.template_len += other.template_len;
+        self.low_qual_bases += other.low_qual_bases;
+        self.spectrum.combine(&other.spectrum);
+        for (ctg, f) in other.ctg_fragments.iter() {
+            self.add_ctg_fragments(ctg, f.n, Some(&f.lens))
+        }
+        for (ctg, v) in other.bin_reads.iter() {
+            self.add_bin_reads(ctg, v.clone())
+        }
+    }
+
+    /// Fraction of reads passing the flag and MAPQ filters that were removed as duplicates
+    pub fn duplicate_rate(&self) -> Option<f64> {
+        let n = self.reads - self.flags - self.mapq;
+        if n > 0 {
+            Some((self.duplicates as f64) / (n as f64))
+        } else {
+            None
+        }
+    }
+}
+
+/// Coverage metrics for one block size
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CoverageQc {
+    mean_cov: Option<f64>,
+    median_cov: Option<f64>,
+    gini: Option<f64>,
+    within_20pct: Option<f64>,
+    na_frac: Option<f64>,
+    cn_mad: Option<f64>,
+    dlrs: Option<f64>,
+    mt_mean_cov: Option<f64>,
+    mt_copies: Option<f64>,
+}
+
+/// Median of a slice (which is sorted in place).  Returns None if the slice is empty
+fn median(v: &mut [f64]) -> Option<f64> {
+    if v.is_empty() {
+        None
+    } else {
+        v.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(v[v.len() >> 1])
+    }
+}
+
+impl CoverageQc {
+    pub fn new(cfg: &Config, block_size: u32, nc: &NormCov) -> Self {
+        let bs = block_size as f64;
+        let mut n_bins = 0;
+        let mut n_na = 0;
+        let mut raw = Vec::new();
+        let mut cn = Vec::new();
+        let mut diffs = Vec::new();
+        for (ctg, cov) in nc.iter() {
+            n_bins += cov.len();
+            n_na += cov.iter().filter(|(_, z, _)| z.is_none()).count();
+            if !cfg
+                .ctg_hash()
+                .get(ctg)
+                .map(|c| c.use_for_normalization())
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            let mut prev: Option<f64> = None;
+            for (c, z) in cov.iter().filter_map(|(c, z, _)| z.map(|z| (c, z))) {
+                raw.push((*c as f64) / bs);
+                cn.push(z);
+                // Bins with zero copy number are skipped for the DLRS
+                if z > 0.0 {
+                    let l = z.log2();
+                    if let Some(p) = prev {
+                        diffs.push(l - p)
+                    }
+                    prev = Some(l)
+                }
+            }
+        }
+        let mean_cov = if raw.is_empty() {
+            None
+        } else {
+            Some(raw.iter().sum::<f64>() / (raw.len() as f64))
+        };
+        let median_cov = median(&mut raw);
+        // raw is now sorted
+        let tot: f64 = raw.iter().sum();
+        let gini = if tot > 0.0 {
+            let n = raw.len() as f64;
+            let s = raw
+                .iter()
+                .enumerate()
+                .fold(0.0, |s, (i, x)| s + (2.0 * (i as f64) + 1.0 - n) * x);
+            Some(s / (n * tot))
+        } else {
+            None
+        };
+        let within_20pct = median_cov.map(|m| {
+            let k = raw.iter().filter(|x| (*x - m).abs() <= 0.2 * m).count();
+            (k as f64) / (raw.len() as f64)
+        });
+        let na_frac = if n_bins > 0 {
+            Some((n_na as f64) / (n_bins as f64))
+        } else {
+            None
+        };
+        let cn_mad = median(&mut cn).and_then(|m| {
+            let mut d: Vec<_> = cn.iter().map(|z| (z - m).abs()).collect();
+            median(&mut d)
+        });
+        let dlrs = if diffs.is_empty() {
+            None
+        } else {
+            diffs.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            let n = diffs.len();
+            robust_sd(diffs[(3 * n) >> 2] - diffs[n >> 2], n).map(|sd| sd / 2.0f64.sqrt())
+        };
+        let (mt_mean_cov, mt_copies) = mito_coverage(cfg, block_size, nc);
+        Self {
+            mean_cov,
+            median_cov,
+            gini,
+            within_20pct,
+            na_frac,
+            cn_mad,
+            dlrs,
+            mt_mean_cov,
+            mt_copies,
+        }
+    }
+}
+
+/// Mean raw coverage and copy number (copies per diploid genome) of the mitochondrial contig
+fn mito_coverage(cfg: &Config, block_size: u32, nc: &NormCov) -> (Option<f64>, Option<f64>) {
+    let bs = block_size as usize;
+    let Some((ctg, cov)) = cfg.mito_contig().and_then(|m| nc.get_key_value(m)) else {
+        return (None, None);
+    };
+    let Some(seq_len) = cfg.gc_data()[0].ctg_data(ctg).map(|d| d.seq_len()) else {
+        return (None, None);
+    };
+    let tot: usize = cov.iter().map(|(c, _, _)| *c).sum();
+    let mean_cov = if seq_len > 0 {
+        Some((tot as f64) / (seq_len as f64))
+    } else {
+        None
+    };
+    // Sum of copy number estimates and of bin lengths for bins with an estimate
+    let (s, l) = cov
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (_, z, _))| z.map(|z| (z, (seq_len - i * bs).min(bs))))
+        .fold((0.0, 0), |(s, l), (z, k)| (s + z, l + k));
+    let copies = if l > 0 {
+        Some(s * (bs as f64) / (l as f64))
+    } else {
+        None
+    };
+    (mean_cov, copies)
+}
+
+fn write_opt_f64<W: Write>(wrt: &mut W, x: Option<f64>) -> io::Result<()> {
+    match x {
+        Some(x) => write!(wrt, "\t{:.6}", x),
+        None => write!(wrt, "\tNA"),
+    }
+}
+
+/// Write QC table for a sample, with one line per block size.  The read level counters are
+/// written as NA if not available (i.e., when rebinning existing coverage files)
+pub fn write_qc_table<W: Write>(
+    wrt: &mut W,
+    cfg: &Config,
+    sample: &str,
+    read_stats: Option<&ReadStats>,
+    cov_qc: &[(u32, CoverageQc)],
+) -> io::Result<()> {
+    let complexity = read_stats.and_then(|s| s.spectrum.extrapolate(&COMPLEXITY_FOLDS));
+    let mito = read_stats.and_then(|s| cfg.mito_contig().and_then(|m| s.ctg_fragments(m)));
+    writeln!(
+        wrt,
+        "sample\tblock_size\treads\tflag_filtered\tmapq_filtered\tduplicates\ttemplate_len_filtered\t\
+        duplicate_rate\tlow_qual_bases\tdistinct_fragments\texp_distinct_2x\texp_distinct_5x\t\
+        exp_distinct_10x\tmean_cov\tmedian_cov\tgini\twithin_20pct\tna_frac\tcn_mad\tdlrs\t\
+        mt_mean_cov\tmt_copies\tmt_fragments\tmt_median_frag_len"
+    )?;
+    for (bs, q) in cov_qc.iter() {
+        write!(wrt, "{}\t{}", sample, bs)?;
+        match read_stats {
+            Some(s) => {
+                for x in [s.reads, s.flags, s.mapq, s.duplicates, s.template_len] {
+                    write!(wrt, "\t{}", x)?
+                }
+                write_opt_f64(wrt, s.duplicate_rate())?;
+                write!(wrt, "\t{}\t{}", s.low_qual_bases, s.spectrum.distinct())?;
+                match complexity.as_ref() {
+                    Some(v) => {
+                        for x in v.iter() {
+                            write!(wrt, "\t{:.0}", x)?
+                        }
+                    }
+                    None => write!(wrt, "{}", "\tNA".repeat(COMPLEXITY_FOLDS.len()))?,
+                }
+            }
+            None => write!(wrt, "{}", "\tNA".repeat(8 + COMPLEXITY_FOLDS.len()))?,
+        }
+        for x in [
+            q.mean_cov,
+            q.median_cov,
+            q.gini,
+            q.within_20pct,
+            q.na_frac,
+            q.cn_mad,
+            q.dlrs,
+            q.mt_mean_cov,
+            q.mt_copies,
+        ] {
+            write_opt_f64(wrt, x)?
+        }
+        match mito {
+            Some(f) => {
+                write!(wrt, "\t{}", f.n)?;
+                match f.median_len() {
+                    Some(l) => write!(wrt, "\t{}", l)?,
+                    None => write!(wrt, "\tNA")?,
+                }
+            }
+            None => write!(wrt, "\tNA\tNA")?,
+        }
+        writeln!(wrt)?
+    }
+    Ok(())
+}
+
+/// Write fragment length distribution (length and count for each length with fragments).  If
+/// ctg is set then the contig is written as the first column
+pub fn write_frag_lens<W: Write>(
+    wrt: &mut W,
+    ctg: Option<&str>,
+    f: &CtgFragments,
+) -> io::Result<()> {
+    for (l, n) in f.lens.iter().enumerate().filter(|(_, n)| **n > 0) {
+        if let Some(c) = ctg {
+            write!(wrt, "{}\t", c)?
+        }
+        writeln!(wrt, "{}\t{}", l, n)?
+    }
+    Ok(())
+}
+
+/// Write table of fragment counts for the non-host contigs (sorted by name), giving the
+/// number of fragments, the fragments per million host fragments and the median fragment length
+pub fn write_non_host_table<W: Write>(
+    wrt: &mut W,
+    cfg: &Config,
+    ctgs: &[&Arc<str>],
+    read_stats: &ReadStats,
+) -> io::Result<()> {
+    let host = read_stats.host_fragments(cfg);
+    writeln!(wrt, "contig\tfragments\tper_million_host\tmedian_frag_len")?;
+    for ctg in ctgs {
+        let f = read_stats.ctg_fragments(ctg);
+        let n = f.map(|f| f.n).unwrap_or(0);
+        write!(wrt, "{}\t{}", ctg, n)?;
+        write_opt_f64(
+            wrt,
+            Some(host)
+                .filter(|h| *h > 0)
+                .map(|h| 1.0e6 * (n as f64) / (h as f64)),
+        )?;
+        match f.and_then(|f| f.median_len()) {
+            Some(l) => writeln!(wrt, "\t{}", l)?,
+            None => writeln!(wrt, "\tNA")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spectrum(hist: Vec<usize>) -> DupSpectrum {
+        DupSpectrum {
+            hist,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn qd_geometric_series() {
+        // sum_k c_0 r^k t^k = c_0 / (1 - r t)
+        let c: Vec<f64> = (0..6).map(|k| 3.0 * 0.5f64.powi(k)).collect();
+        let a = qd_coefficients(&c);
+        assert!((a[0] - 0.5).abs() < 1e-12);
+        assert!(a[1..].iter().all(|x| x.abs() < 1e-12));
+        assert!((3.0 / eval_cf(&a, 0.8) - 3.0 / (1.0 - 0.5 * 0.8)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn extrapolate_poisson() {
+        // Expected spectrum for n molecules each sampled a Poisson(lambda) number of times:
+        // n_j = n exp(-lambda) lambda^j / j!.  At twice the depth the expected number of
+        // distinct molecules is n (1 - exp(-2 lambda))
+        let (n, lambda) = (1.0e7, 1.0f64);
+        let mut hist = Vec::new();
+        let mut p = n * (-lambda).exp();
+        for j in 1.. {
+            p *= lambda / (j as f64);
+            let x = p.round() as usize;
+            if x == 0 {
+                break;
+            }
+            hist.push(x)
+        }
+        let ds = spectrum(hist);
+        let expected = n * (1.0 - (-2.0 * lambda).exp());
+        let v = ds.extrapolate(&[2.0]).expect("No extrapolation");
+        assert!(
+            ((v[0] - expected) / expected).abs() < 0.01,
+            "{} != {}",
+            v[0],
+            expected
+        );
+    }
+
+    #[test]
+    fn extrapolate_singletons() {
+        assert!(spectrum(vec![1000])
+            .extrapolate(&COMPLEXITY_FOLDS)
+            .is_none());
+        assert!(spectrum(Vec::new())
+            .extrapolate(&COMPLEXITY_FOLDS)
+            .is_none());
+    }
+}
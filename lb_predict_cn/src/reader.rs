@@ -1,8 +1,17 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, io::Write, sync::Arc};
 
 use r_htslib::*;
 
-use crate::{config::Config, coverage::*};
+use crate::{
+    bin_reads::BinReads,
+    config::Config,
+    coverage::*,
+    frag_gc::{fragment, FragGcLookup, FragTable, FragWeighter, RefSeqs},
+    input::input_ctg_name,
+    qc::{FilterReason, ReadStats},
+    subsample::ReadSampler,
+    targets::{CtgTargets, Location, TargetCursor},
+};
 
 #[derive(Debug)]
 struct ReadFilter {
@@ -33,78 +42,171 @@ impl ReadFilter {
         }
     }
 
-    fn pass_filter(&self, brec: &BamRec, prev_pos: &Option<(usize, usize, Option<usize>)>) -> bool {
+    /// Check a read against the filters, returning the reason for removal if the read
+    /// does not pass
+    fn filter(
+        &self,
+        brec: &BamRec,
+        prev_pos: &Option<(usize, usize, Option<usize>)>,
+    ) -> Option<FilterReason> {
         let flag = brec.flag();
-        let mapq = brec.qual();
-        if (flag & BAM_FPAIRED) == 0 {
-            // Unpaired reads
-            if mapq >= self.min_mapq && (flag & self.forbid_flags_unpaired) == 0 {
+        let paired = (flag & BAM_FPAIRED) != 0;
+        let forbid = if paired {
+            self.forbid_flags_paired
+        } else {
+            self.forbid_flags_unpaired
+        };
+        // Duplicates flagged in the input are counted separately from the other flags
+        if (flag & forbid & !BAM_FDUP) != 0 || (paired && (flag & BAM_FPROPER_PAIR) == 0) {
+            return Some(FilterReason::Flags);
+        }
+        if brec.qual() < self.min_mapq {
+            return Some(FilterReason::Mapq);
+        }
+        if (flag & forbid) != 0 {
+            return Some(FilterReason::Duplicate);
+        }
+        if paired {
+            if !self.keep_duplicates {
                 // Check for duplicate (same coordinates as previous read)
-                if let Some((tid, x, None)) = prev_pos {
-                    brec.tid().unwrap() != *tid || brec.pos().unwrap() != *x
-                } else {
-                    true
+                if let Some((tid, x, Some(y))) = prev_pos {
+                    if brec.tid().unwrap() == *tid
+                        && brec.pos().unwrap() == *x
+                        && brec.mpos().unwrap() == *y
+                    {
+                        return Some(FilterReason::Duplicate);
+                    }
                 }
-            } else {
-                false
             }
-        } else {
-            // Paired reads
-            if mapq >= self.min_mapq
-                && (flag & (self.forbid_flags_paired | BAM_FPROPER_PAIR)) == BAM_FPROPER_PAIR
-            {
-                if !self.keep_duplicates {
-                    // Check for duplicate (same coordinates as previous read)
-                    if let Some((tid, x, Some(y))) = prev_pos {
-                        if brec.tid().unwrap() == *tid
-                            && brec.pos().unwrap() == *x
-                            && brec.mpos().unwrap() == *y
-                        {
-                            return false;
-                        }
-                    }
+            let m = flag & (BAM_FREVERSE | BAM_FMREVERSE);
+            if m != BAM_FREVERSE && m != BAM_FMREVERSE {
+                return Some(FilterReason::Flags);
+            }
+            if self.min_len > 0 || self.max_len.is_some() {
+                let l = brec.template_len().unsigned_abs();
+                if l < self.min_len || self.max_len.map(|x| l > x).unwrap_or(false) {
+                    return Some(FilterReason::TemplateLen);
                 }
-                let m = flag & (BAM_FREVERSE | BAM_FMREVERSE);
-                (m == BAM_FREVERSE || m == BAM_FMREVERSE)
-                    && if let Some(x) = self.max_len {
-                        let l = brec.template_len().unsigned_abs();
-                        l >= self.min_len && l <= x
-                    } else if self.min_len > 0 {
-                        let l = brec.template_len().unsigned_abs();
-                        l >= self.min_len
-                    } else {
-                        true
-                    }
-            } else {
-                false
+            }
+        } else if let Some((tid, x, None)) = prev_pos {
+            // Unpaired reads: check for duplicate (same coordinates as previous read)
+            if brec.tid().unwrap() == *tid && brec.pos().unwrap() == *x {
+                return Some(FilterReason::Duplicate);
             }
         }
+        None
+    }
+
+    fn pass_filter(&self, brec: &BamRec, prev_pos: &Option<(usize, usize, Option<usize>)>) -> bool {
+        self.filter(brec, prev_pos).is_none()
+    }
+}
+
+/// Count a read for QC.  Reads passing the flag and MAPQ filters are added to the duplicate
+/// spectrum (using the first read for read pairs).  Only reads on contigs from the contig
+/// list are counted, and they are counted before any downsampling
+fn count_read(stats: &mut ReadStats, rec: &BamRec, filtered: Option<FilterReason>) {
+    stats.add_read(filtered);
+    let flag = rec.flag();
+    if !matches!(filtered, Some(FilterReason::Flags | FilterReason::Mapq))
+        && ((flag & BAM_FPAIRED) == 0 || (flag & BAM_FREAD1) != 0)
+    {
+        let mpos = if (flag & BAM_FPAIRED) != 0 {
+            rec.mpos()
+        } else {
+            None
+        };
+        stats.add_position(
+            rec.tid().unwrap(),
+            rec.pos().unwrap(),
+            mpos,
+            (flag & BAM_FREVERSE) != 0,
+        )
     }
 }
 
-struct RawCounter {
+/// Binned coverage for a contig.  If targets are present for the contig then
+/// bases within the targets are counted per target, and bases within the targets
+/// plus padding are not counted in the bins.  Coverage is accumulated as f64 to allow
+/// reads to be weighted (for fragment GC correction)
+struct RawCounter<'a> {
     ctg: Arc<str>,
-    cov: Vec<usize>,
+    cov: Vec<f64>,
     block_size: usize,
     seq_len: usize,
+    fragments: usize,
+    low_qual_bases: usize,
+    frag_lens: Option<Vec<usize>>,
+    bin_reads: Option<Vec<BinReads>>,
+    targets: Option<&'a CtgTargets>,
+    target_cov: Vec<f64>,
 }
 
-impl RawCounter {
-    fn new(ctg: &Arc<str>, seq_len: usize, block_size: usize) -> Self {
+impl<'a> RawCounter<'a> {
+    fn new(cfg: &'a Config, ctg: &Arc<str>, seq_len: usize) -> Self {
+        let block_size = cfg.block_size() as usize;
         let n_bins = (seq_len + block_size - 1) / block_size;
+        let targets = cfg.targets().and_then(|t| t.ctg_targets(ctg));
+        let target_cov = vec![0.0; targets.map(|t| t.targets().len()).unwrap_or(0)];
+        // Fragment lengths are collected for the mitochondrial and non-host contigs
+        let non_host = cfg
+            .ctg_hash()
+            .get(ctg)
+            .map(|c| c.non_host())
+            .unwrap_or(false);
+        let frag_lens = if non_host || cfg.mito_contig() == Some(ctg) {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        let bin_reads = if cfg.bin_read_stats() {
+            Some(vec![BinReads::default(); n_bins])
+        } else {
+            None
+        };
         Self {
             ctg: Arc::clone(ctg),
-            cov: vec![0; n_bins],
+            cov: vec![0.0; n_bins],
             block_size,
             seq_len,
+            fragments: 0,
+            low_qual_bases: 0,
+            frag_lens,
+            bin_reads,
+            targets,
+            target_cov,
+        }
+    }
+
+    /// Add a read to the per bin read statistics (if collected).  Counted is false for reads
+    /// removed by the MAPQ filter
+    fn add_bin_read(&mut self, rec: &BamRec, counted: bool) {
+        if let Some(v) = self.bin_reads.as_mut() {
+            let pos = rec.pos().unwrap().min(self.seq_len - 1);
+            v[pos / self.block_size].add(rec.qual(), (rec.flag() & BAM_FREVERSE) != 0, counted)
         }
     }
 
-    fn add_raw_counts(&mut self, rec: &BamRec, min_qual: u8) {
+    fn add_raw_counts(&mut self, rec: &BamRec, min_qual: u8, weight: f64) {
         let read_start = rec.pos().unwrap();
         let mut x = read_start;
         let end = rec.endpos() + 1;
         let flag = rec.flag();
+        // Count fragments (using the first read for read pairs)
+        if (flag & BAM_FPAIRED) == 0 || (flag & BAM_FREAD1) != 0 {
+            self.fragments += 1;
+            if let Some(v) = self
+                .frag_lens
+                .as_mut()
+                .filter(|_| (flag & BAM_FPAIRED) != 0)
+            {
+                let l = rec.template_len().unsigned_abs();
+                if v.len() <= l {
+                    v.resize(l + 1, 0)
+                }
+                v[l] += 1
+            }
+        }
         let mut y = if (flag & BAM_FPAIRED) != 0 {
             // Paired
             if (flag & BAM_FREVERSE) == 0 {
@@ -154,9 +256,18 @@ impl RawCounter {
             if let Some(qv) = rec.get_qual() {
                 let mut x1 = read_start;
                 assert!(x1 <= x);
+                let mut cursor = self.targets.map(|t| TargetCursor::new(t, x));
                 for q in qv.iter() {
-                    if x1 >= x && *q >= min_qual {
-                        self.cov[x1 / self.block_size] += 1;
+                    if x1 >= x && *q < min_qual {
+                        self.low_qual_bases += 1
+                    } else if x1 >= x {
+                        match cursor.as_mut().map(|c| c.lookup(x1)) {
+                            None | Some(Location::OffTarget) => {
+                                self.cov[x1 / self.block_size] += weight
+                            }
+                            Some(Location::Target(i)) => self.target_cov[i] += weight,
+                            Some(Location::Padding) => (),
+                        }
                     }
                     x1 += 1;
                     if x1 > y {
@@ -173,33 +284,106 @@ impl RawCounter {
     }
 }
 
-/// Read SAM/BAM/CRAM data from input file and calculate binned coverage
+/// Optional sub-sampling of reads.  A read is kept if the hash value of the read name
+/// (see ReadSampler) is less than the sampling fraction
+pub type Sampling<'a> = Option<(&'a ReadSampler, f64)>;
+
+fn keep_read(rec: &BamRec, sampling: Sampling) -> anyhow::Result<bool> {
+    Ok(match sampling {
+        Some((sampler, p)) => sampler.uniform(rec.qname()?.as_bytes()) < p,
+        None => true,
+    })
+}
+
+fn round_counts(v: Vec<f64>) -> Vec<usize> {
+    v.iter().map(|x| x.round() as usize).collect()
+}
+
+fn raw_counts(mut v: Vec<RawCounter>) -> RawCounts {
+    v.drain(..).map(|r| (r.ctg, round_counts(r.cov))).collect()
+}
+
+/// Split into binned counts and counts per target (for contigs with targets)
+fn raw_and_target_counts(mut v: Vec<RawCounter>) -> (RawCounts, RawCounts) {
+    let mut rc = HashMap::with_capacity(v.len());
+    let mut tc = HashMap::new();
+    for r in v.drain(..) {
+        if r.targets.is_some() {
+            tc.insert(Arc::clone(&r.ctg), round_counts(r.target_cov));
+        }
+        rc.insert(r.ctg, round_counts(r.cov));
+    }
+    (rc, tc)
+}
+
+/// Read SAM/BAM/CRAM data from input file and calculate binned coverage.  Returns the binned
+/// coverage, the coverage per target (which will be empty if no targets were supplied) and
+/// the read counters for QC.  If weighter is set, fragments are weighted according to their
+/// GC content, and the returned counts (used for all downstream output) are the rounded
+/// weighted counts
 pub fn read_coverage_data(
     cfg: &Config,
     hts: &mut Hts,
     ctg: Option<&Arc<str>>,
-) -> anyhow::Result<RawCounts> {
-    if let Some(c) = ctg {
-        read_ctg_coverage_data(cfg, hts, c)
+    sampling: Sampling,
+    weighter: Option<&mut FragWeighter>,
+) -> anyhow::Result<(RawCounts, RawCounts, ReadStats)> {
+    let mut stats = ReadStats::default();
+    let mut v = if let Some(c) = ctg {
+        read_ctg_coverage_data(cfg, hts, c, sampling, weighter, &mut stats)
     } else {
-        read_sample_coverage_data(cfg, hts)
+        read_sample_coverage_data(cfg, hts, sampling, weighter, &mut stats)
+    }?;
+    stats.add_low_qual_bases(v.iter().map(|r| r.low_qual_bases).sum());
+    for r in v.iter_mut() {
+        stats.add_ctg_fragments(&r.ctg, r.fragments, r.frag_lens.as_deref());
+        if let Some(br) = r.bin_reads.take() {
+            stats.add_bin_reads(&r.ctg, br)
+        }
     }
+    stats.finish();
+    let (rc, tc) = raw_and_target_counts(v);
+    Ok((rc, tc, stats))
 }
 
-/// Read data from a particular contig (requires indexed file)
-fn read_ctg_coverage_data(
+/// Read all data for a sample from file without index, returning the binned coverage and
+/// the number of fragments passing the filters
+pub fn read_sample_depth(cfg: &Config, hts: &mut Hts) -> anyhow::Result<(RawCounts, usize)> {
+    let v = read_sample_coverage_data(cfg, hts, None, None, &mut ReadStats::default())?;
+    let fragments = v.iter().map(|r| r.fragments).sum();
+    Ok((raw_counts(v), fragments))
+}
+
+/// Read data for a contig from an indexed file, returning the binned coverage and the number
+/// of fragments passing the filters
+pub fn read_ctg_depth(
     cfg: &Config,
     hts: &mut Hts,
     ctg: &Arc<str>,
-) -> anyhow::Result<RawCounts> {
-    let mut rc = HashMap::new();
-    if let Some(seq_len) = hts.seq_length(ctg) {
-        let tid = hts.name2tid(ctg);
-        let block_size = cfg.block_size() as usize;
+) -> anyhow::Result<(RawCounts, usize)> {
+    let v = read_ctg_coverage_data(cfg, hts, ctg, None, None, &mut ReadStats::default())?;
+    let fragments = v.iter().map(|r| r.fragments).sum();
+    Ok((raw_counts(v), fragments))
+}
+
+/// Read data from a particular contig (requires indexed file)
+fn read_ctg_coverage_data<'a>(
+    cfg: &'a Config,
+    hts: &mut Hts,
+    ctg: &Arc<str>,
+    sampling: Sampling,
+    mut weighter: Option<&mut FragWeighter>,
+    stats: &mut ReadStats,
+) -> anyhow::Result<Vec<RawCounter<'a>>> {
+    let mut rc = Vec::with_capacity(1);
+    let contig = cfg.ctg_hash().get(ctg).expect("Unknown contig");
+    if let Some(name) = input_ctg_name(hts, contig) {
+        let seq_len = hts.seq_length(name).unwrap();
+        let tid = hts.name2tid(name);
         let filter = ReadFilter::new(cfg);
         trace!("Filter set to: {:?}", filter);
-        let mut raw_cov = RawCounter::new(ctg, seq_len, block_size);
-        let rlist = hts.make_region_list(&[ctg]);
+        let mut raw_cov = RawCounter::new(cfg, ctg, seq_len);
+        let rlist = hts.make_region_list(&[name]);
         let mut rdr: HtsItrReader<BamRec> = hts.itr_reader(&rlist);
         let mut rec = BamRec::new()?;
 
@@ -207,12 +391,26 @@ fn read_ctg_coverage_data(
         let mut prev_pos: Option<(usize, usize, Option<usize>)> = None;
         while rdr.read(&mut rec)? {
             assert_eq!(rec.tid(), tid);
-            if filter.pass_filter(&rec, &prev_pos) {
-                raw_cov.add_raw_counts(&rec, cfg.min_qual());
+            let filtered = filter.filter(&rec, &prev_pos);
+            count_read(stats, &rec, filtered);
+            if filtered.is_none() {
+                if keep_read(&rec, sampling)? {
+                    let w = match weighter.as_mut() {
+                        Some(fw) => fw.weight(cfg, ctg, &rec)?,
+                        None => 1.0,
+                    };
+                    raw_cov.add_raw_counts(&rec, cfg.min_qual(), w);
+                    raw_cov.add_bin_read(&rec, true);
+                }
                 prev_pos = Some((rec.tid().unwrap(), rec.pos().unwrap(), rec.mpos()));
+            } else if filtered == Some(FilterReason::Mapq)
+                && raw_cov.bin_reads.is_some()
+                && keep_read(&rec, sampling)?
+            {
+                raw_cov.add_bin_read(&rec, false)
             }
         }
-        rc.insert(raw_cov.ctg, raw_cov.cov);
+        rc.push(raw_cov);
     } else {
         warn!("Contig {} not found in input file", ctg);
     }
@@ -221,8 +419,13 @@ fn read_ctg_coverage_data(
 }
 
 /// Read data for all requested contigs from file without index
-fn read_sample_coverage_data(cfg: &Config, hts: &mut Hts) -> anyhow::Result<RawCounts> {
-    let mut rc = HashMap::new();
+fn read_sample_coverage_data<'a>(
+    cfg: &'a Config,
+    hts: &mut Hts,
+    sampling: Sampling,
+    mut weighter: Option<&mut FragWeighter>,
+    stats: &mut ReadStats,
+) -> anyhow::Result<Vec<RawCounter<'a>>> {
     let mut rec = BamRec::new()?;
     let filter = ReadFilter::new(cfg);
 
@@ -230,12 +433,12 @@ fn read_sample_coverage_data(cfg: &Config, hts: &mut Hts) -> anyhow::Result<RawC
     // values being RawCounter structures
     let mut chash: HashMap<_, _> = cfg
         .ctg_hash()
-        .keys()
-        .filter_map(|ctg| {
-            hts.seq_length(ctg).map(|l| {
+        .iter()
+        .filter_map(|(ctg, contig)| {
+            input_ctg_name(hts, contig).map(|name| {
                 (
-                    hts.name2tid(ctg).unwrap(),
-                    RawCounter::new(ctg, l, cfg.block_size() as usize),
+                    hts.name2tid(name).unwrap(),
+                    RawCounter::new(cfg, ctg, hts.seq_length(name).unwrap()),
                 )
             })
         })
@@ -245,17 +448,141 @@ fn read_sample_coverage_data(cfg: &Config, hts: &mut Hts) -> anyhow::Result<RawC
     // Only works if input is sorted on genomic order
     let mut prev_pos: Option<(usize, usize, Option<usize>)> = None;
 
+    while rec.read(hts)? {
+        // Only reads on the requested contigs are considered (and counted), as for indexed files
+        let Some(raw_cov) = rec.tid().and_then(|tid| chash.get_mut(&tid)) else {
+            continue;
+        };
+        let filtered = filter.filter(&rec, &prev_pos);
+        count_read(stats, &rec, filtered);
+        if filtered.is_none() {
+            if keep_read(&rec, sampling)? {
+                let w = match weighter.as_mut() {
+                    Some(fw) => fw.weight(cfg, &raw_cov.ctg, &rec)?,
+                    None => 1.0,
+                };
+                raw_cov.add_raw_counts(&rec, cfg.min_qual(), w);
+                raw_cov.add_bin_read(&rec, true);
+            }
+            prev_pos = Some((rec.tid().unwrap(), rec.pos().unwrap(), rec.mpos()));
+        } else if filtered == Some(FilterReason::Mapq)
+            && raw_cov.bin_reads.is_some()
+            && keep_read(&rec, sampling)?
+        {
+            raw_cov.add_bin_read(&rec, false)
+        }
+    }
+
+    Ok(chash.into_values().collect())
+}
+
+/// Read all data for a sample from file without index, and collect the observed counts of
+/// fragments passing the filters by fragment length and GC content from the contigs used
+/// for normalization.  Only the first read of each pair is used
+pub fn read_fragment_gc(cfg: &Config, hts: &mut Hts, seqs: &RefSeqs) -> anyhow::Result<FragTable> {
+    let mut rec = BamRec::new()?;
+    let filter = ReadFilter::new(cfg);
+    let mut lookup = FragGcLookup::new(seqs);
+    let mut tab = FragTable::new(cfg);
+
+    let chash: HashMap<_, _> = cfg
+        .ctg_hash()
+        .iter()
+        .filter(|(_, contig)| contig.use_for_normalization())
+        .filter_map(|(ctg, contig)| {
+            input_ctg_name(hts, contig).map(|name| (hts.name2tid(name).unwrap(), Arc::clone(ctg)))
+        })
+        .collect();
+
+    let mut prev_pos: Option<(usize, usize, Option<usize>)> = None;
     while rec.read(hts)? {
         if filter.pass_filter(&rec, &prev_pos) {
-            if let Some(raw_cov) = chash.get_mut(&rec.tid().unwrap()) {
-                raw_cov.add_raw_counts(&rec, cfg.min_qual());
+            if let Some(ctg) = chash.get(&rec.tid().unwrap()) {
+                if (rec.flag() & BAM_FREAD1) != 0 {
+                    if let Some((x, l)) = fragment(&rec) {
+                        if let Some(g) = lookup.gc(cfg, ctg, x, l)? {
+                            tab.add(l, g)
+                        }
+                    }
+                }
                 prev_pos = Some((rec.tid().unwrap(), rec.pos().unwrap(), rec.mpos()));
             }
         }
     }
+    Ok(tab)
+}
+
+/// Read data for all requested contigs from file without index, sub-sampling the reads.
+/// For each of the sampling probabilities in probs a separate set of counts is generated.
+/// Reads are selected using a hash of the read name, so both reads of a pair are kept
+/// or dropped together.  If read_lists is set then the names of the sampled templates are
+/// written to the corresponding output (one per sampling probability); this includes
+/// templates that do not pass the read filters
+pub fn read_sampled_coverage_data<W: Write>(
+    cfg: &Config,
+    hts: &mut Hts,
+    sampler: &ReadSampler,
+    probs: &[f64],
+    mut read_lists: Option<&mut [W]>,
+) -> anyhow::Result<Vec<RawCounts>> {
+    let mut rec = BamRec::new()?;
+    let filter = ReadFilter::new(cfg);
 
-    for (_, raw_cov) in chash.drain() {
-        rc.insert(raw_cov.ctg, raw_cov.cov);
+    // As for read_sample_coverage_data, but with a vector of RawCounters per sequence
+    // (one per sampling probability)
+    let mut chash: HashMap<_, _> = cfg
+        .ctg_hash()
+        .iter()
+        .filter_map(|(ctg, contig)| {
+            input_ctg_name(hts, contig).map(|name| {
+                let seq_len = hts.seq_length(name).unwrap();
+                (
+                    hts.name2tid(name).unwrap(),
+                    probs
+                        .iter()
+                        .map(|_| RawCounter::new(cfg, ctg, seq_len))
+                        .collect::<Vec<_>>(),
+                )
+            })
+        })
+        .collect();
+
+    let mut prev_pos: Option<(usize, usize, Option<usize>)> = None;
+
+    while rec.read(hts)? {
+        let flag = rec.flag();
+        let primary = (flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY)) == 0;
+        let u = sampler.uniform(rec.qname()?.as_bytes());
+
+        // Record each template once (using the first read for paired reads)
+        if let Some(wrt) = read_lists.as_mut() {
+            if primary && ((flag & BAM_FPAIRED) == 0 || (flag & BAM_FREAD1) != 0) {
+                for (w, p) in wrt.iter_mut().zip(probs.iter()) {
+                    if u < *p {
+                        writeln!(w, "{}", rec.qname()?)?
+                    }
+                }
+            }
+        }
+
+        // Duplicates are identified before sampling
+        if filter.pass_filter(&rec, &prev_pos) {
+            if let Some(counters) = chash.get_mut(&rec.tid().unwrap()) {
+                for (raw_cov, p) in counters.iter_mut().zip(probs.iter()) {
+                    if u < *p {
+                        raw_cov.add_raw_counts(&rec, cfg.min_qual(), 1.0)
+                    }
+                }
+                prev_pos = Some((rec.tid().unwrap(), rec.pos().unwrap(), rec.mpos()));
+            }
+        }
     }
-    Ok(rc)
+
+    let mut v: Vec<RawCounts> = probs.iter().map(|_| HashMap::new()).collect();
+    for (_, mut counters) in chash.drain() {
+        for (rc, raw_cov) in v.iter_mut().zip(counters.drain(..)) {
+            rc.insert(raw_cov.ctg, round_counts(raw_cov.cov));
+        }
+    }
+    Ok(v)
 }
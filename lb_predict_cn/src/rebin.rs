@@ -0,0 +1,296 @@
+/// Rebinning and merging of existing coverage output files
+///
+/// The raw coverage column of the output files (coverage / block size) allows the raw counts
+/// per bin to be recovered, so coverage files generated with a small block size can be aggregated
+/// into larger bins and GC normalized again without having to re-read the alignment files.
+/// In the same way, the coverage files from multiple runs of a sample (i.e., when a sample has been
+/// topped up with extra sequencing) can be merged by summing the raw counts before normalization.
+///
+/// Bins that were not written in the original output (because the GC content or the GC
+/// prediction was missing, or the bin was masked by targets) are treated as missing.  Aggregated
+/// bins that contain any missing bins are not used for the GC model and are not written, as
+/// their raw counts would be underestimated.
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use utils::{get_next_line, is_coverage_header};
+
+use crate::{
+    cli::RebinTask,
+    config::Config,
+    coverage::{add_counts, MissingBins, RawCounts},
+    normalize::{aggregate_counts, aggregate_missing, normalize_sample},
+    output::{output_gc_curves, output_sample_cfg, output_sample_qc, setup_output},
+    pool::pool_map,
+};
+
+/// Path of the coverage file for a contig in a run directory
+fn ctg_file_path(dir: &Path, prefix: &str, ctg: &str) -> PathBuf {
+    dir.join(format!("{}_{}.txt", prefix, ctg))
+}
+
+/// Check whether a run directory has per target output, i.e., the run was made with targets
+fn has_targets_output(dir: &Path) -> bool {
+    fs::read_dir(dir)
+        .map(|rd| {
+            rd.filter_map(|e| e.ok())
+                .any(|e| e.file_name().to_string_lossy().ends_with("_targets.txt"))
+        })
+        .unwrap_or(false)
+}
+
+/// Check that all runs for a sample have coverage files for all contigs from the contig
+/// list.  Other files in the run directories are ignored
+fn check_run_contigs(task: &RebinTask, sample_idx: usize) -> anyhow::Result<()> {
+    let mut ctgs: Vec<_> = task.cfg.ctg_hash().keys().map(|c| c.as_ref()).collect();
+    ctgs.sort_unstable();
+    for run in task.runs[sample_idx].iter() {
+        if has_targets_output(run) {
+            warn!(
+                "Coverage files in {} appear to be from a run with targets: bins masked by targets are treated as missing, but counts of partially masked bins are not corrected",
+                run.display()
+            )
+        }
+        let missing: Vec<_> = ctgs
+            .iter()
+            .filter(|c| !ctg_file_path(run, &task.input_prefix, c).exists())
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Coverage files missing from {} for contigs: {}",
+                run.display(),
+                missing.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read raw counts for a contig from an existing coverage file.  Bins not present in the
+/// file are returned as None
+fn read_ctg_counts(
+    p: &Path,
+    ctg: &str,
+    block_size: u32,
+    n_bins: usize,
+) -> anyhow::Result<Vec<Option<usize>>> {
+    trace!("Opening coverage file {} for reading", p.display());
+    let mut rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Error opening coverage file {}", p.display()))?;
+    let mut cov = vec![None; n_bins];
+    let bs = block_size as f64;
+    let mut buf = String::new();
+    let mut line = 0;
+
+    while let Some(fields) = get_next_line(&mut rdr, &mut buf)
+        .with_context(|| format!("Error after reading {} lines from {}", line, p.display()))?
+    {
+        line += 1;
+        // Skip short lines and the header line
+        if fields.len() >= 4 && !is_coverage_header(&fields) {
+            if fields[0] != ctg {
+                return Err(anyhow!(
+                    "{}:{} Unexpected contig {} (expected {})",
+                    p.display(),
+                    line,
+                    fields[0],
+                    ctg
+                ));
+            }
+            let x = fields[1]
+                .parse::<usize>()
+                .with_context(|| format!("{}:{} Error reading position", p.display(), line))?;
+            let z = fields[3]
+                .parse::<f64>()
+                .with_context(|| format!("{}:{} Error reading raw coverage", p.display(), line))?;
+            // Check that the position is the mid-point of a bin of the expected size
+            let i = x / (block_size as usize);
+            if i >= n_bins || (((i as f64) + 0.5) * bs).round() as usize != x {
+                return Err(anyhow!(
+                    "{}:{} Position {} is not the mid-point of a bin with block size {}",
+                    p.display(),
+                    line,
+                    x,
+                    block_size
+                ));
+            }
+            cov[i] = Some((z * bs).round() as usize)
+        }
+    }
+    Ok(cov)
+}
+
+/// Read raw counts for all contigs of a run.  Returns the counts (with missing bins set to zero)
+/// and the missing bins
+fn read_run_counts(
+    cfg: &Config,
+    dir: &Path,
+    prefix: &str,
+    block_size: u32,
+) -> anyhow::Result<(RawCounts, MissingBins)> {
+    let gc_data = &cfg.gc_data()[0];
+    let bs = block_size as usize;
+    let mut rc = RawCounts::with_capacity(cfg.ctg_hash().len());
+    let mut missing = MissingBins::with_capacity(cfg.ctg_hash().len());
+    for ctg in cfg.ctg_hash().keys() {
+        let seq_len = gc_data
+            .ctg_data(ctg)
+            .with_context(|| format!("Contig {} not found in reference", ctg))?
+            .seq_len();
+        let p = ctg_file_path(dir, prefix, ctg);
+        let cov = read_ctg_counts(&p, ctg, block_size, seq_len.div_ceil(bs))?;
+        missing.insert(Arc::clone(ctg), cov.iter().map(|c| c.is_none()).collect());
+        rc.insert(
+            Arc::clone(ctg),
+            cov.into_iter().map(|c| c.unwrap_or(0)).collect(),
+        );
+    }
+    Ok((rc, missing))
+}
+
+/// Mean raw coverage across all non-missing bins
+fn mean_coverage(rc: &RawCounts, missing: &MissingBins, block_size: u32) -> f64 {
+    let (n, tot) = rc.iter().fold((0, 0), |(n, tot), (ctg, v)| {
+        v.iter()
+            .zip(missing[ctg].iter())
+            .filter(|(_, m)| !**m)
+            .fold((n, tot), |(n, tot), (c, _)| (n + 1, tot + *c))
+    });
+    if n > 0 {
+        (tot as f64) / ((n as f64) * (block_size as f64))
+    } else {
+        0.0
+    }
+}
+
+/// Write list of runs merged for a sample with the mean raw coverage of each
+fn write_merged_runs(
+    task: &RebinTask,
+    sample_idx: usize,
+    run_cov: &[(&Path, f64)],
+) -> anyhow::Result<()> {
+    let cfg = &task.cfg;
+    let mut p = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    p.push(cfg.sample_list()[sample_idx].name());
+    p.push("merged_runs.txt");
+    let mut wrt = BufWriter::new(
+        fs::File::create(&p)
+            .with_context(|| format!("problem creating output file {}", p.display()))?,
+    );
+    writeln!(
+        wrt,
+        "# Sample {}: merged {} runs with block size {}",
+        cfg.sample_list()[sample_idx].name(),
+        run_cov.len(),
+        task.input_block_size
+    )?;
+    for (run, cov) in run_cov {
+        writeln!(wrt, "{}\t{:.4}", run.display(), cov)?
+    }
+    Ok(())
+}
+
+/// Read raw counts for all contigs of a sample, summing over runs if more than one
+/// run is present.  A bin is missing if it is missing from any run
+fn read_sample_counts(
+    task: &RebinTask,
+    sample_idx: usize,
+) -> anyhow::Result<(RawCounts, MissingBins)> {
+    let runs = &task.runs[sample_idx];
+    check_run_contigs(task, sample_idx)?;
+    let mut rc: Option<(RawCounts, MissingBins)> = None;
+    let mut run_cov = Vec::with_capacity(runs.len());
+    for run in runs {
+        trace!("Reading coverage files from {}", run.display());
+        let (h, m) = read_run_counts(&task.cfg, run, &task.input_prefix, task.input_block_size)?;
+        run_cov.push((run.as_path(), mean_coverage(&h, &m, task.input_block_size)));
+        match rc.as_mut() {
+            None => rc = Some((h, m)),
+            Some((a, am)) => {
+                add_counts(a, &h)
+                    .with_context(|| format!("Error merging counts from {}", run.display()))?;
+                for (ctg, v) in am.iter_mut() {
+                    for (x, y) in v.iter_mut().zip(m[ctg].iter()) {
+                        *x |= *y
+                    }
+                }
+            }
+        }
+    }
+    if task.merge {
+        write_merged_runs(task, sample_idx, &run_cov)?
+    }
+    Ok(rc.expect("No runs for sample"))
+}
+
+fn rebin_sample(task: &RebinTask, sample_idx: usize) -> anyhow::Result<()> {
+    let cfg = &task.cfg;
+    let (rc, missing) = read_sample_counts(task, sample_idx)?;
+
+    // Aggregate to the smallest output block size
+    let k = (cfg.block_size() / task.input_block_size) as usize;
+    let (rc, missing) = if k > 1 {
+        (aggregate_counts(&rc, k), aggregate_missing(&missing, k))
+    } else {
+        (rc, missing)
+    };
+
+    let (mut v, curves) = normalize_sample(cfg, rc, Some(&missing), cfg.gc_model())?;
+    output_gc_curves(cfg, sample_idx, &curves, None)?;
+    output_sample_qc(cfg, sample_idx, None, &v)?;
+    for (level, mut nc) in v.drain(..).enumerate() {
+        for (ctg, cov) in nc.drain() {
+            output_sample_cfg(cfg, sample_idx, level, &ctg, cov, None)?
+        }
+    }
+    Ok(())
+}
+
+/// Rebin or merge existing coverage files (rebin and merge subcommands).  If processing fails
+/// for a sample, the error is logged and the remaining samples are processed
+pub fn rebin_samples(task: &RebinTask) -> anyhow::Result<()> {
+    setup_output(&task.cfg)?;
+
+    let ns = task.cfg.sample_list().len();
+    let res = pool_map(task.cfg.n_tasks(), (0..ns).collect(), "rebin", |ix| {
+        debug!("Processing sample {}", task.cfg.sample_list()[ix].name());
+        rebin_sample(task, ix).map_err(|e| {
+            error!(
+                "Error processing sample {}: {:#}",
+                task.cfg.sample_list()[ix].name(),
+                e
+            );
+            format!("{:#}", e)
+        })
+    })?;
+
+    let failed: Vec<_> = res
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, r)| r.err().map(|s| (i, s)))
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        let names: Vec<_> = failed
+            .iter()
+            .map(|(i, s)| format!("{} ({})", task.cfg.sample_list()[*i].name(), s))
+            .collect();
+        Err(anyhow!(
+            "Processing failed for {} of {} samples: {}",
+            failed.len(),
+            task.cfg.sample_list().len(),
+            names.join(", ")
+        ))
+    }
+}
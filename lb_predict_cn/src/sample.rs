@@ -16,6 +16,13 @@ pub struct Sample {
 }
 
 impl Sample {
+    pub fn new<P: AsRef<Path>>(name: &str, input_path: P) -> Self {
+        Self {
+            name: name.to_owned(),
+            input_path: input_path.as_ref().to_owned(),
+        }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -76,3 +83,55 @@ pub fn sample_vec_from_file<S: AsRef<Path>>(fname: S) -> anyhow::Result<Vec<Samp
     );
     Ok(sample_vec)
 }
+
+/// Read in list of samples to merge from file
+/// Expects at least two tab separated columns.
+/// The first column has the name of the merged sample (used for the output files)
+/// The following columns have the output directories of the runs to be merged
+///
+pub fn merge_list_from_file<S: AsRef<Path>>(
+    fname: S,
+) -> anyhow::Result<Vec<(Sample, Vec<PathBuf>)>> {
+    debug!("Reading in merge list from {}", fname.as_ref().display());
+
+    let mut rdr = CompressIo::new()
+        .path(&fname)
+        .bufreader()
+        .with_context(|| format!("Error opening merge file {}", fname.as_ref().display()))?;
+
+    let mut buf = String::new();
+    let mut line = 0;
+    let mut merge_vec = Vec::new();
+
+    while let Some(fields) = get_next_line(&mut rdr, &mut buf).with_context(|| {
+        format!(
+            "Error after reading {} lines from {}",
+            line,
+            fname.as_ref().display()
+        )
+    })? {
+        line += 1;
+
+        if fields.len() >= 2 {
+            // Skip short lines
+            let runs: Vec<_> = fields[1..].iter().map(PathBuf::from).collect();
+            let sample = Sample {
+                name: fields[0].to_owned(),
+                input_path: runs[0].clone(),
+            };
+            trace!(
+                "Read in merged sample {} with {} runs",
+                sample.name,
+                runs.len()
+            );
+            merge_vec.push((sample, runs))
+        }
+    }
+
+    debug!(
+        "Finished reading in {} lines; found {} merged samples",
+        line,
+        merge_vec.len()
+    );
+    Ok(merge_vec)
+}
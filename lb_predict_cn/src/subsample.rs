@@ -0,0 +1,33 @@
+/// Deterministic sub-sampling of reads
+///
+/// Reads are selected based on a hash of the read name combined with a seed, so that the
+/// selection is reproducible and both reads of a pair (which share the same name) are
+/// always selected or rejected together.
+pub struct ReadSampler {
+    seed: u64,
+}
+
+/// Finalizer from SplitMix64 to spread the bits of the FNV hash
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl ReadSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    /// Map read name to a value uniformly distributed in [0, 1).  A read is selected
+    /// with probability p if the returned value is < p
+    pub fn uniform(&self, name: &[u8]) -> f64 {
+        // FNV-1a hash of name, starting from the mixed seed
+        let mut h = 0xcbf29ce484222325 ^ mix64(self.seed);
+        for c in name {
+            h ^= *c as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        ((mix64(h) >> 11) as f64) / ((1u64 << 53) as f64)
+    }
+}
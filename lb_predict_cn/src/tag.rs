@@ -0,0 +1,109 @@
+/// Tagged copies of input files with per read GC correction weights
+///
+/// The weight for a read is found from the GC correction model of the sample, using the GC bin of
+/// the block (at the smallest block size) containing the start of the fragment (or the read for
+/// unpaired reads), adjusted for the additional covariates of the block if these are used, and is
+/// multiplied by the fragment level weight if fragment GC correction is being performed.  Both
+/// reads of a pair therefore get the same weight.  All reads mapped to contigs from the contig list
+/// are tagged (whether or not they pass the read filters) except where the GC bin of the block is
+/// missing or has no prediction.  All reads are written to the output file.
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Context;
+use r_htslib::*;
+
+use crate::{
+    config::Config,
+    covariates::ctg_covariates,
+    frag_gc::{fragment, FragGc},
+    input::{input_ctg_name, is_cram, open_input, open_output},
+    normalize::ReadWeights,
+};
+
+/// Contig name, GC bins and covariates (if used) for an input contig
+struct TagCtg<'a> {
+    ctg: &'a Arc<str>,
+    gc: &'a [Option<u32>],
+    cov: Vec<&'a [Option<f32>]>,
+}
+
+/// Write a copy of the input file for a sample with the GC correction weights in the
+/// tag given by cfg.gc_tag().  The output is written to the sample output directory, in
+/// CRAM format if the input file is CRAM and otherwise in BAM format
+pub fn write_tagged_input(
+    cfg: &Config,
+    sample_idx: usize,
+    weights: &ReadWeights,
+    frag_gc: Option<&FragGc>,
+    tpool: Option<&HtsThreadPool>,
+) -> anyhow::Result<()> {
+    let sample = &cfg.sample_list()[sample_idx];
+    let tag = cfg.gc_tag().expect("Missing GC tag").as_bytes();
+    let input = sample.input_path();
+    let cram = is_cram(input);
+    let mut p = cfg.output_dir().map(|d| d.to_owned()).unwrap_or_default();
+    p.push(sample.name());
+    p.push(format!(
+        "{}_gc_tagged.{}",
+        cfg.output_prefix(),
+        if cram { "cram" } else { "bam" }
+    ));
+    info!(
+        "Writing GC tagged copy of input file for sample {} to {}",
+        sample.name(),
+        p.display()
+    );
+
+    let mut hts = open_input(input, true, cfg.reference(), tpool)?;
+    let mut out = open_output(&p, cram, cfg.reference(), tpool)?;
+    out.write_header(
+        hts.header()
+            .with_context(|| format!("Missing header for input file {}", input.display()))?,
+    )
+    .with_context(|| format!("Error writing header to {}", p.display()))?;
+
+    // Look up from tid to contig, GC bins and covariates
+    let gc_data = &cfg.gc_data()[0];
+    let bs = gc_data.block_size() as usize;
+    let chash: HashMap<usize, TagCtg> = cfg
+        .ctg_hash()
+        .iter()
+        .filter_map(|(ctg, contig)| {
+            input_ctg_name(&hts, contig).and_then(|name| {
+                gc_data.ctg_data(ctg).map(|gc| {
+                    let tc = TagCtg {
+                        ctg,
+                        gc: gc.data(),
+                        cov: ctg_covariates(cfg, gc_data, ctg),
+                    };
+                    (hts.name2tid(name).unwrap(), tc)
+                })
+            })
+        })
+        .collect();
+
+    let mut weighter = frag_gc.map(|f| f.weighter(sample_idx));
+
+    let mut rec = BamRec::new()?;
+    let mut x = Vec::new();
+    while rec.read(&mut hts)? {
+        if (rec.flag() & BAM_FUNMAP) == 0 {
+            if let Some(tc) = rec.tid().and_then(|t| chash.get(&t)) {
+                let w = fragment(&rec)
+                    .map(|(x, _)| x)
+                    .or_else(|| rec.pos())
+                    .and_then(|pos| weights.weight(tc.gc, &tc.cov, pos / bs, &mut x));
+                if let Some(w) = w {
+                    let fw = match weighter.as_mut() {
+                        Some(fw) => fw.weight(cfg, tc.ctg, &rec)?,
+                        None => 1.0,
+                    };
+                    rec.aux_update_float(tag, (w * fw) as f32)?
+                }
+            }
+        }
+        rec.write(&mut out)
+            .with_context(|| format!("Error writing to {}", p.display()))?
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+/// Target regions for hybrid-capture panels
+///
+/// When a target BED file is supplied, coverage is split into on-target and off-target
+/// components.  Bases falling within a target are counted for that target, while bases
+/// outside of the targets plus padding are counted in the genomic bins as usual.  Bases in
+/// the padding around targets are not counted at all, so that the off-target profile is not
+/// affected by the tails of the on-target coverage.
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Context;
+
+use crate::{
+    bed::{merge_regions, read_bed_regions},
+    contig::Contig,
+    gc::{region_gc_bins, GcParams},
+};
+
+/// Off-target bins with less than this fraction of bases outside of the
+/// padded targets are not used
+pub const MIN_OFF_TARGET_FRACTION: f64 = 0.5;
+
+/// A target region (0 offset, half open)
+pub struct Target {
+    start: usize,
+    end: usize,
+    name: Option<String>,
+    gc: Option<u32>,
+}
+
+impl Target {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// GC bin of target region
+    pub fn gc(&self) -> Option<u32> {
+        self.gc
+    }
+}
+
+/// Targets for a contig.  The targets are sorted and non-overlapping; mask has the
+/// targets with padding added (merged where they overlap)
+pub struct CtgTargets {
+    targets: Vec<Target>,
+    mask: Vec<(usize, usize)>,
+}
+
+impl CtgTargets {
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    /// Number of masked (target + padding) bases in each bin of a contig
+    pub fn masked_bases(&self, seq_len: usize, block_size: usize) -> Vec<usize> {
+        let mut v = vec![0; seq_len.div_ceil(block_size)];
+        for (s, e) in self.mask.iter().map(|(s, e)| (*s, (*e).min(seq_len))) {
+            let mut x = s;
+            while x < e {
+                let i = x / block_size;
+                let y = ((i + 1) * block_size).min(e);
+                v[i] += y - x;
+                x = y
+            }
+        }
+        v
+    }
+
+    fn make_mask(&mut self, padding: usize) {
+        for t in self.targets.iter() {
+            let s = t.start.saturating_sub(padding);
+            let e = t.end + padding;
+            match self.mask.last_mut() {
+                Some((_, e1)) if *e1 >= s => *e1 = (*e1).max(e),
+                _ => self.mask.push((s, e)),
+            }
+        }
+    }
+}
+
+/// Location of a base with respect to the targets
+pub enum Location {
+    OffTarget,
+    Padding,
+    Target(usize),
+}
+
+/// Looks up the location of a series of positions in increasing order
+pub struct TargetCursor<'a> {
+    ctg_targets: &'a CtgTargets,
+    target_ix: usize,
+    mask_ix: usize,
+}
+
+impl<'a> TargetCursor<'a> {
+    /// Create new cursor starting at position x
+    pub fn new(ctg_targets: &'a CtgTargets, x: usize) -> Self {
+        Self {
+            ctg_targets,
+            target_ix: ctg_targets.targets.partition_point(|t| t.end <= x),
+            mask_ix: ctg_targets.mask.partition_point(|(_, e)| *e <= x),
+        }
+    }
+
+    /// Get location of x.  x must not be less than the previous position looked up
+    pub fn lookup(&mut self, x: usize) -> Location {
+        let mask = &self.ctg_targets.mask;
+        while self.mask_ix < mask.len() && mask[self.mask_ix].1 <= x {
+            self.mask_ix += 1
+        }
+        if self.mask_ix == mask.len() || mask[self.mask_ix].0 > x {
+            return Location::OffTarget;
+        }
+        let targets = &self.ctg_targets.targets;
+        while self.target_ix < targets.len() && targets[self.target_ix].end <= x {
+            self.target_ix += 1
+        }
+        if self.target_ix < targets.len() && targets[self.target_ix].start <= x {
+            Location::Target(self.target_ix)
+        } else {
+            Location::Padding
+        }
+    }
+}
+
+/// Target regions for all contigs
+pub struct Targets {
+    chash: HashMap<Arc<str>, CtgTargets>,
+}
+
+impl Targets {
+    pub fn ctg_targets(&self, ctg: &str) -> Option<&CtgTargets> {
+        self.chash.get(ctg)
+    }
+
+    /// Read in targets from a BED file.  Targets on contigs not in the contig list are
+    /// ignored, and overlapping targets are merged.  The GC content of the targets is
+    /// calculated from the reference
+    pub fn from_bed<S: AsRef<Path>, T: AsRef<Path>>(
+        fname: S,
+        padding: usize,
+        reference: T,
+        gc_params: GcParams,
+        ctg_hash: &HashMap<Arc<str>, Contig>,
+    ) -> anyhow::Result<Self> {
+        let fname = fname.as_ref();
+        debug!("Reading in targets from {}", fname.display());
+        let mut regions = read_bed_regions(fname, "target", ctg_hash)?;
+
+        // Merge overlapping targets
+        let mut n_merged = 0;
+        let mut chash = HashMap::with_capacity(regions.len());
+        for (ctg, mut v) in regions.drain() {
+            n_merged += merge_regions(&mut v);
+            let targets = v
+                .into_iter()
+                .map(|(start, end, name)| Target {
+                    start,
+                    end,
+                    name,
+                    gc: None,
+                })
+                .collect();
+            let mut ct = CtgTargets {
+                targets,
+                mask: Vec::new(),
+            };
+            ct.make_mask(padding);
+            chash.insert(ctg, ct);
+        }
+        if n_merged > 0 {
+            warn!("{} overlapping targets were merged", n_merged)
+        }
+
+        // Get GC content of targets
+        let ctg_regions: HashMap<_, _> = chash
+            .iter()
+            .map(|(ctg, ct)| {
+                (
+                    Arc::clone(ctg),
+                    ct.targets.iter().map(|t| (t.start, t.end)).collect(),
+                )
+            })
+            .collect();
+        let mut gc = region_gc_bins(reference, &ctg_regions, gc_params, ctg_hash)
+            .with_context(|| "Error calculating GC content of targets")?;
+        for (ctg, ct) in chash.iter_mut() {
+            if let Some(v) = gc.remove(ctg) {
+                for (t, g) in ct.targets.iter_mut().zip(v) {
+                    t.gc = g
+                }
+            }
+        }
+
+        debug!(
+            "Read in {} targets on {} contigs",
+            chash.values().map(|c| c.targets.len()).sum::<usize>(),
+            chash.len()
+        );
+        Ok(Self { chash })
+    }
+}
@@ -0,0 +1,84 @@
+/// Regions used for training the GC model
+///
+/// By default the GC model is trained on all bins from contigs used for normalization.  For
+/// samples with widespread copy number changes, the training can be restricted to a set of
+/// regions known to be stable (given as a BED file).  A bin is used for training if at least
+/// half of the bin is covered by the training regions.
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use crate::{
+    bed::{merge_regions, read_bed_regions},
+    contig::Contig,
+    gc::GcData,
+};
+
+/// Bins used for training the GC model for each contig.  Contigs that are not present
+/// are not used for training
+pub type TrainMask = HashMap<Arc<str>, Vec<bool>>;
+
+/// Training masks for each block size
+pub struct TrainingRegions {
+    masks: Vec<(u32, TrainMask)>,
+}
+
+impl TrainingRegions {
+    /// Training mask for a block size
+    pub fn mask(&self, block_size: u32) -> Option<&TrainMask> {
+        self.masks
+            .iter()
+            .find(|(bs, _)| *bs == block_size)
+            .map(|(_, m)| m)
+    }
+
+    /// Read in training regions from a BED file and generate the training masks for each block size.
+    /// Regions on contigs not in the contig list are ignored, and overlapping regions are merged
+    pub fn from_bed<S: AsRef<Path>>(
+        fname: S,
+        gc_data: &[GcData],
+        ctg_hash: &HashMap<Arc<str>, Contig>,
+    ) -> anyhow::Result<Self> {
+        let fname = fname.as_ref();
+        debug!("Reading in training regions from {}", fname.display());
+        let mut regions = read_bed_regions(fname, "training region", ctg_hash)?;
+        if regions.is_empty() {
+            return Err(anyhow!("No training regions found in {}", fname.display()));
+        }
+        for v in regions.values_mut() {
+            merge_regions(v);
+        }
+
+        // Generate masks for each block size
+        let masks = gc_data
+            .iter()
+            .map(|gc| {
+                let bs = gc.block_size() as usize;
+                let mask = regions
+                    .iter()
+                    .filter_map(|(ctg, v)| gc.ctg_data(ctg).map(|d| (ctg, v, d)))
+                    .map(|(ctg, v, d)| {
+                        let l = d.seq_len();
+                        let mut covered = vec![0; d.data().len()];
+                        for (start, end, _) in v.iter() {
+                            let end = (*end).min(l);
+                            let mut x = *start;
+                            while x < end {
+                                let i = x / bs;
+                                let y = ((i + 1) * bs).min(end);
+                                covered[i] += y - x;
+                                x = y;
+                            }
+                        }
+                        let m = covered
+                            .iter()
+                            .enumerate()
+                            .map(|(i, c)| (c << 1) >= bs.min(l - i * bs))
+                            .collect();
+                        (Arc::clone(ctg), m)
+                    })
+                    .collect();
+                (gc.block_size(), mask)
+            })
+            .collect();
+        Ok(Self { masks })
+    }
+}
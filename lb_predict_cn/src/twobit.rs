@@ -0,0 +1,162 @@
+/// Reader for UCSC .2bit reference files
+///
+/// The 2bit format stores the sequence of each contig packed 4 bases per byte, with separate
+/// lists of blocks of Ns and of soft masked (lower case) regions.  An index at the start of the file
+/// gives the offset of each contig, so contigs can be read in any order without a separate
+/// index file.  See https://genome.ucsc.edu/FAQ/FAQformat.html#format7
+///
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Context;
+
+const TWOBIT_MAGIC: u32 = 0x1A412743;
+
+/// Decoding of 2 bit base codes
+const BASES: [u8; 4] = [b'T', b'C', b'A', b'G'];
+
+/// Check if file is in 2bit format by looking at the signature
+pub fn is_two_bit<P: AsRef<Path>>(fname: P) -> bool {
+    let mut b = [0; 4];
+    File::open(fname)
+        .and_then(|mut f| f.read_exact(&mut b))
+        .map(|_| u32::from_le_bytes(b) == TWOBIT_MAGIC || u32::from_be_bytes(b) == TWOBIT_MAGIC)
+        .unwrap_or(false)
+}
+
+pub struct TwoBit {
+    rdr: BufReader<File>,
+    big_endian: bool,
+    index: HashMap<String, u64>,
+}
+
+impl TwoBit {
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        let mut b = [0; 4];
+        self.rdr.read_exact(&mut b)?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        let mut b = [0; 8];
+        self.rdr.read_exact(&mut b)?;
+        Ok(if self.big_endian {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        })
+    }
+
+    fn read_u32_vec(&mut self, n: usize) -> anyhow::Result<Vec<u32>> {
+        (0..n).map(|_| self.read_u32()).collect()
+    }
+
+    /// Open file and read in header and index
+    pub fn open<P: AsRef<Path>>(fname: P) -> anyhow::Result<Self> {
+        let fname = fname.as_ref();
+        let f = File::open(fname)
+            .with_context(|| format!("Error opening 2bit file {}", fname.display()))?;
+        let mut tb = Self {
+            rdr: BufReader::new(f),
+            big_endian: false,
+            index: HashMap::new(),
+        };
+        let mut b = [0; 4];
+        tb.rdr.read_exact(&mut b)?;
+        if u32::from_be_bytes(b) == TWOBIT_MAGIC {
+            tb.big_endian = true
+        } else if u32::from_le_bytes(b) != TWOBIT_MAGIC {
+            return Err(anyhow!("File {} is not a 2bit file", fname.display()));
+        }
+        // Version 1 files have 64 bit offsets
+        let version = tb.read_u32()?;
+        if version > 1 {
+            return Err(anyhow!(
+                "Unsupported 2bit version {} for file {}",
+                version,
+                fname.display()
+            ));
+        }
+        let n_seq = tb.read_u32()?;
+        let _ = tb.read_u32()?;
+        for _ in 0..n_seq {
+            let mut l = [0; 1];
+            tb.rdr.read_exact(&mut l)?;
+            let mut name = vec![0; l[0] as usize];
+            tb.rdr.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .with_context(|| format!("Invalid contig name in {}", fname.display()))?;
+            let offset = if version == 1 {
+                tb.read_u64()?
+            } else {
+                tb.read_u32()? as u64
+            };
+            tb.index.insert(name, offset);
+        }
+        trace!(
+            "Read index for {} contigs from 2bit file {}",
+            tb.index.len(),
+            fname.display()
+        );
+        Ok(tb)
+    }
+
+    /// Length of a contig.  Returns None if the contig is not present in the file
+    pub fn seq_len(&mut self, ctg: &str) -> anyhow::Result<Option<usize>> {
+        let offset = match self.index.get(ctg) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+        self.rdr.seek(SeekFrom::Start(offset))?;
+        Ok(Some(self.read_u32()? as usize))
+    }
+
+    /// Fetch the sequence for a contig.  Blocks of Ns are returned as 'N' and the
+    /// soft masking is ignored, so the sequence is always upper case.
+    /// Returns None if the contig is not present in the file
+    pub fn fetch_seq(&mut self, ctg: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let offset = match self.index.get(ctg) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+        self.rdr.seek(SeekFrom::Start(offset))?;
+        let dna_size = self.read_u32()? as usize;
+        let n_blocks = self.read_u32()? as usize;
+        let n_starts = self.read_u32_vec(n_blocks)?;
+        let n_sizes = self.read_u32_vec(n_blocks)?;
+        // Skip mask blocks and reserved field
+        let n_mask = self.read_u32()? as i64;
+        self.rdr.seek_relative(n_mask * 8 + 4)?;
+
+        let mut packed = vec![0; (dna_size + 3) >> 2];
+        self.rdr
+            .read_exact(&mut packed)
+            .with_context(|| format!("Error reading sequence for contig {}", ctg))?;
+
+        let mut seq = Vec::with_capacity(packed.len() << 2);
+        for x in packed {
+            seq.extend_from_slice(&[
+                BASES[(x >> 6) as usize],
+                BASES[((x >> 4) & 3) as usize],
+                BASES[((x >> 2) & 3) as usize],
+                BASES[(x & 3) as usize],
+            ])
+        }
+        seq.truncate(dna_size);
+
+        for (s, l) in n_starts.iter().zip(n_sizes.iter()) {
+            let s = (*s as usize).min(dna_size);
+            let e = (s + *l as usize).min(dna_size);
+            seq[s..e].fill(b'N')
+        }
+        Ok(Some(seq))
+    }
+}
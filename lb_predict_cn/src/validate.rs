@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use crate::{
+    alias::{ASSEMBLIES, CHROMOSOMES},
+    config::Config,
+    contig::Contig,
+    input::{header_seq_info, open_input},
+    md5::md5_hex,
+    sample::Sample,
+    twobit::is_two_bit,
+};
+
+/// Identify assembly from contig lengths.  An assembly is identified if at least one
+/// primary chromosome is present and all primary chromosomes present have the expected length
+fn identify_assembly<'a, I: Iterator<Item = (&'a Contig, usize)>>(it: I) -> Option<&'static str> {
+    let mut counts = [[0usize; 2]; ASSEMBLIES.len()];
+    for (contig, len) in it {
+        if let Some(ix) = contig
+            .names()
+            .find_map(|s| CHROMOSOMES.iter().position(|c| *c == s))
+        {
+            for (ct, (_, lengths)) in counts.iter_mut().zip(ASSEMBLIES.iter()) {
+                ct[usize::from(lengths[ix] != len)] += 1
+            }
+        }
+    }
+    counts
+        .iter()
+        .zip(ASSEMBLIES.iter())
+        .find(|(ct, _)| ct[0] > 0 && ct[1] == 0)
+        .map(|(_, (name, _))| *name)
+}
+
+/// Check the header of an input file against the reference and the contig list.
+/// Returns a list of incompatibilities found.  M5 tags are only checked if check_md5 is set
+fn check_sample(
+    cfg: &Config,
+    sample: &Sample,
+    ctgs: &[&Contig],
+    ref_assembly: Option<&str>,
+    check_md5: bool,
+) -> anyhow::Result<Vec<String>> {
+    let hts = open_input(sample.input_path(), true, cfg.reference(), None)?;
+    let seq_info = header_seq_info(&hts);
+    let hdr: HashMap<_, _> = seq_info.iter().map(|s| (s.name.as_str(), s)).collect();
+
+    let mut problems = Vec::new();
+    let mut found = Vec::with_capacity(ctgs.len());
+    for contig in ctgs {
+        let ctg = contig.name();
+        let si = contig.names().find_map(|s| {
+            hdr.get(s)
+                .filter(|si| si.len.map(|l| contig.matches_seq(s, l)).unwrap_or(true))
+        });
+        match si {
+            None => problems.push(format!("contig {} not found in header", ctg)),
+            // Contigs missing from the reference have already been reported
+            Some(si) => {
+                if let Some(gc) = cfg.gc_data()[0].ctg_data(ctg) {
+                    match si.len {
+                        Some(l) if l != gc.seq_len() => problems.push(format!(
+                            "contig {} has length {} in header but {} in reference",
+                            ctg,
+                            l,
+                            gc.seq_len()
+                        )),
+                        Some(l) => found.push((*contig, l)),
+                        None => problems.push(format!("contig {} has no length in header", ctg)),
+                    }
+                    if let (true, Some(m5), Some(md5)) = (check_md5, si.md5.as_ref(), gc.md5()) {
+                        let md5 = md5_hex(md5);
+                        if *m5 != md5 {
+                            problems.push(format!(
+                                "contig {} has M5 {} in header but reference MD5 is {}",
+                                ctg, m5, md5
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let assembly = identify_assembly(found.iter().copied());
+    debug!(
+        "Sample {}: assembly identified as {}",
+        sample.name(),
+        assembly.unwrap_or("unknown")
+    );
+    if let (Some(a), Some(b)) = (assembly, ref_assembly) {
+        if a != b {
+            problems.push(format!(
+                "assembly {} does not match reference assembly {}",
+                a, b
+            ))
+        }
+    }
+    Ok(problems)
+}
+
+/// Check that the input files are compatible with the reference and contig list before
+/// processing starts.  All incompatible samples are reported before returning an error
+pub fn validate_inputs(cfg: &Config) -> anyhow::Result<()> {
+    debug!("Checking compatibility of input files with reference");
+
+    let mut ctgs: Vec<_> = cfg.ctg_hash().values().collect();
+    ctgs.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+    // Check that all contigs are present in the reference
+    let missing: Vec<_> = ctgs
+        .iter()
+        .filter(|c| cfg.gc_data()[0].ctg_data(c.name()).is_none())
+        .map(|c| c.name().as_ref())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Contigs not found in reference {}: {}",
+            cfg.reference().display(),
+            missing.join(", ")
+        ));
+    }
+
+    let ref_assembly = identify_assembly(
+        ctgs.iter()
+            .map(|c| (*c, cfg.gc_data()[0].ctg_data(c.name()).unwrap().seq_len())),
+    );
+    match ref_assembly {
+        Some(a) => info!("Reference assembly identified as {}", a),
+        None => info!("Reference assembly not identified"),
+    }
+
+    // 2bit files can not store IUPAC codes other than N, so the MD5 of the decoded sequence
+    // will not match the M5 tags if the original FASTA file had any
+    let check_md5 = !is_two_bit(cfg.reference());
+    if !check_md5 {
+        debug!("2bit reference: M5 tags in input headers will not be checked")
+    }
+
+    let mut bad_samples = Vec::new();
+    for sample in cfg.sample_list() {
+        let problems = check_sample(cfg, sample, &ctgs, ref_assembly, check_md5)
+            .unwrap_or_else(|e| vec![format!("{:#}", e)]);
+        if !problems.is_empty() {
+            for p in problems.iter() {
+                error!("Sample {}: {}", sample.name(), p)
+            }
+            bad_samples.push(sample.name())
+        }
+    }
+
+    if bad_samples.is_empty() {
+        debug!("All input files compatible with reference");
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} input file(s) incompatible with reference or contig list: {}",
+            bad_samples.len(),
+            bad_samples.join(", ")
+        ))
+    }
+}
@@ -2,7 +2,7 @@ use std::path::Path;
 
 use anyhow::Context;
 use compress_io::compress::CompressIo;
-use utils::get_next_line;
+use utils::{get_next_line, is_coverage_header};
 
 use crate::region::Region;
 
@@ -25,7 +25,7 @@ pub fn read_region_data(p: &Path, reg: &Region) -> anyhow::Result<Option<f64>> {
     {
         line += 1;
         // Parse input line and store to hash if valid
-        if fields.len() >= 3 {
+        if fields.len() >= 3 && !is_coverage_header(&fields) {
             // Skip short lines
             let x = fields[1]
                 .parse::<usize>()
@@ -88,6 +88,12 @@ pub fn get_next_line<'a, R: BufRead>(
     }
 }
 
+/// Check whether the fields are from the header line of an lb_predict_cn coverage file (this
+/// is present if extra columns are written)
+pub fn is_coverage_header(fields: &[&str]) -> bool {
+    fields.first() == Some(&"contig") && fields.get(1) == Some(&"position")
+}
+
 /// Robust estimation of sd from IQR following approach of
 /// Wan et al. (2014) doi: 10.1186/1471-2288-14-135
 ///